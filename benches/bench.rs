@@ -19,10 +19,12 @@ use raytracer::util::Axis;
 const R_HIT: Ray = Ray {
     origin: Vec3 { x: 0.0, y: 0.0, z: -2.0 },
     dir: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
+    wavelength: 0.0,
 };
 const R_MISS: Ray = Ray {
     origin: Vec3 { x: 0.0, y: 0.0, z: -2.0 },
     dir: Vec3 { x: 10.0, y: 10.0, z: 1.0 },
+    wavelength: 0.0,
 };
 
 #[bench]
@@ -61,8 +63,9 @@ fn bench_bvh_hit(b: &mut test::Bencher) {
             Material::lambertian_constant(Vec3::new(0.5,0.5,0.5))
         ))]
     );
+    let mut rng = rand::XorShiftRng::new_unseeded();
     b.iter(|| {
-        unit_bvh.hit(&R_HIT, 0.0000001, 10000.0);
+        unit_bvh.hit(&R_HIT, 0.0000001, 10000.0, &mut rng);
     });
 }
 
@@ -74,8 +77,9 @@ fn bench_bvh_miss(b: &mut test::Bencher) {
             Material::lambertian_constant(Vec3::new(0.5,0.5,0.5))
         ))]
     );
+    let mut rng = rand::XorShiftRng::new_unseeded();
     b.iter(|| {
-        unit_bvh.hit(&R_MISS, 0.0000001, 10000.0);
+        unit_bvh.hit(&R_MISS, 0.0000001, 10000.0, &mut rng);
     });
 }
 
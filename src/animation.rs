@@ -0,0 +1,232 @@
+// Module for rendering animations
+//
+// Camera parameters and object transforms are keyframed in seconds, and sampled per frame using
+// the frame rate. Objects that never move are put in a BVH once and shared by every frame.
+
+use camera::Camera;
+use model::Renderable;
+use model::bvh::{self, BVHItem};
+use util::Axis;
+use vec3::Vec3;
+
+use std::sync::Arc;
+
+// Linear interpolation between two values, t in [0,1]
+pub trait Interpolate: Clone {
+    fn lerp(a: &Self, b: &Self, t: f64) -> Self;
+}
+
+impl Interpolate for f64 {
+    fn lerp(a: &f64, b: &f64, t: f64) -> f64 {
+        a * (1.0 - t) + b * t
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn lerp(a: &Vec3, b: &Vec3, t: f64) -> Vec3 {
+        *a * (1.0 - t) + *b * t
+    }
+}
+
+//
+// Keyframes
+// List of (time in seconds, value), values are held before the first and after the last key
+//
+#[derive(Debug, Clone)]
+pub struct Keyframes<T: Interpolate> {
+    keys: Vec<(f64, T)>,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new(mut keys: Vec<(f64, T)>) -> Keyframes<T> {
+        assert!(!keys.is_empty(), "Keyframes need at least one key");
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Keyframes {
+            keys: keys
+        }
+    }
+
+    pub fn constant(value: T) -> Keyframes<T> {
+        Keyframes::new(vec![(0.0, value)])
+    }
+
+    pub fn at(&self, time: f64) -> T {
+        let first = &self.keys[0];
+        let last = &self.keys[self.keys.len() - 1];
+        if time <= first.0 {
+            return first.1.clone();
+        }
+        if time >= last.0 {
+            return last.1.clone();
+        }
+
+        // Find the pair of keys surrounding time
+        let i = self.keys.iter().position(|k| k.0 > time).unwrap();
+        let (t0, ref v0) = self.keys[i-1];
+        let (t1, ref v1) = self.keys[i];
+        T::lerp(v0, v1, (time - t0) / (t1 - t0))
+    }
+
+    // True if no key changes the value between time a and b
+    pub fn is_constant_between(&self, a: f64, b: f64) -> bool {
+        let (a, b) = if a < b { (a, b) } else { (b, a) };
+        let first = self.keys[0].0;
+        let last = self.keys[self.keys.len() - 1].0;
+        self.keys.len() == 1 || b <= first || a >= last
+    }
+}
+
+//
+// Camera
+//
+#[derive(Debug, Clone)]
+pub struct CameraKeyframe {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vfov: f64,
+    pub focus_dist: f64,
+}
+
+impl Interpolate for CameraKeyframe {
+    fn lerp(a: &CameraKeyframe, b: &CameraKeyframe, t: f64) -> CameraKeyframe {
+        CameraKeyframe {
+            lookfrom: Vec3::lerp(&a.lookfrom, &b.lookfrom, t),
+            lookat: Vec3::lerp(&a.lookat, &b.lookat, t),
+            vfov: f64::lerp(&a.vfov, &b.vfov, t),
+            focus_dist: f64::lerp(&a.focus_dist, &b.focus_dist, t),
+        }
+    }
+}
+
+// Parameters that are not keyframed are fixed for the whole animation
+pub struct AnimatedCamera {
+    pub keys: Keyframes<CameraKeyframe>,
+    pub vup: Vec3,
+    pub aspect: f64,
+    pub aperture: f64,
+}
+
+impl AnimatedCamera {
+    pub fn camera_at(&self, time: f64) -> Camera {
+        let k = self.keys.at(time);
+        Camera::new(k.lookfrom, k.lookat, self.vup, k.vfov, self.aspect, self.aperture, k.focus_dist)
+    }
+}
+
+//
+// Objects
+//
+pub struct Transform {
+    pub translation: Keyframes<Vec3>,
+    pub axis: Axis,
+    pub rotation: Keyframes<f64>, // degrees around axis
+}
+
+impl Transform {
+    pub fn is_constant_between(&self, a: f64, b: f64) -> bool {
+        self.translation.is_constant_between(a, b) && self.rotation.is_constant_between(a, b)
+    }
+}
+
+// An object rebuilt every frame from its interpolated transform
+// build receives (axis, degrees, offset), and is expected to rotate then translate the geometry
+pub struct AnimatedObject {
+    pub transform: Transform,
+    build: Box<Fn(Axis, f64, Vec3) -> Box<BVHItem> + Send + Sync>,
+}
+
+impl AnimatedObject {
+    pub fn new<F>(transform: Transform, build: F) -> AnimatedObject
+        where F: Fn(Axis, f64, Vec3) -> Box<BVHItem> + Send + Sync + 'static
+    {
+        AnimatedObject {
+            transform: transform,
+            build: Box::new(build),
+        }
+    }
+
+    pub fn build_at(&self, time: f64) -> Box<BVHItem> {
+        (self.build)(self.transform.axis, self.transform.rotation.at(time), self.transform.translation.at(time))
+    }
+}
+
+//
+// Animation
+//
+pub struct Animation {
+    pub camera: AnimatedCamera,
+    pub fps: f64,
+    static_scene: Option<Arc<bvh::Node>>,
+    objects: Vec<AnimatedObject>,
+
+    // BVH of animated objects from the last frame and the time it was built for
+    cached: Option<(f64, Arc<bvh::Node>)>,
+}
+
+impl Animation {
+    pub fn new(camera: AnimatedCamera, fps: f64, static_items: Vec<Box<BVHItem>>, objects: Vec<AnimatedObject>) -> Animation {
+        Animation {
+            camera: camera,
+            fps: fps,
+            static_scene: if static_items.is_empty() { None } else { Some(Arc::new(bvh::Node::new(static_items))) },
+            objects: objects,
+            cached: None,
+        }
+    }
+
+    pub fn frame_time(&self, frame: i32) -> f64 {
+        frame as f64 / self.fps
+    }
+
+    pub fn camera_at(&self, frame: i32) -> Camera {
+        self.camera.camera_at(self.frame_time(frame))
+    }
+
+    // Scene for a frame. The static BVH is always shared, the BVH of animated objects is reused
+    // from the previous call if none of them moved since
+    pub fn scene_at(&mut self, frame: i32) -> Box<Renderable + Sync> {
+        let time = self.frame_time(frame);
+        let mut world: Vec<Box<Renderable>> = Vec::new();
+
+        if let Some(ref s) = self.static_scene {
+            world.push(Box::new(s.clone()));
+        }
+
+        if !self.objects.is_empty() {
+            let reuse = match self.cached {
+                Some((t, _)) => self.objects.iter().all(|o| o.transform.is_constant_between(t, time)),
+                None => false,
+            };
+            if !reuse {
+                let items = self.objects.iter().map(|o| o.build_at(time)).collect();
+                self.cached = Some((time, Arc::new(bvh::Node::new(items))));
+            }
+            if let Some((_, ref dynamic)) = self.cached {
+                world.push(Box::new(dynamic.clone()));
+            }
+        }
+
+        Box::new(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyframes_interpolate() {
+        let k = Keyframes::new(vec![(1.0, 10.0), (0.0, 0.0)]);
+        assert!(k.at(0.5) == 5.0);
+        assert!(k.at(-1.0) == 0.0);
+        assert!(k.at(2.0) == 10.0);
+    }
+
+    #[test]
+    fn test_keyframes_constant_between() {
+        let k = Keyframes::new(vec![(1.0, Vec3::new(0.0,0.0,0.0)), (2.0, Vec3::new(1.0,0.0,0.0))]);
+        assert!(k.is_constant_between(0.0, 1.0));
+        assert!(k.is_constant_between(3.0, 2.0));
+        assert!(!k.is_constant_between(0.5, 1.5));
+    }
+}
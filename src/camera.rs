@@ -51,6 +51,7 @@ impl Camera {
                  + (self.vertical * t)
                  - self.origin
                  - offset,
+            wavelength: 0.0,
         }
     }
 }
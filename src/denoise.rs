@@ -0,0 +1,217 @@
+// Module for denoising a rendered film
+//
+// Filters are guided by the albedo, normal and depth AOVs so edges in the scene are kept while
+// noise on flat regions is averaged away. Lighting is filtered with the albedo divided out so
+// texture detail is not blurred, then multiplied back in.
+
+use film::{Aov, Film};
+use vec3::Vec3;
+
+use std::f64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Method {
+    // Joint cross bilateral filter over a (2*radius+1)^2 window
+    CrossBilateral { radius: i32, sigma_spatial: f64 },
+    // Edge avoiding a-trous wavelet filter, 5x5 kernel with step doubling each iteration
+    ATrous { iterations: i32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub method: Method,
+    pub sigma_color: f64,  // luminance difference
+    pub sigma_albedo: f64, // albedo difference
+    pub sigma_normal: f64, // exponent on the normals' dot product
+    pub sigma_depth: f64,  // depth difference relative to the center depth
+}
+
+impl Settings {
+    pub fn cross_bilateral() -> Settings {
+        Settings {
+            method: Method::CrossBilateral { radius: 6, sigma_spatial: 3.0 },
+            sigma_color: 0.8,
+            sigma_albedo: 0.1,
+            sigma_normal: 64.0,
+            sigma_depth: 0.05,
+        }
+    }
+
+    pub fn a_trous() -> Settings {
+        Settings {
+            method: Method::ATrous { iterations: 5 },
+            .. Settings::cross_bilateral()
+        }
+    }
+}
+
+// AOVs the filters use, missing ones are not used as edge stopping features
+pub fn feature_aovs() -> Vec<Aov> {
+    vec![Aov::Albedo, Aov::Normal, Aov::Depth]
+}
+
+// Denoise the film's beauty, returning the filtered linear colors
+pub fn denoise(film: &Film, settings: &Settings) -> Vec<Vec3> {
+    let features = Features {
+        width: film.width,
+        height: film.height,
+        albedo: film.aov(Aov::Albedo),
+        normal: film.aov(Aov::Normal),
+        depth: film.aov(Aov::Depth),
+    };
+
+    // Demodulate albedo, where it is too dark to divide by the color is filtered as is
+    let albedo_at = |i: usize| features.albedo.map(|a| a[i].map(&|x| if x > 0.01 { x } else { 1.0 }));
+    let irradiance: Vec<Vec3> = film.beauty.iter().enumerate().map(|(i, c)| {
+        match albedo_at(i) {
+            Some(a) => Vec3::map2(*c, a, &|x, y| x / y),
+            None => *c,
+        }
+    }).collect();
+
+    let filtered = match settings.method {
+        Method::CrossBilateral { radius, sigma_spatial } => {
+            let offsets = window(radius, 1, &|dx, dy| {
+                (-((dx*dx + dy*dy) as f64) / (2.0 * sigma_spatial * sigma_spatial)).exp()
+            });
+            filter_pass(&irradiance, &features, settings, &offsets, settings.sigma_color)
+        }
+        Method::ATrous { iterations } => {
+            let h = [1.0/16.0, 1.0/4.0, 3.0/8.0, 1.0/4.0, 1.0/16.0];
+            let mut c = irradiance;
+            for i in 0..iterations {
+                let step = 1 << i;
+                let offsets = window(2, step, &|dx, dy| h[(dx/step + 2) as usize] * h[(dy/step + 2) as usize]);
+                // Color differences get smaller as noise is removed, so tighten the color weight
+                let sigma_color = settings.sigma_color / (1 << i) as f64;
+                c = filter_pass(&c, &features, settings, &offsets, sigma_color);
+            }
+            c
+        }
+    };
+
+    filtered.iter().enumerate().map(|(i, c)| {
+        match albedo_at(i) {
+            Some(a) => *c * a,
+            None => *c,
+        }
+    }).collect()
+}
+
+struct Features<'a> {
+    width: i32,
+    height: i32,
+    albedo: Option<&'a Vec<Vec3>>,
+    normal: Option<&'a Vec<Vec3>>,
+    depth: Option<&'a Vec<Vec3>>,
+}
+
+// Pixel offsets (dx, dy, weight) of a square window of step spaced taps
+fn window(radius: i32, step: i32, weight: &Fn(i32, i32) -> f64) -> Vec<(i32, i32, f64)> {
+    let mut offsets = Vec::new();
+    for y in -radius..(radius+1) {
+        for x in -radius..(radius+1) {
+            let (dx, dy) = (x * step, y * step);
+            offsets.push((dx, dy, weight(dx, dy)));
+        }
+    }
+    offsets
+}
+
+// Weighted average of each pixel's window, weights reduced across feature edges
+fn filter_pass(data: &Vec<Vec3>, f: &Features, settings: &Settings, offsets: &Vec<(i32, i32, f64)>, sigma_color: f64) -> Vec<Vec3> {
+    let mut out = Vec::with_capacity(data.len());
+    for y in 0..f.height {
+        for x in 0..f.width {
+            let center = (y * f.width + x) as usize;
+            let mut sum = Vec3::new(0.0, 0.0, 0.0);
+            let mut total_weight = 0.0;
+
+            for &(dx, dy, kernel_weight) in offsets {
+                let (sx, sy) = (x + dx, y + dy);
+                if sx < 0 || sy < 0 || sx >= f.width || sy >= f.height {
+                    continue;
+                }
+                let s = (sy * f.width + sx) as usize;
+
+                let w = kernel_weight
+                    * color_weight(&data[center], &data[s], sigma_color)
+                    * feature_weight(f, settings, center, s);
+                sum += data[s] * w;
+                total_weight += w;
+            }
+
+            // The center pixel always has weight, unless its own color is not finite
+            out.push(if total_weight > 0.0 { sum / total_weight } else { data[center] });
+        }
+    }
+    out
+}
+
+fn color_weight(a: &Vec3, b: &Vec3, sigma: f64) -> f64 {
+    let d = a.luminance() - b.luminance();
+    (-(d * d) / (2.0 * sigma * sigma)).exp()
+}
+
+fn feature_weight(f: &Features, settings: &Settings, a: usize, b: usize) -> f64 {
+    let mut w = 1.0;
+
+    if let Some(albedo) = f.albedo {
+        let d = (albedo[a] - albedo[b]).squared_length();
+        w *= (-d / (2.0 * settings.sigma_albedo * settings.sigma_albedo)).exp();
+    }
+
+    if let Some(normal) = f.normal {
+        w *= normal[a].dot(normal[b]).max(0.0).powf(settings.sigma_normal);
+    }
+
+    if let Some(depth) = f.depth {
+        let (za, zb) = (depth[a].x, depth[b].x);
+        if za.is_finite() && zb.is_finite() {
+            let d = (za - zb) / za.max(f64::EPSILON);
+            w *= (-(d * d) / (2.0 * settings.sigma_depth * settings.sigma_depth)).exp();
+        } else if za.is_finite() != zb.is_finite() {
+            // Background next to geometry
+            w = 0.0;
+        }
+    }
+
+    w
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn film(beauty: Vec<Vec3>, albedo: Vec<Vec3>, width: i32) -> Film {
+        let height = beauty.len() as i32 / width;
+        let mut f = Film::new(width, height, &[Aov::Albedo]);
+        f.beauty = beauty;
+        f.aovs[0].1 = albedo;
+        f
+    }
+
+    #[test]
+    fn test_denoise_constant() {
+        let c = Vec3::new(0.5, 0.25, 0.125);
+        let f = film(vec![c; 16], vec![Vec3::new(0.5, 0.5, 0.5); 16], 4);
+        for settings in &[Settings::cross_bilateral(), Settings::a_trous()] {
+            for res in denoise(&f, settings) {
+                assert!(Vec3::approx_float_eq(&res, &c));
+            }
+        }
+    }
+
+    #[test]
+    fn test_denoise_keeps_albedo_edge() {
+        // Left half black, right half white, split by albedo
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let beauty: Vec<Vec3> = (0..16).map(|i| if i % 4 < 2 { black } else { white }).collect();
+        let f = film(beauty.clone(), beauty.clone(), 4);
+        let res = denoise(&f, &Settings::cross_bilateral());
+        for (r, b) in res.iter().zip(beauty.iter()) {
+            assert!(Vec3::approx_float_eq(r, b));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// Module for render output before it is quantized to an image
+//
+// Holds the linear beauty pass and any arbitrary output variables (AOVs), one Vec3 per pixel from
+// top left to bottom right.
+
+use vec3::Vec3;
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+// Arbitrary output variables recorded from the first hit of each camera sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    Normal,      // shading normal
+    Albedo,      // first hit material albedo
+    Depth,       // distance from the camera, stored in every channel
+    Position,    // world space position
+    ObjectId,    // Model object id, stored in every channel
+    MaterialId,  // Model material id, stored in every channel
+    Emission,    // light emitted by the first hit
+    Direct,      // light reaching the first hit directly from emitters
+    Indirect,    // light reaching the first hit after more than one bounce
+    SampleCount, // samples averaged into the pixel, stored in every channel
+}
+
+impl Aov {
+    pub fn all() -> Vec<Aov> {
+        vec![
+            Aov::Normal,
+            Aov::Albedo,
+            Aov::Depth,
+            Aov::Position,
+            Aov::ObjectId,
+            Aov::MaterialId,
+            Aov::Emission,
+            Aov::Direct,
+            Aov::Indirect,
+            Aov::SampleCount,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Emission => "emission",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::SampleCount => "sample_count",
+        }
+    }
+}
+
+pub struct Film {
+    pub width: i32,
+    pub height: i32,
+    pub beauty: Vec<Vec3>,
+    pub aovs: Vec<(Aov, Vec<Vec3>)>,
+}
+
+impl Film {
+    pub fn new(width: i32, height: i32, aovs: &[Aov]) -> Film {
+        let n = (width * height) as usize;
+        Film {
+            width: width,
+            height: height,
+            beauty: Vec::with_capacity(n),
+            aovs: aovs.iter().map(|&a| (a, Vec::with_capacity(n))).collect(),
+        }
+    }
+
+    pub fn aov(&self, aov: Aov) -> Option<&Vec<Vec3>> {
+        self.aovs.iter().find(|x| x.0 == aov).map(|x| &x.1)
+    }
+
+    // Append rows from another film of the same width, e.g. a slice rendered by another thread
+    pub fn append(&mut self, other: &mut Film) {
+        assert!(self.width == other.width);
+        self.height += other.height;
+        self.beauty.append(&mut other.beauty);
+        for &mut (aov, ref mut data) in self.aovs.iter_mut() {
+            for &mut (other_aov, ref mut other_data) in other.aovs.iter_mut() {
+                if aov == other_aov {
+                    data.append(other_data);
+                }
+            }
+        }
+    }
+
+    // Beauty as RGB triples of ints, gamma corrected to 2
+    pub fn to_rgb(&self) -> Vec<i32> {
+        to_rgb(&self.beauty)
+    }
+}
+
+// Quantize linear colors to RGB triples of ints
+pub fn to_rgb(data: &Vec<Vec3>) -> Vec<i32> {
+    let mut outbuf = Vec::with_capacity(data.len() * 3);
+    for c in data {
+        // Gamma correct to 2: output color ^ (1/gamma) = x^(1/2) = sqrt
+        let c = c.map(&|x: f64| x.max(0.0).sqrt());
+        let c = c * 255.99;
+        let c = c.map(&|x: f64| if x > 255.99 { 255.99 } else { x });
+
+        outbuf.push(c.x as i32);
+        outbuf.push(c.y as i32);
+        outbuf.push(c.z as i32);
+    }
+    outbuf
+}
+
+// Write a float image as a Portable Float Map (PFM)
+// Little endian, rows stored bottom to top
+pub fn write_pfm(data: &Vec<Vec3>, width: i32, height: i32, output_path: &str) -> io::Result<()> {
+    let mut file = File::create(&Path::new(output_path))?;
+    file.write_fmt(format_args!("PF\n{} {}\n-1.0\n", width, height))?;
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(data.len() * 12);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let c = data[(y * width + x) as usize];
+            for v in &[c.x, c.y, c.z] {
+                let bits = (*v as f32).to_bits();
+                bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+            }
+        }
+    }
+    file.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_rgb() {
+        let res = to_rgb(&vec![Vec3::new(0.25, 2.0, -1.0)]);
+        assert!(res == vec![127, 255, 0]);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut a = Film::new(1, 0, &[Aov::Depth]);
+        let mut b = Film::new(1, 0, &[Aov::Depth]);
+        b.height = 1;
+        b.beauty.push(Vec3::new(1.0,1.0,1.0));
+        b.aovs[0].1.push(Vec3::new(2.0,2.0,2.0));
+        a.append(&mut b);
+        assert!(a.height == 1);
+        assert!(a.aov(Aov::Depth).unwrap()[0] == Vec3::new(2.0,2.0,2.0));
+    }
+}
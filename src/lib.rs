@@ -5,9 +5,19 @@ extern crate time;
 pub mod model;
 pub mod shader;
 
+pub mod animation;
 pub mod bench;
 pub mod camera;
+pub mod denoise;
+pub mod film;
+pub mod light;
+pub mod light_sampler;
+pub mod medium;
 pub mod ray;
 pub mod render;
+pub mod scene;
+pub mod sky;
+pub mod spectrum;
 pub mod util;
 pub mod vec3;
+pub mod volume;
@@ -0,0 +1,405 @@
+// Lights sampled explicitly: point, spot, directional and spheres
+//
+// Delta lights have no geometry so a ray can never hit them by chance, the integrator samples them
+// at every hit and casts a shadow ray (see render::color). Add them to a scene with
+// scene::Scene::with_light. A sphere light stands in for an emissive sphere in the world, tagged
+// with its model's object id so paths that hit the sphere don't count its emission twice.
+
+extern crate rand;
+
+use model::bvh::AABB;
+use model::sphere::Sphere;
+use vec3::{Vec3, Onb};
+use rand::*;
+
+use std::f64;
+use std::f64::consts::PI;
+use std::fmt::Debug;
+
+// Light arriving at a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightSample {
+    pub dir: Vec3,      // unit direction from the point towards the light
+    pub distance: f64,  // to the light, infinite for directional lights
+    pub radiance: Vec3, // incident light divided by the pdf of dir, f * radiance is the estimate
+}
+
+// Where a light is, how much it emits and which way, for picking lights by their contribution to
+// a point (see light_sampler). Light leaves within theta_o + theta_e of axis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightBounds {
+    pub aabb: AABB,
+    pub power: f64,     // total emitted power, luminance of the rgb
+    pub axis: Vec3,
+    pub theta_o: f64,   // spread of the emitting normals (or a spot's directions) around axis
+    pub theta_e: f64,   // how far past a normal light is emitted, pi/2 for diffuse surfaces
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    // Estimated contribution to a point p with normal n, a zero n for points in a medium. An
+    // upper bound over the box and cone, like the light tree of Conty Estevez and Kulla
+    pub fn importance(&self, p: &Vec3, n: &Vec3) -> f64 {
+        if self.power <= 0.0 {
+            return 0.0;
+        }
+        let center = (self.aabb.min + self.aabb.max) * 0.5;
+        let radius2 = (self.aabb.max - center).squared_length();
+        let to_p = *p - center;
+        let distance2 = to_p.squared_length();
+        // Inside or close to the box the distance says little, don't let it blow up
+        let d2 = distance2.max(radius2).max(1e-12);
+        let wi = if distance2 > 0.0 { to_p / distance2.sqrt() } else { self.axis };
+
+        // Angle the box covers seen from p
+        let theta_b = if distance2 <= radius2 {
+            PI
+        } else {
+            (radius2 / distance2).sqrt().asin()
+        };
+
+        // Smallest angle between p and the directions light leaves in
+        let cos_w = if self.two_sided { self.axis.dot(wi).abs() } else { self.axis.dot(wi) };
+        let theta_w = cos_w.max(-1.0).min(1.0).acos();
+        let theta = (theta_w - self.theta_o - theta_b).max(0.0);
+        if theta > self.theta_e || theta >= PI / 2.0 {
+            return 0.0;
+        }
+        let mut importance = self.power * theta.cos() / d2;
+
+        // Smallest angle to the normal
+        if n.squared_length() > 0.0 {
+            let cos_i = (wi.dot(*n) / n.length()).abs().min(1.0);
+            importance *= (cos_i.acos() - theta_b).max(0.0).cos();
+        }
+        importance.max(0.0)
+    }
+}
+
+pub trait Light: Debug + Sync + Send {
+    // Sample light reaching p, None if p gets none
+    fn sample(&self, p: &Vec3, rng: &mut XorShiftRng) -> Option<LightSample>;
+
+    // None for lights at infinity, like directional lights and the sky
+    fn bounds(&self) -> Option<LightBounds>;
+
+    // Density of sample returning unit direction wi from p, per steradian. 0 for lights a ray can't
+    // find by chance, like point lights, those are only ever sampled
+    fn pdf(&self, _p: &Vec3, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    // Object id of the model this light is the emission of, if any
+    fn object_id(&self) -> Option<u32> {
+        None
+    }
+}
+
+pub fn point(position: Vec3, intensity: Vec3) -> PointLight {
+    PointLight {
+        position: position,
+        intensity: intensity,
+    }
+}
+
+// inner and outer are the cone's half angles in degrees, full intensity inside inner
+pub fn spot(position: Vec3, direction: Vec3, intensity: Vec3, inner: f64, outer: f64) -> SpotLight {
+    let outer = outer.max(inner);
+    SpotLight {
+        position: position,
+        direction: direction.normalized(),
+        intensity: intensity,
+        cos_inner: inner.to_radians().cos(),
+        cos_outer: outer.to_radians().cos(),
+    }
+}
+
+// Emits radiance from every point of the sphere's surface, like Material::diffuse_light on it
+pub fn sphere(sphere: &Sphere, radiance: Vec3) -> SphereLight {
+    SphereLight {
+        center: sphere.center,
+        radius: sphere.radius,
+        radiance: radiance,
+        object: None,
+    }
+}
+
+// direction the light travels in, e.g. down for a sun overhead
+pub fn directional(direction: Vec3, irradiance: Vec3) -> DirectionalLight {
+    DirectionalLight {
+        direction: direction.normalized(),
+        irradiance: irradiance,
+        cos_max: 1.0,
+    }
+}
+
+//
+// Point light, intensity is radiant intensity (power per steradian), falling off with the square
+// of distance
+//
+#[derive(Debug, Clone)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Vec3,
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: &Vec3, _rng: &mut XorShiftRng) -> Option<LightSample> {
+        let to_light = self.position - *p;
+        let distance2 = to_light.squared_length();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        Some(LightSample {
+            dir: to_light / distance,
+            distance: distance,
+            radiance: self.intensity / distance2,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            aabb: AABB { min: self.position, max: self.position },
+            power: 4.0 * PI * self.intensity.luminance(),
+            axis: Vec3::new(0.0,0.0,1.0),
+            theta_o: PI,
+            theta_e: PI / 2.0,
+            two_sided: false,
+        })
+    }
+}
+
+//
+// Spot light, a point light shining into a cone around direction
+//
+#[derive(Debug, Clone)]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Vec3,
+    cos_inner: f64,
+    cos_outer: f64,
+}
+
+impl SpotLight {
+    // Smooth falloff between the inner and outer cone
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_inner {
+            1.0
+        } else if cos_theta <= self.cos_outer {
+            0.0
+        } else {
+            let x = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            x * x * (3.0 - 2.0 * x)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: &Vec3, _rng: &mut XorShiftRng) -> Option<LightSample> {
+        let to_light = self.position - *p;
+        let distance2 = to_light.squared_length();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        let dir = to_light / distance;
+        let falloff = self.falloff(-dir.dot(self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            dir: dir,
+            distance: distance,
+            radiance: self.intensity * (falloff / distance2),
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        // Falloff averaged over the band between the cones
+        Some(LightBounds {
+            aabb: AABB { min: self.position, max: self.position },
+            power: 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer)) * self.intensity.luminance(),
+            axis: self.direction,
+            theta_o: self.cos_outer.max(-1.0).min(1.0).acos(),
+            theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+//
+// Distant light such as the sun, irradiance is measured perpendicular to direction
+// With an angular diameter directions are sampled over the disk it covers, for soft shadows
+//
+#[derive(Debug, Clone)]
+pub struct DirectionalLight {
+    pub direction: Vec3,
+    pub irradiance: Vec3,
+    cos_max: f64,
+}
+
+impl DirectionalLight {
+    // Angular diameter in degrees, about 0.53 for the sun
+    pub fn with_angular_diameter(mut self, degrees: f64) -> DirectionalLight {
+        self.cos_max = (degrees.max(0.0).min(180.0) / 2.0).to_radians().cos();
+        self
+    }
+}
+
+impl Light for DirectionalLight {
+    // Radiance over the disk is irradiance / solid angle, which cancels with the uniform pdf
+    fn sample(&self, _p: &Vec3, rng: &mut XorShiftRng) -> Option<LightSample> {
+        let to_light = -self.direction;
+        let dir = if self.cos_max < 1.0 {
+            let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - self.cos_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            Onb::from_w(to_light).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+        } else {
+            to_light
+        };
+        Some(LightSample {
+            dir: dir,
+            distance: f64::INFINITY,
+            radiance: self.irradiance,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+}
+
+//
+// Sphere light, sampled uniformly over the cone of directions it covers
+//
+#[derive(Debug, Clone)]
+pub struct SphereLight {
+    pub center: Vec3,
+    pub radius: f64,
+    pub radiance: Vec3,
+    object: Option<u32>,
+}
+
+impl SphereLight {
+    // The emissive model's ids.object, so its emission isn't counted again when hit
+    pub fn with_object_id(mut self, id: u32) -> SphereLight {
+        self.object = Some(id);
+        self
+    }
+}
+
+impl Light for SphereLight {
+    fn sample(&self, p: &Vec3, rng: &mut XorShiftRng) -> Option<LightSample> {
+        let to_center = self.center - *p;
+        let distance2 = to_center.squared_length();
+        let radius2 = self.radius * self.radius;
+        if distance2 <= radius2 {
+            // Inside, the light is found by scattering instead
+            return None;
+        }
+        let cos_max = (1.0 - radius2 / distance2).max(0.0).sqrt();
+        let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let dir = Onb::from_w(to_center.normalized()).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+
+        // Nearest intersection with the sphere, at the tangent if rounding misses it
+        let b = dir.dot(to_center);
+        let discriminant = (b * b - distance2 + radius2).max(0.0);
+        Some(LightSample {
+            dir: dir,
+            distance: b - discriminant.sqrt(),
+            radiance: self.radiance * (2.0 * PI * (1.0 - cos_max)),
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(LightBounds {
+            aabb: AABB { min: self.center - r, max: self.center + r },
+            power: PI * 4.0 * PI * self.radius * self.radius * self.radiance.luminance(),
+            axis: Vec3::new(0.0,0.0,1.0),
+            theta_o: PI,
+            theta_e: PI / 2.0,
+            two_sided: false,
+        })
+    }
+
+    // Uniform over the cone the sphere covers from p, inside it isn't sampled
+    fn pdf(&self, p: &Vec3, wi: Vec3) -> f64 {
+        let to_center = self.center - *p;
+        let distance2 = to_center.squared_length();
+        let radius2 = self.radius * self.radius;
+        if distance2 <= radius2 {
+            return 0.0;
+        }
+        let cos_max = (1.0 - radius2 / distance2).max(0.0).sqrt();
+        if wi.normalized().dot(to_center) < cos_max * distance2.sqrt() {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - cos_max))
+    }
+
+    fn object_id(&self) -> Option<u32> {
+        self.object
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_inverse_square() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let l = point(Vec3::new(0.0,2.0,0.0), Vec3::new(4.0,4.0,4.0));
+        let s = l.sample(&Vec3::new(0.0,0.0,0.0), &mut rng).unwrap();
+        assert!(s.dir == Vec3::new(0.0,1.0,0.0));
+        assert!(s.distance == 2.0);
+        assert!(s.radiance == Vec3::new(1.0,1.0,1.0));
+    }
+
+    #[test]
+    fn test_spot_cone() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let l = spot(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,-1.0,0.0), Vec3::new(1.0,1.0,1.0), 20.0, 30.0);
+        let at = |x: f64, rng: &mut XorShiftRng| l.sample(&Vec3::new(x,0.0,0.0), rng).map(|s| s.radiance.x * s.distance * s.distance);
+        assert!(at(0.0, &mut rng) == Some(1.0));
+        let edge = at(25f64.to_radians().tan(), &mut rng).unwrap();
+        assert!(edge > 0.0 && edge < 1.0);
+        assert!(at(1.0, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_directional_disk() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let l = directional(Vec3::new(0.0,-1.0,0.0), Vec3::new(1.0,1.0,1.0)).with_angular_diameter(10.0);
+        let cos_max = 5f64.to_radians().cos();
+        for _ in 0..100 {
+            let s = l.sample(&Vec3::new(0.0,0.0,0.0), &mut rng).unwrap();
+            assert!(s.dir.y >= cos_max - 1e-12);
+            assert!(s.distance == f64::INFINITY);
+        }
+    }
+
+    #[test]
+    fn test_sphere_samples_visible_cap() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let l = sphere(&Sphere { center: Vec3::new(0.0,4.0,0.0), radius: 1.0 }, Vec3::new(1.0,1.0,1.0));
+        let p = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..100 {
+            let s = l.sample(&p, &mut rng).unwrap();
+            assert!((s.dir.length() - 1.0).abs() < 1e-9);
+            let on_sphere = p + s.dir * s.distance;
+            assert!(((on_sphere - l.center).length() - 1.0).abs() < 1e-6);
+            assert!(on_sphere.y <= 4.0);
+            assert!((l.pdf(&p, s.dir) * s.radiance.x - 1.0).abs() < 1e-9);
+        }
+        assert!(l.pdf(&p, Vec3::new(1.0,0.0,0.0)) == 0.0);
+        assert!(l.sample(&Vec3::new(0.0,4.5,0.0), &mut rng).is_none());
+        assert!(l.pdf(&Vec3::new(0.0,4.5,0.0), Vec3::new(0.0,1.0,0.0)) == 0.0);
+    }
+}
@@ -0,0 +1,398 @@
+// Choosing which light to sample at a hit
+//
+// Sampling every light at every hit costs a shadow ray each, which doesn't scale past a handful.
+// The other strategies pick one light and divide what it gives by the probability it was picked:
+// uniformly, by power, or from a light tree, a BVH over the lights' bounds holding their power and
+// an orientation cone, walked down towards the lights likely to contribute most to the hit.
+// Lights at infinity have no bounds, they are picked uniformly as a group with the rest.
+
+extern crate rand;
+
+use light::{Light, LightBounds};
+use model::bvh::AABB;
+use vec3::Vec3;
+use rand::*;
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSampling {
+    All,     // every light at every hit, best for a few lights
+    Uniform, // one light, all equally likely
+    Power,   // one light in proportion to its power
+    Tree,    // one light in proportion to its estimated contribution, from a light tree
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf(usize),
+    Interior(usize, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct LightSampler {
+    pub strategy: LightSampling,
+    count: usize,
+    infinite: Vec<usize>,
+    bounded: Vec<usize>,    // with power, in the order of cdf
+    cdf: Vec<f64>,          // of power over bounded
+    nodes: Vec<(LightBounds, Node)>, // root last
+    parents: Vec<usize>,    // of each node, the root is its own
+    slots: Vec<Option<usize>>, // of each light in bounded
+    leaves: Vec<usize>,     // node of each light in bounded
+    objects: Vec<u32>,      // sorted object ids of lights standing in for emissive models
+}
+
+impl LightSampler {
+    pub fn new(lights: &[Box<Light>], strategy: LightSampling) -> LightSampler {
+        let mut infinite = vec![];
+        let mut bounded = vec![];
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(b) => if b.power > 0.0 {
+                    bounded.push((i, b));
+                },
+                None => infinite.push(i),
+            }
+        }
+
+        let mut cdf = Vec::with_capacity(bounded.len());
+        let mut total = 0.0;
+        for &(_, ref b) in &bounded {
+            total += b.power;
+            cdf.push(total);
+        }
+
+        let mut nodes = vec![];
+        if strategy == LightSampling::Tree && !bounded.is_empty() {
+            build(bounded.clone(), &mut nodes);
+        }
+
+        let mut slots = vec![None; lights.len()];
+        for (k, &(i, _)) in bounded.iter().enumerate() {
+            slots[i] = Some(k);
+        }
+        let mut parents: Vec<usize> = (0..nodes.len()).collect();
+        let mut leaves = vec![0; bounded.len()];
+        for (k, node) in nodes.iter().enumerate() {
+            match node.1 {
+                Node::Leaf(i) => leaves[slots[i].unwrap()] = k,
+                Node::Interior(left, right) => {
+                    parents[left] = k;
+                    parents[right] = k;
+                }
+            }
+        }
+
+        let mut objects: Vec<u32> = lights.iter().filter_map(|l| l.object_id()).collect();
+        objects.sort();
+
+        LightSampler {
+            strategy: strategy,
+            count: lights.len(),
+            infinite: infinite,
+            bounded: bounded.into_iter().map(|(i, _)| i).collect(),
+            cdf: cdf,
+            nodes: nodes,
+            parents: parents,
+            slots: slots,
+            leaves: leaves,
+            objects: objects,
+        }
+    }
+
+    // True if a light samples the emission of the model with this object id
+    pub fn is_light_object(&self, object: u32) -> bool {
+        self.objects.binary_search(&object).is_ok()
+    }
+
+    // One light for a hit at p with normal n, and the probability it was picked. None with All
+    pub fn pick(&self, p: &Vec3, n: &Vec3, rng: &mut XorShiftRng) -> Option<(usize, f64)> {
+        match self.strategy {
+            LightSampling::All => None,
+            LightSampling::Uniform => {
+                if self.count == 0 {
+                    return None;
+                }
+                let i = ((rng.gen::<f64>() * self.count as f64) as usize).min(self.count - 1);
+                Some((i, 1.0 / self.count as f64))
+            }
+            LightSampling::Power | LightSampling::Tree => {
+                // Infinite lights each count as much as all the bounded ones together
+                let has_bounded = if self.bounded.is_empty() { 0 } else { 1 };
+                let groups = self.infinite.len() + has_bounded;
+                if groups == 0 {
+                    return None;
+                }
+                let i = ((rng.gen::<f64>() * groups as f64) as usize).min(groups - 1);
+                if i < self.infinite.len() {
+                    return Some((self.infinite[i], 1.0 / groups as f64));
+                }
+                let picked = if self.strategy == LightSampling::Power {
+                    self.pick_power(rng)
+                } else {
+                    self.pick_tree(p, n, rng)
+                };
+                picked.map(|(i, probability)| (i, probability / groups as f64))
+            }
+        }
+    }
+
+    // Probability pick returns light i for a hit at p with normal n, 1 with All
+    pub fn probability(&self, p: &Vec3, n: &Vec3, i: usize) -> f64 {
+        match self.strategy {
+            LightSampling::All => 1.0,
+            LightSampling::Uniform => if i < self.count { 1.0 / self.count as f64 } else { 0.0 },
+            LightSampling::Power | LightSampling::Tree => {
+                let has_bounded = if self.bounded.is_empty() { 0 } else { 1 };
+                let groups = (self.infinite.len() + has_bounded) as f64;
+                if self.infinite.contains(&i) {
+                    return 1.0 / groups;
+                }
+                let k = match self.slots.get(i) {
+                    Some(&Some(k)) => k,
+                    _ => return 0.0,
+                };
+                let probability = if self.strategy == LightSampling::Power {
+                    let power = if k == 0 { self.cdf[0] } else { self.cdf[k] - self.cdf[k-1] };
+                    power / *self.cdf.last().unwrap()
+                } else {
+                    self.tree_probability(p, n, self.leaves[k])
+                };
+                probability / groups
+            }
+        }
+    }
+
+    fn pick_power(&self, rng: &mut XorShiftRng) -> Option<(usize, f64)> {
+        let total = *self.cdf.last().unwrap();
+        let u = rng.gen::<f64>() * total;
+        let i = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }.min(self.cdf.len() - 1);
+        let power = if i == 0 { self.cdf[0] } else { self.cdf[i] - self.cdf[i-1] };
+        Some((self.bounded[i], power / total))
+    }
+
+    fn pick_tree(&self, p: &Vec3, n: &Vec3, rng: &mut XorShiftRng) -> Option<(usize, f64)> {
+        let mut node = self.nodes.len() - 1;
+        let mut probability = 1.0;
+        loop {
+            match self.nodes[node].1 {
+                Node::Leaf(i) => {
+                    // A lone light can still be facing away
+                    if self.nodes[node].0.importance(p, n) <= 0.0 {
+                        return None;
+                    }
+                    return Some((i, probability));
+                }
+                Node::Interior(left, right) => {
+                    let l = self.nodes[left].0.importance(p, n);
+                    let r = self.nodes[right].0.importance(p, n);
+                    if l + r <= 0.0 {
+                        return None;
+                    }
+                    let p_left = l / (l + r);
+                    if rng.gen::<f64>() < p_left {
+                        probability *= p_left;
+                        node = left;
+                    } else {
+                        probability *= 1.0 - p_left;
+                        node = right;
+                    }
+                }
+            }
+        }
+    }
+
+    // Probability pick_tree walks down to this leaf
+    fn tree_probability(&self, p: &Vec3, n: &Vec3, leaf: usize) -> f64 {
+        if self.nodes[leaf].0.importance(p, n) <= 0.0 {
+            return 0.0;
+        }
+        let mut probability = 1.0;
+        let mut node = leaf;
+        while self.parents[node] != node {
+            let parent = self.parents[node];
+            if let Node::Interior(left, right) = self.nodes[parent].1 {
+                let l = self.nodes[left].0.importance(p, n);
+                let r = self.nodes[right].0.importance(p, n);
+                if l + r <= 0.0 {
+                    return 0.0;
+                }
+                probability *= if node == left { l } else { r } / (l + r);
+            }
+            node = parent;
+        }
+        probability
+    }
+}
+
+// Split at the median along the widest axis of the lights' centers, children pushed before their
+// parent. Returns the index of the subtree's root
+fn build(mut lights: Vec<(usize, LightBounds)>, nodes: &mut Vec<(LightBounds, Node)>) -> usize {
+    if lights.len() == 1 {
+        nodes.push((lights[0].1, Node::Leaf(lights[0].0)));
+        return nodes.len() - 1;
+    }
+
+    let center = |b: &LightBounds| (b.aabb.min + b.aabb.max) * 0.5;
+    let mut lo = center(&lights[0].1);
+    let mut hi = lo;
+    for &(_, ref b) in &lights {
+        let c = center(b);
+        lo = Vec3::map2(lo, c, &|x, y| x.min(y));
+        hi = Vec3::map2(hi, c, &|x, y| x.max(y));
+    }
+    let extent = hi - lo;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    let coordinate = move |b: &LightBounds| {
+        let c = center(b);
+        match axis { 0 => c.x, 1 => c.y, _ => c.z }
+    };
+    lights.sort_by(|a, b| coordinate(&a.1).partial_cmp(&coordinate(&b.1)).unwrap());
+
+    let n = lights.len();
+    let right_lights = lights.split_off(n / 2);
+    let left = build(lights, nodes);
+    let right = build(right_lights, nodes);
+    let bounds = union(&nodes[left].0, &nodes[right].0);
+    nodes.push((bounds, Node::Interior(left, right)));
+    nodes.len() - 1
+}
+
+fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+    let (axis, theta_o) = union_cone(a.axis, a.theta_o, b.axis, b.theta_o);
+    LightBounds {
+        aabb: AABB::surrounding_box(&a.aabb, &b.aabb),
+        power: a.power + b.power,
+        axis: axis,
+        theta_o: theta_o,
+        theta_e: a.theta_e.max(b.theta_e),
+        two_sided: a.two_sided || b.two_sided,
+    }
+}
+
+// Smallest cone holding two cones, given as axes and half angles
+fn union_cone(a: Vec3, theta_a: f64, b: Vec3, theta_b: f64) -> (Vec3, f64) {
+    let theta_d = a.dot(b).max(-1.0).min(1.0).acos();
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (a, theta_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (b, theta_b);
+    }
+
+    let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+    if theta_o >= PI {
+        return (a, PI);
+    }
+
+    // Turn a towards b, keeping both cones' far edges inside
+    let turn = theta_o - theta_a;
+    let k = a.cross(b);
+    if k.squared_length() == 0.0 {
+        return (a, PI);
+    }
+    let k = k.normalized();
+    let axis = a * turn.cos() + k.cross(a) * turn.sin();
+    (axis.normalized(), theta_o)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use light;
+
+    fn points(n: usize) -> Vec<Box<Light>> {
+        (0..n).map(|i| {
+            Box::new(light::point(Vec3::new(i as f64, 1.0, 0.0), Vec3::new(1.0,1.0,1.0) * (i + 1) as f64)) as Box<Light>
+        }).collect()
+    }
+
+    // How often each light is picked against the probability reported for it
+    fn check_probabilities(lights: &[Box<Light>], strategy: LightSampling, p: &Vec3, n: &Vec3) {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let sampler = LightSampler::new(lights, strategy);
+        let mut counts = vec![0; lights.len()];
+        let mut reported = vec![0.0; lights.len()];
+        let trials = 100000;
+        for _ in 0..trials {
+            if let Some((i, probability)) = sampler.pick(p, n, &mut rng) {
+                counts[i] += 1;
+                reported[i] = probability;
+            }
+        }
+        for i in 0..lights.len() {
+            let frequency = counts[i] as f64 / trials as f64;
+            assert!((frequency - reported[i]).abs() < 0.01);
+            assert!(counts[i] == 0 || (sampler.probability(p, n, i) - reported[i]).abs() < 1e-9);
+            assert!((frequency - sampler.probability(p, n, i)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_probabilities_match() {
+        let lights = points(7);
+        let (p, n) = (Vec3::new(2.0,0.0,0.0), Vec3::new(0.0,1.0,0.0));
+        check_probabilities(&lights, LightSampling::Uniform, &p, &n);
+        check_probabilities(&lights, LightSampling::Power, &p, &n);
+        check_probabilities(&lights, LightSampling::Tree, &p, &n);
+    }
+
+    #[test]
+    fn test_power_proportional() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let sampler = LightSampler::new(&points(4), LightSampling::Power);
+        for _ in 0..100 {
+            let (i, probability) = sampler.pick(&Vec3::new(0.0,0.0,0.0), &Vec3::new(0.0,1.0,0.0), &mut rng).unwrap();
+            assert!((probability - (i + 1) as f64 / 10.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_tree_prefers_near_lights() {
+        // Two equal lights, one right above the point and one far off
+        let lights: Vec<Box<Light>> = vec![
+            Box::new(light::point(Vec3::new(0.0,1.0,0.0), Vec3::new(1.0,1.0,1.0))),
+            Box::new(light::point(Vec3::new(100.0,1.0,0.0), Vec3::new(1.0,1.0,1.0))),
+        ];
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let sampler = LightSampler::new(&lights, LightSampling::Tree);
+        let near = (0..1000).filter(|_| sampler.pick(&Vec3::new(0.0,0.0,0.0), &Vec3::new(0.0,1.0,0.0), &mut rng).unwrap().0 == 0).count();
+        assert!(near > 990);
+    }
+
+    #[test]
+    fn test_tree_skips_lights_behind() {
+        // A bright spot pointing away from the point
+        let lights: Vec<Box<Light>> = vec![
+            Box::new(light::spot(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,1.0,0.0), Vec3::new(100.0,100.0,100.0), 10.0, 20.0)),
+            Box::new(light::point(Vec3::new(3.0,2.0,0.0), Vec3::new(1.0,1.0,1.0))),
+        ];
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let sampler = LightSampler::new(&lights, LightSampling::Tree);
+        for _ in 0..100 {
+            let (i, _) = sampler.pick(&Vec3::new(0.0,0.0,0.0), &Vec3::new(0.0,1.0,0.0), &mut rng).unwrap();
+            assert!(i == 1);
+        }
+    }
+
+    #[test]
+    fn test_union_cone_contains_both() {
+        let a = Vec3::new(0.0,0.0,1.0);
+        let b = Vec3::new(1.0,0.0,0.0);
+        let (axis, theta) = union_cone(a, 0.1, b, 0.2);
+        for &(d, t) in &[(a, 0.1), (b, 0.2)] {
+            assert!(axis.dot(d).acos() + t <= theta + 1e-9);
+        }
+        assert!((theta - (0.1 + PI / 2.0 + 0.2) / 2.0).abs() < 1e-9);
+    }
+}
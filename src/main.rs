@@ -2,18 +2,27 @@ extern crate image;
 extern crate raytracer;
 
 use raytracer::*;
+use raytracer::animation::{Animation, AnimatedCamera, AnimatedObject, CameraKeyframe, Keyframes, Transform};
 use raytracer::vec3::{Vec3};
 use raytracer::camera::Camera;
 use raytracer::model::{hitable, Renderable, Model};
+use raytracer::model::bvh::BVHItem;
 use raytracer::model::hitable::{flip_normals, translate, rotate};
 use raytracer::model::cube::Cube;
 use raytracer::model::rect::Rect;
 use raytracer::model::sphere::Sphere;
 use raytracer::model::constant_medium::ConstantMedium;
+use raytracer::model::heterogeneous_medium::HeterogeneousMedium;
 use raytracer::shader::texture;
-use raytracer::shader::material::Material;
+use raytracer::light_sampler::LightSampling;
+use raytracer::scene::Scene;
+use raytracer::shader::material::{Material, MetalPreset};
+use raytracer::shader::phase::Phase;
+use raytracer::sky::Sky;
 use raytracer::util::Axis;
 
+use image::GenericImage;
+
 use std::io::Write;
 use std::fs::File;
 use std::path::Path;
@@ -84,7 +93,7 @@ fn main() {
     let mat_green = Material::lambertian_constant(Vec3::new(0.12,0.45,0.15));
     let mat_white = Material::lambertian_constant(Vec3::new(0.73,0.73,0.73));
     // let mat_light = Material::diffuse_light_constant(Vec3::new(15.0,15.0,15.0));
-    let mat_light = Material::diffuse_light_constant(Vec3::new(5.0,5.0,5.0));
+    let mat_light = Material::diffuse_light_constant(Vec3::new(5.0,5.0,5.0)).one_sided();
     let mat_iso   = Material::isotropic_constant(Vec3::new(1.0,1.0,1.0));
     let world: Box<Vec<Box<Renderable>>> = Box::new(vec![
         // Colored walls
@@ -96,10 +105,10 @@ fn main() {
             Rect::yz_rect(0.0,555.0,0.0,555.0,0.0),
             mat_red.clone()
         )),
-        // Light
+        // Light, facing down
         Box::new(Model::new(
-            // Rect::xz_rect(213.0,343.0,227.0,332.0,554.0), // small light
-            Rect::xz_rect(113.0,443.0,127.0,432.0,554.0), // big light
+            // flip_normals(Rect::xz_rect(213.0,343.0,227.0,332.0,554.0)), // small light
+            flip_normals(Rect::xz_rect(113.0,443.0,127.0,432.0,554.0)), // big light
             mat_light.clone()
         )),
         // Ceiling
@@ -173,6 +182,175 @@ fn main() {
         Some(ref mode) if mode == "bench" => {
             bench::bench_rays_per_sec(30)
         }
+        Some(ref mode) if mode == "animate" => {
+            // animate [first frame] [last frame] [fps]
+            let arg = |n: usize, default: f64| {
+                std::env::args().nth(n).map(|x| x.parse::<f64>().expect("Invalid animate argument")).unwrap_or(default)
+            };
+            let (first, last, fps) = (arg(2, 1.0) as i32, arg(3, 24.0) as i32, arg(4, 24.0));
+            render_animation(cornell_animation(fps), first, last);
+        }
+        Some(ref mode) if mode == "aovs" => {
+            // Beauty plus every output variable as float images in out/<name>.pfm
+            let settings = render::Settings {
+                debug: true,
+                aovs: film::Aov::all(),
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film.to_rgb(), NX, NY, "out/out.png", Filetype::PNG);
+            film::write_pfm(&film.beauty, NX, NY, "out/beauty.pfm").unwrap();
+            for &(aov, ref data) in &film.aovs {
+                film::write_pfm(data, NX, NY, &format!("out/{}.pfm", aov.name())).unwrap();
+            }
+        }
+        Some(ref mode) if mode == "spectral" => {
+            // Adds a diamond on the short cube and renders with wavelengths, so it disperses light
+            let mut world = world;
+            world.push(Box::new(Model::new(
+                Sphere {
+                    center: Vec3::new(212.0,235.0,147.0),
+                    radius: 70.0,
+                },
+                Material::dispersive_dielectric(spectrum::Ior::diamond(), 0.0),
+            )));
+            let settings = render::Settings {
+                debug: true,
+                spectral: true,
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film.to_rgb(), NX, NY, "out/out.png", Filetype::PNG);
+        }
+        Some(ref mode) if mode == "lights" => {
+            // lights [all|uniform|power|tree]: random scene lit by hundreds of glowing spheres
+            let arg = std::env::args().nth(2).unwrap_or("tree".to_string());
+            let strategy = match arg.as_str() {
+                "all" => LightSampling::All,
+                "uniform" => LightSampling::Uniform,
+                "power" => LightSampling::Power,
+                "tree" => LightSampling::Tree,
+                s => panic!("Unknown light sampling: {}", s),
+            };
+            let world = render::random_lights_scene(strategy);
+            let lookfrom = Vec3::new(16.0, 2.0, 4.0);
+            let lookat = Vec3::new(-3.0, 0.5, -1.0);
+            let camera = Camera::new(
+                lookfrom,
+                lookat,
+                Vec3::new(0.0, 1.0, 0.0),
+                15.0,
+                (NX as f64) / (NY as f64),
+                0.1,
+                (lookfrom - lookat).length(),
+            );
+            let settings = render::Settings {
+                debug: true,
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film.to_rgb(), NX, NY, "out/out.png", Filetype::PNG);
+        }
+        Some(ref mode) if mode == "sky" => {
+            // sky [hour]: outdoor scene under the daylight model, solar time in hours
+            let hour = std::env::args().nth(2).map(|x| x.parse::<f64>().expect("Invalid hour")).unwrap_or(10.0);
+            let (world, camera) = outdoor_scene(hour);
+            let settings = render::Settings {
+                debug: true,
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film.to_rgb(), NX, NY, "out/out.png", Filetype::PNG);
+        }
+        Some(ref mode) if mode == "volume" => {
+            // volume path [scattering]: smoke from a sparse volume file (see volume) over a ground
+            // plane in daylight, scattering per unit density and world unit
+            let path = std::env::args().nth(2).expect("Missing volume path");
+            let sigma_s = std::env::args().nth(3).map(|x| x.parse::<f64>().expect("Invalid scattering")).unwrap_or(1.0);
+            let grid = volume::SparseGrid::load(&path).unwrap_or_else(|e| panic!("Could not load {}: {}", path, e));
+            let (world, camera) = volume_scene(grid, sigma_s);
+            let settings = render::Settings {
+                debug: true,
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film.to_rgb(), NX, NY, "out/out.png", Filetype::PNG);
+        }
+        Some(ref mode) if mode == "denoise" => {
+            // denoise [--atrous] [--noisy]
+            // --atrous: a-trous wavelet filter instead of cross bilateral
+            // --noisy: also write the image before denoising to out/noisy.png
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            let settings = render::Settings {
+                debug: true,
+                aovs: denoise::feature_aovs(),
+                .. render::Settings::new(NX, NY, NUM_SAMPLES)
+            };
+            let film = render::render_film(world, Arc::new(camera), &settings);
+
+            let denoise_settings = if args.iter().any(|a| a == "--atrous") {
+                denoise::Settings::a_trous()
+            } else {
+                denoise::Settings::cross_bilateral()
+            };
+            let denoised = denoise::denoise(&film, &denoise_settings);
+
+            std::fs::create_dir_all("out").unwrap();
+            save_file(&film::to_rgb(&denoised), NX, NY, "out/out.png", Filetype::PNG);
+            if args.iter().any(|a| a == "--noisy") {
+                save_file(&film.to_rgb(), NX, NY, "out/noisy.png", Filetype::PNG);
+            }
+        }
+        Some(ref mode) if mode == "crop" => {
+            // crop x0 y0 x1 y1 [--window] [--full]
+            // --window: coordinates are normalized [0,1] instead of pixels
+            // --full: composite into out/out.png, keeping any regions already rendered there
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            let coords: Vec<f64> = args.iter()
+                .filter(|a| !a.starts_with("--"))
+                .map(|a| a.parse::<f64>().expect("Invalid crop coordinate"))
+                .collect();
+            assert!(coords.len() == 4, "crop takes x0 y0 x1 y1");
+            let region = if args.iter().any(|a| a == "--window") {
+                render::Region::from_window(NX, NY, coords[0], coords[1], coords[2], coords[3])
+            } else {
+                render::Region { x0: coords[0] as i32, y0: coords[1] as i32, x1: coords[2] as i32, y1: coords[3] as i32 }
+            };
+
+            let outbuf = render::render_region(world, Arc::new(camera), NX, NY, NUM_SAMPLES, 4, true, region);
+
+            std::fs::create_dir_all("out").unwrap();
+            if args.iter().any(|a| a == "--full") {
+                let path = "out/out.png";
+                let full = match image::open(&Path::new(path)) {
+                    // Resume: paste over what was rendered previously, unless it was another size
+                    Ok(ref img) if img.dimensions() == (NX as u32, NY as u32) => {
+                        let mut prev: Vec<i32> = img.to_rgba().into_raw().iter().map(|&x| x as i32).collect();
+                        let crop = render::composite_rgba(NX, NY, &region, &outbuf);
+                        for (i, px) in crop.chunks(4).enumerate() {
+                            if px[3] != 0 {
+                                prev[i*4..i*4+4].copy_from_slice(px);
+                            }
+                        }
+                        prev
+                    }
+                    _ => render::composite_rgba(NX, NY, &region, &outbuf),
+                };
+                save_file(&full, NX, NY, path, Filetype::PNG);
+            } else {
+                save_file(&outbuf, region.width(), region.height(), "out/crop.png", Filetype::PNG);
+            }
+        }
         _ => {
 
             let (outbuf, runtime) = bench::time(|| {
@@ -188,12 +366,157 @@ fn main() {
     }
 }
 
+// Spheres on a ground plane lit by sky and sun, mid latitude in early summer
+fn outdoor_scene(hour: f64) -> (Box<Renderable + Sync>, Camera) {
+    let world: Vec<Box<Renderable>> = vec![
+        Box::new(Model::new(
+            Sphere { center: Vec3::new(0.0,-1000.0,0.0), radius: 1000.0 },
+            Material::lambertian_constant(Vec3::new(0.4,0.4,0.35)),
+        )),
+        Box::new(Model::new(
+            Sphere { center: Vec3::new(-2.2,1.0,0.0), radius: 1.0 },
+            Material::principled_constant(Vec3::new(0.7,0.1,0.1)).with_roughness(0.3).with_clearcoat(1.0, 0.05),
+        )),
+        Box::new(Model::new(
+            Sphere { center: Vec3::new(0.0,1.0,0.0), radius: 1.0 },
+            Material::dielectric(1.5),
+        )),
+        Box::new(Model::new(
+            Sphere { center: Vec3::new(2.2,1.0,0.0), radius: 1.0 },
+            Material::metal_preset(MetalPreset::Gold, 0.2),
+        )),
+    ];
+    let sky = Sky::from_time(45.0, 172.0, hour, 3.0, Vec3::new(0.3,0.3,0.3)).with_intensity(0.05);
+
+    let lookfrom = Vec3::new(0.0, 2.0, 10.0);
+    let lookat = Vec3::new(0.0, 1.0, 0.0);
+    let camera = Camera::new(
+        lookfrom,
+        lookat,
+        Vec3::new(0.0, 1.0, 0.0),
+        30.0,
+        (NX as f64) / (NY as f64),
+        0.0,
+        (lookfrom - lookat).length(),
+    );
+    (Box::new(Scene::new(world).with_sky(sky)), camera)
+}
+
+// A sparse volume as smoke sitting on the ground, lit by a midday sky
+fn volume_scene(grid: volume::SparseGrid, sigma_s: f64) -> (Box<Renderable + Sync>, Camera) {
+    let bounds = grid.bounds;
+    let center = (bounds.min + bounds.max) * 0.5;
+    let extent = (bounds.max - bounds.min).length();
+    let majorants = grid.majorant_grid();
+    let max = grid.max();
+    let smoke = HeterogeneousMedium::new(
+        Cube::new_from_min_max(bounds.min, bounds.max),
+        grid,
+        max,
+        texture::constant_texture(Vec3::new(0.8,0.8,0.8)),
+        0.1 * sigma_s,
+        sigma_s,
+    ).with_phase(Phase::HenyeyGreenstein(0.5)).with_majorant_grid(majorants);
+
+    let world: Vec<Box<Renderable>> = vec![
+        Box::new(Model::new(
+            Sphere { center: Vec3::new(center.x, bounds.min.y - 1000.0 * extent, center.z), radius: 1000.0 * extent },
+            Material::lambertian_constant(Vec3::new(0.4,0.4,0.35)),
+        )),
+        Box::new(smoke),
+    ];
+    let sky = Sky::from_time(45.0, 172.0, 12.0, 3.0, Vec3::new(0.3,0.3,0.3)).with_intensity(0.05);
+
+    let lookfrom = center + Vec3::new(0.0, 0.3, 1.5) * extent;
+    let camera = Camera::new(
+        lookfrom,
+        center,
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        (NX as f64) / (NY as f64),
+        0.0,
+        (lookfrom - center).length(),
+    );
+    (Box::new(Scene::new(world).with_sky(sky)), camera)
+}
+
+// Cornell box with the tall cube spinning while the camera dollies in
+fn cornell_animation(fps: f64) -> Animation {
+    let mat_red   = Material::lambertian_constant(Vec3::new(0.65,0.05,0.05));
+    let mat_green = Material::lambertian_constant(Vec3::new(0.12,0.45,0.15));
+    let mat_white = Material::lambertian_constant(Vec3::new(0.73,0.73,0.73));
+    let mat_light = Material::diffuse_light_constant(Vec3::new(5.0,5.0,5.0)).one_sided();
+
+    let walls: Vec<Box<BVHItem>> = vec![
+        Box::new(Model::new(flip_normals(Rect::yz_rect(0.0,555.0,0.0,555.0,555.0)), mat_green.clone())),
+        Box::new(Model::new(Rect::yz_rect(0.0,555.0,0.0,555.0,0.0), mat_red.clone())),
+        Box::new(Model::new(flip_normals(Rect::xz_rect(113.0,443.0,127.0,432.0,554.0)), mat_light.clone())),
+        Box::new(Model::new(flip_normals(Rect::xz_rect(0.0,555.0,0.0,555.0,555.0)), mat_white.clone())),
+        Box::new(Model::new(Rect::xz_rect(0.0,555.0,0.0,555.0,0.0), mat_white.clone())),
+        Box::new(Model::new(flip_normals(Rect::xy_rect(0.0,555.0,0.0,555.0,555.0)), mat_white.clone())),
+        Box::new(Model::new(
+            translate(rotate(Cube::new(Vec3::new(165.0,165.0,165.0)), Axis::Y, -18.0), Vec3::new(130.0,0.0,65.0)),
+            mat_white.clone()
+        )),
+    ];
+
+    let spinning_cube = AnimatedObject::new(
+        Transform {
+            translation: Keyframes::constant(Vec3::new(265.0,0.0,295.0)),
+            axis: Axis::Y,
+            rotation: Keyframes::new(vec![(0.0, 15.0), (1.0, 105.0)]),
+        },
+        move |axis, degrees, offset| {
+            Box::new(Model::new(
+                translate(rotate(Cube::new(Vec3::new(165.0,330.0,165.0)), axis, degrees), offset),
+                mat_white.clone()
+            ))
+        }
+    );
+
+    let lookat = Vec3::new(275.0,275.0,0.0);
+    let key = |lookfrom: Vec3| CameraKeyframe {
+        lookfrom: lookfrom,
+        lookat: lookat,
+        vfov: 35.0,
+        focus_dist: (lookfrom - lookat).length(),
+    };
+    let camera = AnimatedCamera {
+        keys: Keyframes::new(vec![
+            (0.0, key(Vec3::new(275.0,275.0,-950.0))),
+            (1.0, key(Vec3::new(275.0,275.0,-700.0))),
+        ]),
+        vup: Vec3::new(0.0, 1.0, 0.0),
+        aspect: (NX as f64) / (NY as f64),
+        aperture: 0.0,
+    };
+
+    Animation::new(camera, fps, walls, vec![spinning_cube])
+}
+
+// Renders frames first..=last into out/frame_0001.png ...
+fn render_animation(mut animation: Animation, first: i32, last: i32) {
+    std::fs::create_dir_all("out").unwrap();
+    for frame in first..(last+1) {
+        let scene = animation.scene_at(frame);
+        let camera = animation.camera_at(frame);
+        let (outbuf, runtime) = bench::time(|| {
+            render::render(scene, Arc::new(camera), NX, NY, NUM_SAMPLES, 4, false)
+        });
+
+        let path = format!("out/frame_{:04}.png", frame);
+        save_file(&outbuf, NX, NY, &path, Filetype::PNG);
+        writeln!(&mut ::std::io::stderr(), "Frame {} in {} seconds: {}", frame, runtime, path).unwrap();
+    }
+}
+
 // Supported output filetypes
 enum Filetype {
     PPM,
     PNG
 }
 
+// data is RGB, or RGBA for PNG, by its length
 fn save_file(data: &Vec<i32>, nx: i32, ny: i32, output_path: &str, filetype: Filetype) {
     match filetype {
         Filetype::PPM => {
@@ -203,7 +526,7 @@ fn save_file(data: &Vec<i32>, nx: i32, ny: i32, output_path: &str, filetype: Fil
             let mut file = File::create(&path).unwrap();
 
             // Metadata
-            file.write_fmt(format_args!("P3\n{} {}\n{}\n", NX, NY, 255)).unwrap();
+            file.write_fmt(format_args!("P3\n{} {}\n{}\n", nx, ny, 255)).unwrap();
 
             // Data, one rgb triple per line
             let mut i = 0;
@@ -217,7 +540,8 @@ fn save_file(data: &Vec<i32>, nx: i32, ny: i32, output_path: &str, filetype: Fil
         }
         Filetype::PNG => {
             let outbuf_u8: Vec<u8> = data.iter().map(|&x| { x as u8 }).collect();
-            image::save_buffer(&Path::new(output_path), &outbuf_u8, nx as u32, ny as u32, image::RGB(8)).unwrap();
+            let color = if data.len() == (nx * ny * 4) as usize { image::RGBA(8) } else { image::RGB(8) };
+            image::save_buffer(&Path::new(output_path), &outbuf_u8, nx as u32, ny as u32, color).unwrap();
         }
     }
 }
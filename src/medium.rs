@@ -0,0 +1,276 @@
+// Media the path travels through: fog filling the scene, or the inside of an object like tinted
+// glass, murky water or milk
+//
+// Unlike model::constant_medium, which is a volume object hit like any other, these are tracked
+// along the path. A dielectric carries the medium inside it (material::Dielectric::with_medium_inside)
+// or the scene names it by object id (scene::Scene::with_medium_inside), and every path keeps a stack of the objects it is inside of, pushed and popped where it crosses
+// their surfaces. Where objects overlap the one with the highest priority wins, and surfaces of
+// objects with a lower priority than the one the path is in are passed through as if not there,
+// e.g. water in a glass gives the water priority over the glass. Outside of everything the path is
+// in the scene's atmosphere, if any.
+//
+// Media may glow (HomogeneousMedium::with_emission), emitting in proportion to what they absorb.
+
+extern crate rand;
+
+use shader::phase::Phase;
+use ray::Ray;
+use vec3::Vec3;
+use rand::*;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+// Where a path travelling through a medium stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediumSample {
+    pub t: Option<f64>, // where it scattered, None if it went all the way
+    pub weight: Vec3,   // what it carries from there, over the pdf of what happened
+}
+
+pub trait Medium: Debug + Sync + Send {
+    // Follow r from its origin up to t_max, scattering or not
+    fn sample(&self, r: &Ray, t_max: f64, rng: &mut XorShiftRng) -> MediumSample;
+
+    // Fraction of light getting from the origin of r to t_max
+    fn transmittance(&self, r: &Ray, t_max: f64, rng: &mut XorShiftRng) -> Vec3;
+
+    // Light given off along r up to t_max that reaches its origin, on top of what sample carries
+    fn emitted(&self, r: &Ray, t_max: f64, rng: &mut XorShiftRng) -> Vec3;
+
+    fn phase(&self) -> Phase;
+}
+
+pub fn homogeneous(sigma_a: Vec3, sigma_s: Vec3) -> HomogeneousMedium {
+    HomogeneousMedium {
+        sigma_a: sigma_a,
+        sigma_s: sigma_s,
+        phase: Phase::Isotropic,
+        emission: Vec3::new(0.0,0.0,0.0),
+    }
+}
+
+// Absorbing only, light passing through distance d keeps color^(d / distance), e.g. for tinted
+// glass
+pub fn absorbing(color: Vec3, distance: f64) -> HomogeneousMedium {
+    homogeneous(color.map(&|c| -c.max(1e-9).min(1.0).ln() / distance), Vec3::new(0.0,0.0,0.0))
+}
+
+//
+// Homogeneous medium, coefficients per channel in inverse world units
+//
+#[derive(Debug, Clone)]
+pub struct HomogeneousMedium {
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    pub phase: Phase,
+    pub emission: Vec3,
+}
+
+impl HomogeneousMedium {
+    pub fn with_phase(mut self, phase: Phase) -> HomogeneousMedium {
+        self.phase = phase;
+        self
+    }
+
+    // Radiance given off where it absorbs, e.g. glowing gas or a spectrum::blackbody color. Deep
+    // enough it reaches emission in each channel that absorbs and doesn't scatter
+    pub fn with_emission(mut self, emission: Vec3) -> HomogeneousMedium {
+        self.emission = emission;
+        self
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    // Distance from one channel's extinction chosen at random, weighted by the pdf averaged over
+    // the channels so all three are unbiased
+    fn sample(&self, r: &Ray, t_max: f64, rng: &mut XorShiftRng) -> MediumSample {
+        // Nothing to scatter off, only dimmed
+        if self.sigma_s == Vec3::new(0.0,0.0,0.0) {
+            return MediumSample {
+                t: None,
+                weight: self.transmittance(r, t_max, rng),
+            };
+        }
+
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let speed = r.dir.length();
+        let channel = match (rng.gen::<f64>() * 3.0) as i32 {
+            0 => sigma_t.x,
+            1 => sigma_t.y,
+            _ => sigma_t.z,
+        };
+        let distance = if channel > 0.0 { -(1.0 - rng.gen::<f64>()).ln() / channel } else { f64::INFINITY };
+
+        let scattered = distance < t_max * speed;
+        let d = if scattered { distance } else { t_max * speed };
+        let transmittance = sigma_t.map(&|s| (-s * d).exp());
+        if scattered {
+            let pdf = (sigma_t * transmittance).dot(Vec3::new(1.0,1.0,1.0)) / 3.0;
+            MediumSample {
+                t: Some(distance / speed),
+                weight: self.sigma_s * transmittance / pdf,
+            }
+        } else {
+            let pdf = transmittance.dot(Vec3::new(1.0,1.0,1.0)) / 3.0;
+            MediumSample {
+                t: None,
+                weight: if pdf > 0.0 { transmittance / pdf } else { Vec3::new(0.0,0.0,0.0) },
+            }
+        }
+    }
+
+    fn transmittance(&self, r: &Ray, t_max: f64, _rng: &mut XorShiftRng) -> Vec3 {
+        let d = t_max * r.dir.length();
+        (self.sigma_a + self.sigma_s).map(&|s| (-s * d).exp())
+    }
+
+    // Integral of transmittance times sigma_a times emission, in closed form
+    fn emitted(&self, r: &Ray, t_max: f64, _rng: &mut XorShiftRng) -> Vec3 {
+        let d = t_max * r.dir.length();
+        let fraction = Vec3::map2(self.sigma_a, self.sigma_a + self.sigma_s, &|a, t| {
+            if t > 0.0 { a / t * (1.0 - (-t * d).exp()) } else { 0.0 }
+        });
+        self.emission * fraction
+    }
+
+    fn phase(&self) -> Phase {
+        self.phase
+    }
+}
+
+// What fills an object's inside, None for vacuum (or air)
+#[derive(Debug, Clone)]
+pub struct Interior {
+    pub medium: Option<Arc<Medium>>,
+    pub priority: i32,
+}
+
+//
+// Objects a path is inside of, by object id
+//
+#[derive(Debug, Clone, Default)]
+pub struct MediumStack {
+    inside: Vec<(u32, i32)>,
+}
+
+impl MediumStack {
+    pub fn new() -> MediumStack {
+        MediumStack { inside: vec![] }
+    }
+
+    // Object with the highest priority the path is in, the latest entered of equals
+    pub fn top(&self) -> Option<u32> {
+        let mut top: Option<(u32, i32)> = None;
+        for &(object, priority) in &self.inside {
+            if top.map_or(true, |(_, p)| priority >= p) {
+                top = Some((object, priority));
+            }
+        }
+        top.map(|(object, _)| object)
+    }
+
+    // Highest priority of the objects the path is in other than object
+    pub fn highest_priority_besides(&self, object: u32) -> Option<i32> {
+        self.inside.iter().filter(|&&(o, _)| o != object).map(|&(_, p)| p).max()
+    }
+
+    pub fn contains(&self, object: u32) -> bool {
+        self.inside.iter().any(|&(o, _)| o == object)
+    }
+
+    // Crossing the surface of object, into it if entering
+    pub fn crossed(&self, object: u32, priority: i32, entering: bool) -> MediumStack {
+        let mut next = self.clone();
+        if entering {
+            next.inside.push((object, priority));
+        } else if let Some(i) = next.inside.iter().rposition(|&(o, _)| o == object) {
+            next.inside.remove(i);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_homogeneous_sampling_unbiased() {
+        // Chromatic extinction, the mean weight of passing through is the transmittance of each
+        // channel
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = homogeneous(Vec3::new(0.1,0.5,1.5), Vec3::new(0.05,0.05,0.05));
+        let r = Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(0.0,0.0,2.0));
+        let n = 100000;
+        let mut sum = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..n {
+            let s = m.sample(&r, 1.0, &mut rng);
+            if s.t.is_none() {
+                sum = sum + s.weight;
+            }
+        }
+        let mean = sum / n as f64;
+        let expected = m.transmittance(&r, 1.0, &mut rng);
+        assert!(Vec3::map2(mean, expected, &|a, b| (a - b).abs()).dot(Vec3::new(1.0,1.0,1.0)) < 0.02);
+        assert!((expected.x - (-0.3f64).exp()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_scattering_conserves_energy() {
+        // With no absorption every path either scatters with weight 1 on average or passes
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = homogeneous(Vec3::new(0.0,0.0,0.0), Vec3::new(0.3,0.6,0.9));
+        let r = Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(1.0,0.0,0.0));
+        let n = 100000;
+        let mut sum = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..n {
+            sum = sum + m.sample(&r, 2.0, &mut rng).weight;
+        }
+        let mean = sum / n as f64;
+        for &c in &[mean.x, mean.y, mean.z] {
+            assert!((c - 1.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_absorbing_color() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = absorbing(Vec3::new(0.5,0.25,1.0), 2.0);
+        let t = m.transmittance(&Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(0.0,1.0,0.0)), 2.0, &mut rng);
+        assert!(Vec3::approx_float_eq(&t, &Vec3::new(0.5,0.25,1.0)));
+    }
+
+    #[test]
+    fn test_emission() {
+        // Against summing emission over small steps dimmed by what lies in front
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = homogeneous(Vec3::new(0.5,1.0,0.0), Vec3::new(0.5,0.0,0.2)).with_emission(Vec3::new(2.0,1.0,3.0));
+        let r = Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(0.0,0.0,2.0));
+        let steps = 10000;
+        let ds = 3.0 / steps as f64;
+        let mut expected = Vec3::new(0.0,0.0,0.0);
+        for i in 0..steps {
+            let s = (i as f64 + 0.5) * ds;
+            expected += m.emission * m.sigma_a * m.transmittance(&r, s / 2.0, &mut rng) * ds;
+        }
+        let e = m.emitted(&r, 1.5, &mut rng);
+        assert!((e - expected).length() < 1e-6);
+        assert!(e.z == 0.0);
+
+        // Thick enough it is just the emission
+        let m = absorbing(Vec3::new(0.5,0.5,0.5), 1.0).with_emission(Vec3::new(1.0,2.0,3.0));
+        assert!(Vec3::approx_float_eq(&m.emitted(&r, 100.0, &mut rng), &m.emission));
+    }
+
+    #[test]
+    fn test_stack_priorities() {
+        // Glass with water inside, the water wins while both are entered
+        let s = MediumStack::new().crossed(1, 1, true).crossed(2, 5, true);
+        assert!(s.top() == Some(2));
+        assert!(s.highest_priority_besides(1) == Some(5));
+        let s = s.crossed(2, 5, false);
+        assert!(s.top() == Some(1));
+        assert!(!s.crossed(1, 1, false).contains(1));
+    }
+}
@@ -1,6 +1,7 @@
-use model::Renderable;
+use model::{Renderable, ModelIds};
+use medium::Interior;
 use model::hitable::*;
-use rand::random;
+use rand::{random, XorShiftRng};
 use ray::Ray;
 use shader::material::Material;
 use util::Axis;
@@ -155,14 +156,14 @@ impl Node {
 }
 
 impl Renderable for Node {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(HitRecord, &Material)> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
         if self.bounding_box.hit(r, t_min, t_max) {
             // If left/right exist, attempt to hit them
-            let lhit = self.left.as_ref().and_then(|h| h.hit(r, t_min, t_max));
-            let rhit = self.right.as_ref().and_then(|h| h.hit(r, t_min, t_max));
+            let lhit = self.left.as_ref().and_then(|h| h.hit(r, t_min, t_max, rng));
+            let rhit = self.right.as_ref().and_then(|h| h.hit(r, t_min, t_max, rng));
 
             match (&lhit, &rhit) {
-                (&Some((ref lhitrec, _)), &Some((ref rhitrec, _))) => {
+                (&Some((ref lhitrec, _, _)), &Some((ref rhitrec, _, _))) => {
                     if lhitrec.t < rhitrec.t { lhit.clone() } else  { rhit.clone() }
                 }
                 (&Some(_), &None) => lhit.clone(),
@@ -173,6 +174,21 @@ impl Renderable for Node {
             None
         }
     }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        if !self.bounding_box.hit(r, t_min, t_max) {
+            return Vec3::new(1.0,1.0,1.0);
+        }
+        let left = self.left.as_ref().map_or(Vec3::new(1.0,1.0,1.0), |h| h.transmittance(r, t_min, t_max, rng));
+        if left == Vec3::new(0.0,0.0,0.0) {
+            return left;
+        }
+        left * self.right.as_ref().map_or(Vec3::new(1.0,1.0,1.0), |h| h.transmittance(r, t_min, t_max, rng))
+    }
+
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        self.left.iter().chain(self.right.iter()).flat_map(|h| h.interiors()).collect()
+    }
 }
 
 impl BoundingBox for Node {
@@ -190,6 +206,7 @@ mod tests {
         let r = Ray {
             origin: Vec3::new(0.0,0.0,-2.0),
             dir: Vec3::new(0.0,0.0,2.0),
+            wavelength: 0.0,
         };
         let res = AABB::unit_aabb().hit(&r, 0.0001, 1000.0);
         assert!(res);
@@ -200,6 +217,7 @@ mod tests {
         let r = Ray {
             origin: Vec3::new(0.0,0.0,-2.0),
             dir: Vec3::new(0.0,2.0,2.0),
+            wavelength: 0.0,
         };
         let res = AABB::unit_aabb().hit(&r, 0.0001, 1000.0);
         assert!(!res);
@@ -250,8 +268,9 @@ mod bvh_tests {
         let r = Ray {
             origin: Vec3::new(0.0,0.0,-2.0),
             dir: Vec3::new(0.0,0.0,2.0),
+            wavelength: 0.0,
         };
-        let res = unit_bvh.hit(&r, 0.0001, 1000.0);
+        let res = unit_bvh.hit(&r, 0.0001, 1000.0, &mut XorShiftRng::new_unseeded());
         assert!(res.is_some());
     }
 
@@ -266,8 +285,9 @@ mod bvh_tests {
         let r = Ray {
             origin: Vec3::new(0.0,0.0,-2.0),
             dir: Vec3::new(0.0,2.0,2.0),
+            wavelength: 0.0,
         };
-        let res = unit_bvh.hit(&r, 0.0001, 1000.0);
+        let res = unit_bvh.hit(&r, 0.0001, 1000.0, &mut XorShiftRng::new_unseeded());
         assert!(res.is_none());
     }
 }
@@ -0,0 +1,226 @@
+use vec3::Vec3;
+use ray::Ray;
+use model::bvh::{AABB, BoundingBox};
+use model::cylinder::CutFace;
+use model::disk::Disk;
+use model::hitable::{HitRecord, Hitable};
+use util::{azimuth, solve_quadratic};
+
+use std::f64::consts::PI;
+
+// Cone with its base of radius on center and its tip up the y axis by height, the base closed by a
+// disk unless open
+// Swept from +x towards +z by phi_max, all the way around unless with_sweep, then closed where it
+// is cut too unless open
+// On the side u goes around and v goes up to the tip, the base is parameterized like a Disk and
+// the cuts like a CutFace
+#[derive(Debug, Clone)]
+pub struct Cone {
+    pub center: Vec3,
+    pub radius: f64,
+    pub height: f64,
+    pub phi_max: f64, // radians
+    pub capped: bool,
+}
+
+impl Cone {
+    pub fn new(center: Vec3, radius: f64, height: f64) -> Cone {
+        Cone {
+            center: center,
+            radius: radius,
+            height: height,
+            phi_max: 2.0 * PI,
+            capped: true,
+        }
+    }
+
+    // Only a slice of degrees
+    pub fn with_sweep(mut self, degrees: f64) -> Cone {
+        self.phi_max = degrees.max(0.0).min(360.0).to_radians();
+        self
+    }
+
+    // Without the base or cuts
+    pub fn open(mut self) -> Cone {
+        self.capped = false;
+        self
+    }
+
+    // x^2 + z^2 = (k (height - y))^2 with k the radius over the height
+    fn hit_side(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let o = r.origin - self.center;
+        let d = r.dir;
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let h = self.height - o.y;
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * h * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * h * h;
+        let (t0, t1) = match solve_quadratic(a, b, c) {
+            Some(roots) => roots,
+            None => return None,
+        };
+        for &t in &[t0, t1] {
+            if t <= t_min || t >= t_max {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = azimuth(p.x, p.z);
+            // The other nappe of the double cone is above the tip
+            if p.y < 0.0 || p.y > self.height || phi > self.phi_max {
+                continue;
+            }
+            let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+            let normal = Vec3::new(cos_phi, k, sin_phi) / (1.0 + k2).sqrt();
+            return Some(HitRecord {
+                t: t,
+                p: self.center + p,
+                normal: normal,
+                u: phi / self.phi_max,
+                v: p.y / self.height,
+                dpdu: Vec3::new(-p.z, 0.0, p.x) * self.phi_max,
+                dpdv: Vec3::new(-self.radius * cos_phi, self.height, -self.radius * sin_phi),
+            });
+        }
+        None
+    }
+
+    fn cuts(&self) -> [CutFace; 2] {
+        let cut = |phi: f64, at_end: bool| CutFace {
+            center: self.center,
+            phi: phi,
+            height: self.height,
+            radius: self.radius,
+            top_radius: 0.0,
+            at_end: at_end,
+        };
+        [cut(0.0, false), cut(self.phi_max, true)]
+    }
+}
+
+impl Hitable for Cone {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut closest = self.hit_side(r, t_min, t_max);
+        if !self.capped {
+            return closest;
+        }
+        let base = Disk {
+            center: self.center,
+            radius: self.radius,
+            inner_radius: 0.0,
+            phi_max: self.phi_max,
+        };
+        if let Some(mut h) = base.hit(r, t_min, closest.as_ref().map_or(t_max, |h| h.t)) {
+            h.normal = -h.normal; // facing down, out of the cone
+            closest = Some(h);
+        }
+        if self.phi_max < 2.0 * PI {
+            for cut in &self.cuts() {
+                if let Some(h) = cut.hit(r, t_min, closest.as_ref().map_or(t_max, |h| h.t)) {
+                    closest = Some(h);
+                }
+            }
+        }
+        closest
+    }
+}
+
+impl BoundingBox for Cone {
+    fn bounding_box(&self) -> AABB {
+        AABB {
+            min: self.center - Vec3::new(self.radius, 0.0, self.radius),
+            max: self.center + Vec3::new(self.radius, self.height, self.radius),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::inside_segments;
+    use std::f64;
+
+    #[test]
+    fn test_hit() {
+        // 45 degree sides, radius 1 at the base
+        let c = Cone::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        let h = c.hit(&Ray::new(Vec3::new(-5.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.t - 4.5).abs() < 1e-12);
+        assert!((h.normal - Vec3::new(-1.0, 1.0, 0.0) / 2.0f64.sqrt()).length() < 1e-12);
+        assert!((h.u - 0.5).abs() < 1e-12 && (h.v - 0.5).abs() < 1e-12);
+
+        // Above the tip is the other half of the double cone, not this one
+        assert!(c.hit(&Ray::new(Vec3::new(-5.0, 1.5, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).is_none());
+
+        // Up through the base
+        let h = c.hit(&Ray::new(Vec3::new(0.2, -1.0, 0.1), Vec3::new(0.0, 1.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!(h.t == 1.0 && h.normal == Vec3::new(0.0, -1.0, 0.0));
+        let h = c.clone().open().hit(&Ray::new(Vec3::new(0.2, -1.0, 0.1), Vec3::new(0.0, 1.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!(h.t > 1.0 && h.normal.y > 0.0);
+
+        // Parallel to a side, only crossing the other one
+        assert!(c.hit(&Ray::new(Vec3::new(-2.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0)), 0.0, 100.0).is_none());
+        let h = c.clone().open().hit(&Ray::new(Vec3::new(0.0, -0.5, 0.0), Vec3::new(1.0, 1.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.p - Vec3::new(0.75, 0.25, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_partial_sweep() {
+        // Open, so rays through the axis only hit the side
+        let c = Cone::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 2.0).with_sweep(120.0).open();
+        let towards = |phi: f64| {
+            let dir = Vec3::new(phi.to_radians().cos(), 0.0, phi.to_radians().sin());
+            c.hit(&Ray::new(dir * -5.0 + Vec3::new(0.0, 0.5, 0.0), dir), 0.0, 100.0)
+        };
+        // A ray along phi leaves the cone at phi and enters it opposite
+        assert!((towards(60.0).unwrap().u - 0.5).abs() < 1e-9);
+        assert!(towards(150.0).is_none());
+        let h = towards(270.0).unwrap();
+        assert!((h.u - 0.75).abs() < 1e-9 && h.p.z > 0.0);
+    }
+
+    #[test]
+    fn test_sweep_closed_for_media() {
+        // A quarter, radius 1 at the base narrowing to 0.5 halfway up
+        let c = Cone::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0).with_sweep(90.0);
+        // In through the cut at phi 0 and out the side
+        let r = Ray::new(Vec3::new(0.25, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        let s = inside_segments(&c, &r, 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - (1.0 + 0.1875f64.sqrt())).abs() < 1e-9);
+        let h = c.hit(&r, 0.0, 100.0).unwrap();
+        assert!(h.normal == Vec3::new(0.0, 0.0, -1.0) && (h.u - 0.25).abs() < 1e-12 && (h.v - 0.5).abs() < 1e-12);
+
+        // Past the slanted edge of the cut it misses
+        assert!(c.hit(&Ray::new(Vec3::new(0.75, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)), 0.0, 100.0).is_none());
+
+        // In through the cut at phi_max and out the one at phi 0
+        let s = inside_segments(&c, &Ray::new(Vec3::new(-1.0, 0.25, 1.1), Vec3::new(1.0, 0.0, -1.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_closed_for_media() {
+        let c = Cone::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        let s = inside_segments(&c, &Ray::new(Vec3::new(-0.25, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - 1.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tangents_match_uv() {
+        let c = Cone::new(Vec3::new(1.0, -1.0, 2.0), 1.5, 3.0);
+        let h = c.hit(&Ray::new(Vec3::new(-4.0, 0.0, 1.0), Vec3::new(1.0, 0.05, 0.25)), 0.0, 100.0).unwrap();
+        let uv_at = |p: Vec3| {
+            let q = p - c.center;
+            (azimuth(q.x, q.z) / c.phi_max, q.y / c.height)
+        };
+        let eps = 1e-6;
+        let (u, v) = uv_at(h.p + h.dpdu * eps);
+        assert!((u - h.u - eps).abs() < 1e-8 && (v - h.v).abs() < 1e-8);
+        let (u, v) = uv_at(h.p + h.dpdv * eps);
+        assert!((u - h.u).abs() < 1e-8 && (v - h.v - eps).abs() < 1e-8);
+        assert!(h.dpdu.dot(h.normal).abs() < 1e-9 && h.dpdv.dot(h.normal).abs() < 1e-9);
+    }
+}
@@ -3,7 +3,7 @@ extern crate rand;
 use vec3::Vec3;
 use ray::Ray;
 use model::bvh::{AABB, BoundingBox};
-use model::hitable::{HitRecord, Hitable};
+use model::hitable::{HitRecord, Hitable, inside_segments};
 
 use std::f64;
 use rand::random;
@@ -20,63 +20,46 @@ impl<H: Hitable + BoundingBox> ConstantMedium<H> {
             density: density
         }
     }
-
-    // Find hit when ray enters and exits bounding volume
-    // Inverting the ray is not sufficient for concave volumes
-    pub fn find_bounding_hits(&self, r: &Ray) -> Option<(HitRecord, HitRecord)> {
-        let hit_min = self.bounding.hit(r,f64::NEG_INFINITY,f64::INFINITY);
-
-        let inverted_ray = Ray::new(r.origin, -r.dir);
-        let hit_max = self.bounding.hit(&inverted_ray,f64::NEG_INFINITY,f64::INFINITY);
-        // Because inverted ray is going in negative direction, t for inverted ray = -t for ray
-        let hit_max = hit_max.map(|h| HitRecord { t: -h.t, .. h });
-
-        hit_min.and_then(|hmin| hit_max.map(|hmax| (hmin,hmax)))
-    }
 }
 
 impl<H: Hitable + BoundingBox> Hitable for ConstantMedium<H> {
     // For a constant volume:
-    // 1. find minimum hit with the bounding hitable along the ray
-    // 2. find the max hit
-    // 3. generate some hit distance stochastically based on density, if hit distance > (max-min),
-    //    ray exitted the volume
+    // 1. find the stretches of the ray inside the bounding hitable, following it from crossing to
+    //    crossing so concave volumes and rays starting inside work (see hitable::inside_segments)
+    // 2. generate some hit distance stochastically based on density, if it is past the length
+    //    inside, the ray exitted the volume
     //
     // The book seems to make an assumption that H is a thin surface, so to find max it only looks
-    // at min_hit + epsilon, which would immediately return if H is solid. Instead I invert the
-    // ray, which is cleaner, but has its own shortcomings when H may be concave.
+    // at min_hit + epsilon, which would immediately return if H is solid.
     //
-    // Note this also doesn't consider any internal refraction
+    // Refraction at the boundary is not considered, for a volume inside glass put the medium inside
+    // the glass instead (scene::Scene::with_medium_inside)
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        match self.find_bounding_hits(r) {
-            Some((hmin, hmax)) => {
-                // Edge case where object is outside of (t_min, t_max)
-                if hmin.t > t_max || hmax.t < t_min {
-                    return None
-                }
-
-                // Make sure returned t not outside (t_min,t_max)
-                let result_min = hmin.t.max(t_min);
-                let result_max = hmax.t.min(t_max);
-
-                let distance_inside_boundary = ((result_max - result_min)*r.dir).length();
-                let hit_distance = -(1.0/self.density) * random::<f64>().ln();
+        let segments = inside_segments(&self.bounding, r, t_min, t_max);
+        let distance_inside_boundary: f64 = segments.iter().map(|&(a, b)| b - a).sum::<f64>() * r.dir.length();
+        let hit_distance = -(1.0/self.density) * random::<f64>().ln();
+        if hit_distance >= distance_inside_boundary {
+            return None;
+        }
 
-                if hit_distance < distance_inside_boundary {
-                    let t = result_min + hit_distance / r.dir.length();
-                    Some(HitRecord {
-                        t: t,
-                        p: r.point_at_parameter(t),
-                        normal: Vec3::new(1.0,0.0,0.0), // arbitrary
-                        u: 0.0, // arbitrary
-                        v: 0.0, // arbitrary
-                    })
-                } else {
-                    None
-                }
+        // Walk the distance along the segments
+        let mut remaining = hit_distance / r.dir.length();
+        for (a, b) in segments {
+            if remaining < b - a {
+                let t = a + remaining;
+                return Some(HitRecord {
+                    t: t,
+                    p: r.point_at_parameter(t),
+                    normal: Vec3::new(0.0,0.0,0.0), // none inside a volume
+                    u: 0.0, // arbitrary
+                    v: 0.0, // arbitrary
+                    dpdu: Vec3::new(0.0,0.0,0.0),
+                    dpdv: Vec3::new(0.0,0.0,0.0),
+                });
             }
-            None => None
+            remaining -= b - a;
         }
+        None
     }
 }
 
@@ -92,24 +75,30 @@ mod tests {
     use model::sphere::Sphere;
 
     #[test]
-    fn test_find_bounding_hits() {
+    fn test_bounding_segments() {
         let s = Sphere::unit_sphere();
-        let m = ConstantMedium {
-            bounding: s,
-            density: 0.0,
-        };
         let r = Ray {
           origin: Vec3::new(0.0, 0.0, -5.0),
           dir: Vec3::new(0.0, 0.0, 1.0),
+          wavelength: 0.0,
         };
 
-        let res = m.find_bounding_hits(&r);
-        println!("{:?}", res);
+        let res = inside_segments(&s, &r, 0.0, f64::INFINITY);
+        assert!(res == vec![(4.0, 6.0)]);
+    }
 
-        assert!(res.clone().is_some());
-        assert!(res.clone().unwrap().0.p == Vec3::new(0.0,0.0,-1.0));
-        assert!(res.clone().unwrap().1.p == Vec3::new(0.0,0.0,1.0));
-        assert!(res.clone().unwrap().0.t == 4.0);
-        assert!(res.clone().unwrap().1.t == 6.0);
+    #[test]
+    fn test_hits_from_inside() {
+        // Starting at the center half of the unit path is inside, exp(-1) of rays get out
+        let m = ConstantMedium::new(Sphere::unit_sphere(), 1.0);
+        let r = Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(0.0,0.0,1.0));
+        let n = 20000;
+        let escaped = (0..n).filter(|_| m.hit(&r, 0.0, f64::INFINITY).is_none()).count() as f64 / n as f64;
+        assert!((escaped - (-1.0f64).exp()).abs() < 0.015);
+        for _ in 0..100 {
+            if let Some(h) = m.hit(&r, 0.0, f64::INFINITY) {
+                assert!(h.t > 0.0 && h.t < 1.0);
+            }
+        }
     }
 }
@@ -0,0 +1,262 @@
+use vec3::Vec3;
+use ray::Ray;
+use model::bvh::{AABB, BoundingBox};
+use model::disk::Disk;
+use model::hitable::{HitRecord, Hitable};
+use util::{azimuth, solve_quadratic};
+
+use std::f64::consts::PI;
+
+// Cylinder standing on center, up the y axis by height, closed by disks at both ends unless open
+// Swept from +x towards +z by phi_max, all the way around unless with_sweep, then closed where it
+// is cut too unless open
+// On the side u goes around and v goes up, the caps are parameterized like a Disk and the cuts
+// like a CutFace
+#[derive(Debug, Clone)]
+pub struct Cylinder {
+    pub center: Vec3,
+    pub radius: f64,
+    pub height: f64,
+    pub phi_max: f64, // radians
+    pub capped: bool,
+}
+
+impl Cylinder {
+    pub fn new(center: Vec3, radius: f64, height: f64) -> Cylinder {
+        Cylinder {
+            center: center,
+            radius: radius,
+            height: height,
+            phi_max: 2.0 * PI,
+            capped: true,
+        }
+    }
+
+    // Only a slice of degrees
+    pub fn with_sweep(mut self, degrees: f64) -> Cylinder {
+        self.phi_max = degrees.max(0.0).min(360.0).to_radians();
+        self
+    }
+
+    // A tube, without caps
+    pub fn open(mut self) -> Cylinder {
+        self.capped = false;
+        self
+    }
+
+    fn hit_side(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let o = r.origin - self.center;
+        let d = r.dir;
+        let a = d.x * d.x + d.z * d.z;
+        if a == 0.0 {
+            return None; // along the axis, only the caps
+        }
+        let b = 2.0 * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        let (t0, t1) = match solve_quadratic(a, b, c) {
+            Some(roots) => roots,
+            None => return None,
+        };
+        for &t in &[t0, t1] {
+            if t <= t_min || t >= t_max {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = azimuth(p.x, p.z);
+            if p.y < 0.0 || p.y > self.height || phi > self.phi_max {
+                continue;
+            }
+            return Some(HitRecord {
+                t: t,
+                p: self.center + p,
+                normal: Vec3::new(p.x, 0.0, p.z) / self.radius,
+                u: phi / self.phi_max,
+                v: p.y / self.height,
+                dpdu: Vec3::new(-p.z, 0.0, p.x) * self.phi_max,
+                dpdv: Vec3::new(0.0, self.height, 0.0),
+            });
+        }
+        None
+    }
+
+    fn cap(&self, y: f64) -> Disk {
+        Disk {
+            center: self.center + Vec3::new(0.0, y, 0.0),
+            radius: self.radius,
+            inner_radius: 0.0,
+            phi_max: self.phi_max,
+        }
+    }
+
+    fn cuts(&self) -> [CutFace; 2] {
+        let cut = |phi: f64, at_end: bool| CutFace {
+            center: self.center,
+            phi: phi,
+            height: self.height,
+            radius: self.radius,
+            top_radius: self.radius,
+            at_end: at_end,
+        };
+        [cut(0.0, false), cut(self.phi_max, true)]
+    }
+}
+
+impl Hitable for Cylinder {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut closest = self.hit_side(r, t_min, t_max);
+        if self.capped {
+            let t_max = closest.as_ref().map_or(t_max, |h| h.t);
+            if let Some(mut h) = self.cap(0.0).hit(r, t_min, t_max) {
+                h.normal = -h.normal; // facing down, out of the cylinder
+                closest = Some(h);
+            }
+            let t_max = closest.as_ref().map_or(t_max, |h| h.t);
+            if let Some(h) = self.cap(self.height).hit(r, t_min, t_max) {
+                closest = Some(h);
+            }
+            if self.phi_max < 2.0 * PI {
+                for cut in &self.cuts() {
+                    let t_max = closest.as_ref().map_or(t_max, |h| h.t);
+                    if let Some(h) = cut.hit(r, t_min, t_max) {
+                        closest = Some(h);
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+impl BoundingBox for Cylinder {
+    fn bounding_box(&self) -> AABB {
+        AABB {
+            min: self.center - Vec3::new(self.radius, 0.0, self.radius),
+            max: self.center + Vec3::new(self.radius, self.height, self.radius),
+        }
+    }
+}
+
+// Flat face closing a swept solid around the y axis where it is cut at phi, reaching out from the
+// axis to radius at the bottom and to top_radius at height. Faces away from the solid, which is
+// towards larger phi from the face at 0 and towards smaller phi from the one at_end (phi_max)
+// u goes out from the axis in units of radius, v goes up
+#[derive(Debug, Clone)]
+pub struct CutFace {
+    pub center: Vec3,
+    pub phi: f64, // radians
+    pub height: f64,
+    pub radius: f64,
+    pub top_radius: f64,
+    pub at_end: bool,
+}
+
+impl Hitable for CutFace {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        // The plane holds the axis and out, around is the direction phi grows in
+        let (sin_phi, cos_phi) = self.phi.sin_cos();
+        let out = Vec3::new(cos_phi, 0.0, sin_phi);
+        let around = Vec3::new(-sin_phi, 0.0, cos_phi);
+        let speed = r.dir.dot(around);
+        if speed == 0.0 {
+            return None;
+        }
+        let o = r.origin - self.center;
+        let t = -o.dot(around) / speed;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let p = o + r.dir * t;
+        let s = p.dot(out);
+        let width = self.radius + (self.top_radius - self.radius) * p.y / self.height;
+        if p.y < 0.0 || p.y > self.height || s < 0.0 || s > width {
+            return None;
+        }
+        Some(HitRecord {
+            t: t,
+            p: self.center + out * s + Vec3::new(0.0, p.y, 0.0),
+            normal: if self.at_end { around } else { -around },
+            u: s / self.radius,
+            v: p.y / self.height,
+            dpdu: out * self.radius,
+            dpdv: Vec3::new(0.0, self.height, 0.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::inside_segments;
+    use std::f64;
+
+    #[test]
+    fn test_hit_side_and_caps() {
+        let c = Cylinder::new(Vec3::new(0.0, 1.0, 0.0), 1.0, 2.0);
+        let h = c.hit(&Ray::new(Vec3::new(-5.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!(h.t == 4.0 && h.normal == Vec3::new(-1.0, 0.0, 0.0));
+        assert!((h.u - 0.5).abs() < 1e-12 && (h.v - 0.5).abs() < 1e-12);
+
+        // Down through the top cap, and from inside out through the bottom one
+        let down = Ray::new(Vec3::new(0.3, 5.0, 0.2), Vec3::new(0.0, -1.0, 0.0));
+        let h = c.hit(&down, 0.0, 100.0).unwrap();
+        assert!(h.t == 2.0 && h.normal == Vec3::new(0.0, 1.0, 0.0));
+        let h = c.hit(&down, 2.5, 100.0).unwrap();
+        assert!(h.t == 4.0 && h.normal == Vec3::new(0.0, -1.0, 0.0));
+        assert!(c.clone().open().hit(&down, 0.0, 100.0).is_none());
+
+        // Over the top
+        assert!(c.hit(&Ray::new(Vec3::new(-5.0, 3.5, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).is_none());
+    }
+
+    #[test]
+    fn test_closed_for_media() {
+        let c = Cylinder::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        // In through the bottom and out the side
+        let s = inside_segments(&c, &Ray::new(Vec3::new(-2.5, -1.0, 0.0), Vec3::new(2.0, 1.0, 0.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - 1.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sweep_closed_for_media() {
+        let c = Cylinder::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0).with_sweep(90.0);
+        // In through the cut at phi 0 and out the side, then in through the other cut and out the
+        // one at phi 0
+        let s = inside_segments(&c, &Ray::new(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - (1.0 + 0.75f64.sqrt())).abs() < 1e-9);
+        let s = inside_segments(&c, &Ray::new(Vec3::new(-1.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 1);
+        assert!((s[0].0 - 1.0).abs() < 1e-9 && (s[0].1 - (1.0 + 0.75f64.sqrt())).abs() < 1e-9);
+
+        let h = c.hit(&Ray::new(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0)), 0.0, 100.0).unwrap();
+        assert!(h.normal == Vec3::new(0.0, 0.0, -1.0) && (h.u - 0.5).abs() < 1e-12 && (h.v - 0.5).abs() < 1e-12);
+        let h = c.hit(&Ray::new(Vec3::new(-1.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let c = Cylinder::new(Vec3::new(0.0, 0.0, 0.0), 1.0, 1.0).with_sweep(90.0).open();
+        let towards = |x: f64, z: f64| c.hit(&Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(x, 0.0, z)), 0.0, 100.0);
+        assert!(towards(1.0, 1.0).is_some());
+        assert!(towards(-1.0, 1.0).is_none());
+        assert!(towards(1.0, -0.1).is_none());
+    }
+
+    #[test]
+    fn test_tangents_match_uv() {
+        let c = Cylinder::new(Vec3::new(1.0, -1.0, 2.0), 1.5, 3.0);
+        let h = c.hit(&Ray::new(Vec3::new(-4.0, 0.0, 1.0), Vec3::new(1.0, 0.1, 0.3)), 0.0, 100.0).unwrap();
+        let uv_at = |p: Vec3| {
+            let q = p - c.center;
+            (azimuth(q.x, q.z) / c.phi_max, q.y / c.height)
+        };
+        let eps = 1e-6;
+        let (u, v) = uv_at(h.p + h.dpdu * eps);
+        assert!((u - h.u - eps).abs() < 1e-8 && (v - h.v).abs() < 1e-8);
+        let (u, v) = uv_at(h.p + h.dpdv * eps);
+        assert!((u - h.u).abs() < 1e-8 && (v - h.v - eps).abs() < 1e-8);
+        assert!(h.dpdu.dot(h.normal).abs() < 1e-9 && h.dpdv.dot(h.normal).abs() < 1e-9);
+    }
+}
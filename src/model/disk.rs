@@ -0,0 +1,124 @@
+use vec3::Vec3;
+use ray::Ray;
+use model::bvh::{AABB, BoundingBox};
+use model::hitable::{HitRecord, Hitable};
+use util::azimuth;
+
+use std::f64::consts::PI;
+
+// Disk facing up the y axis, or an annulus with a hole of inner_radius
+// Swept from +x towards +z by phi_max, all the way around unless with_sweep
+// u goes around, v goes in from the outer edge
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub center: Vec3,
+    pub radius: f64,
+    pub inner_radius: f64,
+    pub phi_max: f64, // radians
+}
+
+impl Disk {
+    pub fn new(center: Vec3, radius: f64) -> Disk {
+        Disk::annulus(center, 0.0, radius)
+    }
+
+    pub fn annulus(center: Vec3, inner_radius: f64, radius: f64) -> Disk {
+        Disk {
+            center: center,
+            radius: radius,
+            inner_radius: inner_radius,
+            phi_max: 2.0 * PI,
+        }
+    }
+
+    // Only a slice of degrees
+    pub fn with_sweep(mut self, degrees: f64) -> Disk {
+        self.phi_max = degrees.max(0.0).min(360.0).to_radians();
+        self
+    }
+}
+
+impl Hitable for Disk {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if r.dir.y == 0.0 {
+            return None;
+        }
+        let t = (self.center.y - r.origin.y) / r.dir.y;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let p = r.point_at_parameter(t);
+        let (x, z) = (p.x - self.center.x, p.z - self.center.z);
+        let distance = (x * x + z * z).sqrt();
+        if distance > self.radius || distance < self.inner_radius {
+            return None;
+        }
+        let phi = azimuth(x, z);
+        if phi > self.phi_max {
+            return None;
+        }
+
+        let width = self.radius - self.inner_radius;
+        let dpdv = if distance > 0.0 {
+            Vec3::new(x, 0.0, z) * (-width / distance)
+        } else {
+            Vec3::new(-width, 0.0, 0.0) // at the center, any way out
+        };
+        Some(HitRecord {
+            t: t,
+            p: Vec3::new(p.x, self.center.y, p.z),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            u: phi / self.phi_max,
+            v: if width > 0.0 { (self.radius - distance) / width } else { 0.0 },
+            dpdu: Vec3::new(-z, 0.0, x) * self.phi_max,
+            dpdv: dpdv,
+        })
+    }
+}
+
+impl BoundingBox for Disk {
+    fn bounding_box(&self) -> AABB {
+        AABB {
+            min: self.center - Vec3::new(self.radius, 0.0001, self.radius),
+            max: self.center + Vec3::new(self.radius, 0.0001, self.radius),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annulus() {
+        let d = Disk::annulus(Vec3::new(1.0, 2.0, 3.0), 0.5, 1.0);
+        let down = |x: f64, z: f64| d.hit(&Ray::new(Vec3::new(1.0 + x, 5.0, 3.0 + z), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0);
+        let h = down(0.75, 0.0).unwrap();
+        assert!(h.t == 3.0 && h.normal == Vec3::new(0.0, 1.0, 0.0));
+        assert!(h.u == 0.0 && (h.v - 0.5).abs() < 1e-12);
+        assert!(down(0.0, 0.25).is_none());
+        assert!(down(0.0, 1.1).is_none());
+        let h = down(0.0, -0.9).unwrap();
+        assert!((h.u - 0.75).abs() < 1e-12 && (h.v - 0.2).abs() < 1e-12);
+
+        // Half of it
+        let d = d.with_sweep(180.0);
+        assert!(d.hit(&Ray::new(Vec3::new(1.0, 5.0, 2.1), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0).is_none());
+        assert!(d.hit(&Ray::new(Vec3::new(1.0, 5.0, 3.9), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0).is_some());
+    }
+
+    #[test]
+    fn test_tangents_match_uv() {
+        let d = Disk::annulus(Vec3::new(0.0, 0.0, 0.0), 0.3, 2.0).with_sweep(270.0);
+        let h = d.hit(&Ray::new(Vec3::new(-0.4, 1.0, 0.9), Vec3::new(0.1, -1.0, 0.2)), 0.0, 100.0).unwrap();
+        let uv_at = |p: Vec3| {
+            let distance = (p.x * p.x + p.z * p.z).sqrt();
+            (azimuth(p.x, p.z) / d.phi_max, (d.radius - distance) / (d.radius - d.inner_radius))
+        };
+        let eps = 1e-6;
+        let (u, v) = uv_at(h.p + h.dpdu * eps);
+        assert!((u - h.u - eps).abs() < 1e-8 && (v - h.v).abs() < 1e-8);
+        let (u, v) = uv_at(h.p + h.dpdv * eps);
+        assert!((u - h.u).abs() < 1e-8 && (v - h.v - eps).abs() < 1e-8);
+    }
+}
@@ -0,0 +1,367 @@
+extern crate rand;
+
+use vec3::Vec3;
+use ray::Ray;
+use model::{ModelIds, Renderable, next_object_id};
+use model::bvh::{AABB, BoundingBox};
+use model::hitable::{HitRecord, Hitable, inside_segments};
+use shader::material::Material;
+use shader::phase::Phase;
+use shader::texture::{self, ConstantTexture, Texture};
+use volume::MajorantGrid;
+
+use std::f64;
+use rand::*;
+
+//
+// Heterogeneous medium: smoke and clouds
+//
+// Density comes from a texture read at each point (a PerlinNoise or a texture::VoxelGrid, first
+// channel), scaling the absorption and scattering coefficients. Scattering events are found by
+// delta tracking: tentative collisions are drawn against the majorant, the largest extinction
+// anywhere, and a collision is real with probability extinction / majorant. Shadow rays are
+// dimmed by ratio tracking instead, see Renderable::transmittance. A majorant grid (with_majorant_grid, e.g. from a
+// volume::SparseGrid) bounds the density per cell instead, for longer steps where it is thin.
+//
+// The medium is its own material, scattering by its phase function (isotropic unless set) with an
+// albedo of color times the fraction of extinction that scatters, so it is added to a world
+// directly rather than in a Model. It glows too when given an emission, see with_emission.
+// The boundary has to be closed.
+//
+pub struct HeterogeneousMedium<H: Hitable, D: Texture, C: Texture, E: Texture = ConstantTexture> {
+    pub boundary: H,
+    pub density: D,
+    pub max_density: f64,   // density never goes above this, the majorant is it times extinction
+    pub color: C,
+    pub sigma_a: f64,       // absorption and scattering coefficients at density 1
+    pub sigma_s: f64,
+    pub phase: Phase,
+    pub emission: E,        // radiance given off where it absorbs, none unless set
+    pub majorants: Option<MajorantGrid>,
+    pub ids: ModelIds,
+}
+
+unsafe impl<H: Hitable, D: Texture, C: Texture, E: Texture> Sync for HeterogeneousMedium<H, D, C, E> {}
+unsafe impl<H: Hitable, D: Texture, C: Texture, E: Texture> Send for HeterogeneousMedium<H, D, C, E> {}
+
+impl<H: Hitable, D: Texture, C: Texture> HeterogeneousMedium<H, D, C> {
+    // Density is clamped to max_density, e.g. grid.max() for a VoxelGrid or 1 for PerlinNoise
+    pub fn new(boundary: H, density: D, max_density: f64, color: C, sigma_a: f64, sigma_s: f64) -> Self {
+        HeterogeneousMedium {
+            boundary: boundary,
+            density: density,
+            max_density: max_density,
+            color: color,
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            phase: Phase::Isotropic,
+            emission: texture::constant_texture(Vec3::new(0.0,0.0,0.0)),
+            majorants: None,
+            ids: ModelIds { object: next_object_id(), material: 0 },
+        }
+    }
+}
+
+impl<H: Hitable, D: Texture, C: Texture, E: Texture> HeterogeneousMedium<H, D, C, E> {
+    // Fire and glowing gas, e.g. a texture::blackbody_texture of a temperature grid. Only the
+    // absorbing part of the medium glows, so it needs sigma_a
+    pub fn with_emission<E2: Texture>(self, emission: E2) -> HeterogeneousMedium<H, D, C, E2> {
+        HeterogeneousMedium {
+            boundary: self.boundary,
+            density: self.density,
+            max_density: self.max_density,
+            color: self.color,
+            sigma_a: self.sigma_a,
+            sigma_s: self.sigma_s,
+            phase: self.phase,
+            emission: emission,
+            majorants: self.majorants,
+            ids: self.ids,
+        }
+    }
+
+    pub fn with_phase(mut self, phase: Phase) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    // Bounds on the density over cells of space, e.g. SparseGrid::majorant_grid of the density.
+    // Outside the grid max_density bounds it
+    pub fn with_majorant_grid(mut self, majorants: MajorantGrid) -> Self {
+        self.majorants = Some(majorants);
+        self
+    }
+
+    // Stretches of r inside the boundary, each with the majorant over it
+    fn tracking_segments(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<(f64, f64, f64)> {
+        let sigma_t = self.sigma_a + self.sigma_s;
+        let mut segments = vec![];
+        for (start, end) in inside_segments(&self.boundary, r, t_min, t_max) {
+            match self.majorants {
+                Some(ref grid) => {
+                    for (a, b, max) in grid.segments(r, start, end, self.max_density) {
+                        segments.push((a, b, max.min(self.max_density) * sigma_t));
+                    }
+                }
+                None => segments.push((start, end, self.max_density * sigma_t)),
+            }
+        }
+        segments
+    }
+
+    fn extinction(&self, p: &Vec3) -> f64 {
+        let density = self.density.value(0.0, 0.0, p).x.max(0.0).min(self.max_density);
+        density * (self.sigma_a + self.sigma_s)
+    }
+
+    // Transmittance along r between t_min and t_max, an unbiased estimate by ratio tracking
+    pub fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> f64 {
+        let majorant = self.max_density * (self.sigma_a + self.sigma_s);
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+        let speed = r.dir.length();
+        let mut transmittance = 1.0;
+        for (start, end, majorant) in self.tracking_segments(r, t_min, t_max) {
+            if majorant <= 0.0 {
+                continue;
+            }
+            let mut t = start;
+            loop {
+                t += -(1.0 - rng.gen::<f64>()).ln() / (majorant * speed);
+                if t >= end {
+                    break;
+                }
+                transmittance *= 1.0 - self.extinction(&r.point_at_parameter(t)) / majorant;
+            }
+        }
+        transmittance
+    }
+
+    // Delta tracking through each stretch inside the boundary under its majorant
+    fn delta_tracking(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<HitRecord> {
+        let majorant = self.max_density * (self.sigma_a + self.sigma_s);
+        if majorant <= 0.0 {
+            return None;
+        }
+        let speed = r.dir.length();
+        for (start, end, majorant) in self.tracking_segments(r, t_min, t_max) {
+            if majorant <= 0.0 {
+                continue;
+            }
+            let mut t = start;
+            loop {
+                t += -(1.0 - rng.gen::<f64>()).ln() / (majorant * speed);
+                if t >= end {
+                    break;
+                }
+                let p = r.point_at_parameter(t);
+                if rng.gen::<f64>() * majorant < self.extinction(&p) {
+                    return Some(HitRecord {
+                        t: t,
+                        p: p,
+                        normal: Vec3::new(0.0,0.0,0.0), // none, so lights are picked for a point in a medium
+                        u: 0.0,
+                        v: 0.0,
+                        dpdu: Vec3::new(0.0,0.0,0.0),
+                        dpdv: Vec3::new(0.0,0.0,0.0),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<H: Hitable, D: Texture, C: Texture, E: Texture> Material for HeterogeneousMedium<H, D, C, E> {
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let scattered = Ray { origin: hit.p, dir: self.phase.sample(r.dir, rng), .. *r };
+        Some((self.albedo(hit), scattered))
+    }
+
+    // At a collision the absorbing fraction of the extinction gives off its emission, the rest
+    // scatters (albedo), together an unbiased estimate of the light emitted along the ray
+    fn emitted(&self, _r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        let extinction = self.sigma_a + self.sigma_s;
+        if self.sigma_a <= 0.0 || extinction <= 0.0 {
+            return None;
+        }
+        let emission = self.emission.value(hit.u, hit.v, &hit.p);
+        if emission == Vec3::new(0.0,0.0,0.0) {
+            return None;
+        }
+        Some(emission * (self.sigma_a / extinction))
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        self.albedo(hit) * self.phase.eval_directions(r.dir, wi)
+    }
+
+    fn pdf(&self, r: &Ray, _hit: &HitRecord, wi: Vec3) -> f64 {
+        self.phase.eval_directions(r.dir, wi)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        let extinction = self.sigma_a + self.sigma_s;
+        if extinction <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        self.color.value(hit.u, hit.v, &hit.p) * (self.sigma_s / extinction)
+    }
+}
+
+impl<H: Hitable, D: Texture, C: Texture, E: Texture> Renderable for HeterogeneousMedium<H, D, C, E> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
+        self.delta_tracking(r, t_min, t_max, rng).map(|h| (h, self as &Material, self.ids))
+    }
+
+    // Shadow rays pass through, dimmed by ratio tracking rather than stopped at a collision
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        let t = HeterogeneousMedium::transmittance(self, r, t_min, t_max, rng);
+        Vec3::new(t, t, t)
+    }
+}
+
+impl<H: Hitable + BoundingBox, D: Texture, C: Texture, E: Texture> BoundingBox for HeterogeneousMedium<H, D, C, E> {
+    fn bounding_box(&self) -> AABB {
+        self.boundary.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::Model;
+    use model::sphere::Sphere;
+    use shader::texture::{self, VoxelGrid};
+    use volume::SparseGrid;
+
+    fn escaped<R: Renderable>(m: &R, r: &Ray, n: i32) -> f64 {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        (0..n).filter(|_| m.hit(r, 0.0, f64::INFINITY, &mut rng).is_none()).count() as f64 / n as f64
+    }
+
+    #[test]
+    fn test_constant_density_matches_beer_lambert() {
+        // Density 0.5 under a majorant of 2, so most tentative collisions are null
+        let m = HeterogeneousMedium::new(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(0.5,0.5,0.5)), 2.0, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 0.2, 0.8);
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,2.0));
+        let e = escaped(&m, &r, 20000);
+        assert!((e - (-1.0f64).exp()).abs() < 0.015);
+
+        // From the center only half the path is inside
+        let r = Ray::new(Vec3::new(0.0,0.0,0.0), Vec3::new(0.0,0.0,1.0));
+        let e = escaped(&m, &r, 20000);
+        assert!((e - (-0.5f64).exp()).abs() < 0.015);
+    }
+
+    #[test]
+    fn test_ratio_tracking_through_gradient() {
+        // Density rising linearly along x through a box, integral of x over 0..2 is 2
+        let bounds = AABB { min: Vec3::new(0.0,-1.0,-1.0), max: Vec3::new(2.0,1.0,1.0) };
+        let grid = VoxelGrid::from_fn((16, 2, 2), bounds, &|p| Vec3::new(p.x, p.x, p.x));
+        let max = grid.max();
+        let boundary = ::model::cube::Cube::new_from_min_max(bounds.min, bounds.max);
+        let m = HeterogeneousMedium::new(boundary, grid, max, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 0.0, 1.0);
+        let r = Ray::new(Vec3::new(-1.0,0.0,0.0), Vec3::new(1.0,0.0,0.0));
+
+        // Clamping past the outermost voxel centers adds as much at one end as it takes at the other
+        let expected = (-2.0f64).exp();
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 20000;
+        let mean = (0..n).map(|_| m.transmittance(&r, 0.0, f64::INFINITY, &mut rng)).sum::<f64>() / n as f64;
+        assert!((mean - expected).abs() < 0.01 * expected);
+        assert!((escaped(&m, &r, n) - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_shadow_rays_ratio_tracked() {
+        let m = HeterogeneousMedium::new(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(0.5,0.5,0.5)), 2.0, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 0.2, 0.8);
+        let wall = Model::new(Sphere { center: Vec3::new(0.0,0.0,5.0), radius: 1.0 }, Material::lambertian_constant(Vec3::new(0.5,0.5,0.5)));
+        let world: Vec<Box<Renderable>> = vec![Box::new(m), Box::new(wall)];
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+
+        // Partly through the medium rather than all or nothing, then stopped by the wall
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 20000;
+        let estimates: Vec<f64> = (0..n).map(|_| world.transmittance(&r, 0.0, 8.0, &mut rng).x).collect();
+        assert!(estimates.iter().any(|&t| t > 0.0 && t < 1.0));
+        let mean = estimates.iter().sum::<f64>() / n as f64;
+        assert!((mean - (-1.0f64).exp()).abs() < 0.01);
+        assert!(world.transmittance(&r, 0.0, 100.0, &mut rng) == Vec3::new(0.0,0.0,0.0));
+
+        // Collisions repeat with the path's seed
+        let collide = |seed| world.hit(&r, 0.0, 100.0, &mut XorShiftRng::from_seed(seed)).map(|h| h.0.t);
+        assert!(collide([9, 9, 9, 9]) == collide([9, 9, 9, 9]));
+    }
+
+    #[test]
+    fn test_emission_along_ray() {
+        // Absorbing only, the mean emission collected at collisions is the emission times the
+        // fraction absorbed, half of it for a density of half the majorant
+        let temperature = texture::constant_texture(Vec3::new(1500.0,1500.0,1500.0));
+        let m = HeterogeneousMedium::new(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(0.5,0.5,0.5)), 1.0, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 1.0, 0.0)
+            .with_emission(texture::blackbody_texture(temperature, 1.0));
+        let glow = m.emission.value(0.0, 0.0, &Vec3::new(0.0,0.0,0.0));
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 20000;
+        let mut sum = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..n {
+            if let Some((h, material, _)) = m.hit(&r, 0.0, f64::INFINITY, &mut rng) {
+                sum = sum + material.emitted(&r, &h).unwrap();
+            }
+        }
+        let expected = glow * (1.0 - (-1.0f64).exp());
+        assert!((sum / n as f64 - expected).length() < 0.015 * glow.length());
+        assert!(glow.x > glow.z);
+    }
+
+    #[test]
+    fn test_majorant_grid_unbiased() {
+        // A dense blob in a corner of a sparse grid, tracked with and without the grid's majorants
+        let bounds = AABB { min: Vec3::new(-1.0,-1.0,-1.0), max: Vec3::new(1.0,1.0,1.0) };
+        let mut grid = SparseGrid::new((32, 32, 32), bounds, 0.0);
+        for k in 0..32 {
+            for j in 0..32 {
+                for i in 0..32 {
+                    let p = bounds.min + Vec3::new(i as f64 + 0.5, j as f64 + 0.5, k as f64 + 0.5) / 16.0;
+                    grid.set(i, j, k, if p.x > 0.5 && p.y > 0.5 { 4.0 } else { 0.1 });
+                }
+            }
+        }
+        let boundary = || ::model::cube::Cube::new_from_min_max(bounds.min, bounds.max);
+        let white = texture::constant_texture(Vec3::new(1.0,1.0,1.0));
+        let m = HeterogeneousMedium::new(boundary(), grid.clone(), grid.max(), white.clone(), 0.0, 1.0);
+        let majorants = grid.majorant_grid();
+        let fast = HeterogeneousMedium::new(boundary(), grid, m.max_density, white, 0.0, 1.0).with_majorant_grid(majorants);
+
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 20000;
+        for r in &[Ray::new(Vec3::new(-2.0,0.0,0.0), Vec3::new(1.0,0.1,0.05)), Ray::new(Vec3::new(-2.0,0.8,0.8), Vec3::new(1.0,0.0,0.0))] {
+            let slow_mean = (0..n).map(|_| m.transmittance(r, 0.0, f64::INFINITY, &mut rng)).sum::<f64>() / n as f64;
+            let fast_mean = (0..n).map(|_| fast.transmittance(r, 0.0, f64::INFINITY, &mut rng)).sum::<f64>() / n as f64;
+            assert!((slow_mean - fast_mean).abs() < 0.02, "{} {}", slow_mean, fast_mean);
+            assert!((escaped(&fast, r, n) - slow_mean).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_inside_segments_concave() {
+        // Two spheres side by side as one boundary, the ray crosses both
+        let spheres: Vec<Box<Hitable>> = vec![
+            Box::new(Sphere { center: Vec3::new(-2.0,0.0,0.0), radius: 1.0 }),
+            Box::new(Sphere { center: Vec3::new(2.0,0.0,0.0), radius: 1.0 }),
+        ];
+        let m = HeterogeneousMedium::new(spheres, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 1.0, texture::constant_texture(Vec3::new(1.0,1.0,1.0)), 0.0, 1.0);
+        let r = Ray::new(Vec3::new(-5.0,0.0,0.0), Vec3::new(1.0,0.0,0.0));
+        let s = inside_segments(&m.boundary, &r, 0.0, f64::INFINITY);
+        assert!(s.len() == 2);
+        assert!((s[0].0 - 2.0).abs() < 1e-9 && (s[0].1 - 4.0).abs() < 1e-9);
+        assert!((s[1].0 - 6.0).abs() < 1e-9 && (s[1].1 - 8.0).abs() < 1e-9);
+
+        // Starting inside the first
+        let s = inside_segments(&m.boundary, &r, 3.0, 7.0);
+        assert!(s == vec![(3.0, 4.0), (6.0, 7.0)]);
+    }
+}
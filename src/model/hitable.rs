@@ -1,8 +1,10 @@
-use vec3::Vec3;
+use vec3::{Vec3, Onb};
 use ray::Ray;
 use model::bvh::{AABB, BoundingBox};
+use shader::texture::Texture;
 use util::Axis;
 use std::f64;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct HitRecord {
@@ -11,6 +13,31 @@ pub struct HitRecord {
     pub normal: Vec3,
     pub u: f64,
     pub v: f64,
+    // Derivatives of p with respect to u and v, zero if the hitable has no parameterization
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+}
+
+impl HitRecord {
+    // Shading frame around n (the normal, possibly flipped to a side) with u along dpdu
+    pub fn tangent_frame(&self, n: Vec3) -> Onb {
+        Onb::from_w_tangent(n.normalized(), self.dpdu)
+    }
+}
+
+// Test fixture for materials: a hit on the xz plane at the origin, facing up, u along x and v
+// along z
+#[cfg(test)]
+pub fn hit_xz_plane() -> HitRecord {
+    HitRecord {
+        t: 1.0,
+        p: Vec3::new(0.0,0.0,0.0),
+        normal: Vec3::new(0.0,1.0,0.0),
+        u: 0.0,
+        v: 0.0,
+        dpdu: Vec3::new(1.0,0.0,0.0),
+        dpdv: Vec3::new(0.0,0.0,1.0),
+    }
 }
 
 // Hitable trait: function to check if a ray hits the object
@@ -32,6 +59,57 @@ impl Hitable for Vec<Box<Hitable>> {
     }
 }
 
+// Shared geometry, e.g. a model's boundary also used by its material
+impl<H: Hitable + ?Sized> Hitable for Arc<H> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        (**self).hit(r, t_min, t_max)
+    }
+}
+
+// Ignore boundary hits closer than this to the last one, as render's MIN_DISTANCE
+const MIN_DISTANCE: f64 = 1e-6;
+
+// Bounds the walks through concave boundaries
+const MAX_CROSSINGS: i32 = 64;
+
+// Intervals of t inside the closed boundary between t_min and t_max, in order, e.g. for volumes.
+// Follows the ray from crossing to crossing, so it works for concave boundaries and rays starting
+// inside
+pub fn inside_segments<H: Hitable + ?Sized>(boundary: &H, r: &Ray, t_min: f64, t_max: f64) -> Vec<(f64, f64)> {
+    let mut segments = vec![];
+    let mut t = t_min;
+    let mut entered = None;
+    let mut first = true;
+    for _ in 0..MAX_CROSSINGS {
+        match boundary.hit(r, t, f64::INFINITY) {
+            Some(h) => {
+                let entering = r.dir.dot(h.normal) < 0.0;
+                match (entering, entered) {
+                    (true, _) => entered = Some(h.t),
+                    // Leaving without having entered, the ray started inside
+                    (false, None) => if first {
+                        segments.push((t_min, h.t));
+                    },
+                    (false, Some(start)) => {
+                        segments.push((start, h.t));
+                        entered = None;
+                    }
+                }
+                if h.t >= t_max {
+                    break;
+                }
+                first = false;
+                t = h.t + MIN_DISTANCE;
+            }
+            None => break,
+        }
+    }
+    segments.into_iter()
+        .map(|(a, b)| (a.max(t_min), b.min(t_max)))
+        .filter(|&(a, b)| a < b)
+        .collect()
+}
+
 //
 // Utilities
 //
@@ -53,6 +131,13 @@ pub fn rotate<H: Hitable + BoundingBox>(h: H, axis: Axis, degrees: f64) -> Rotat
     Rotate::new(h, axis, degrees)
 }
 
+pub fn alpha_mask<H: Hitable, T: Texture>(h: H, opacity: T) -> AlphaMask<H, T> {
+    AlphaMask {
+        h: h,
+        opacity: opacity,
+    }
+}
+
 // FlipNormals
 pub struct FlipNormals<H: Hitable> {
     h: H,
@@ -84,6 +169,7 @@ impl<H: Hitable> Hitable for Translate<H> {
         let moved_r = Ray {
             origin: r.origin - self.offset,
             dir: r.dir,
+            .. *r
         };
 
         self.h.hit(&moved_r, t_min, t_max).map(|x| {
@@ -98,6 +184,53 @@ impl<H: Hitable + BoundingBox> BoundingBox for Translate<H> {
     }
 }
 
+// Alpha mask
+// Cut out geometry, hits are ignored where opacity (first channel) is 0 and kept with probability
+// opacity in between, so leaves and fences need no dielectric
+// Which points are kept in between is a hash of the point, so it is the same for every ray and
+// every render
+pub struct AlphaMask<H: Hitable, T: Texture> {
+    h: H,
+    opacity: T,
+}
+
+impl<H: Hitable, T: Texture> Hitable for AlphaMask<H, T> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut t_min = t_min;
+        loop {
+            match self.h.hit(r, t_min, t_max) {
+                Some(x) => {
+                    let opacity = self.opacity.value(x.u, x.v, &x.p).x;
+                    if opacity >= 1.0 || (opacity > 0.0 && hash_to_unit(&x.p) < opacity) {
+                        return Some(x);
+                    }
+                    // Look for the next hit behind this one, nudged so it is not found again
+                    t_min = x.t + f64::EPSILON * 16.0 * x.t.abs().max(1.0);
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+// Uniform in [0, 1) from the bits of p, mixed by splitmix64
+fn hash_to_unit(p: &Vec3) -> f64 {
+    let mut h = 0u64;
+    for &x in &[p.x, p.y, p.z] {
+        h = (h ^ x.to_bits()).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl<H: Hitable + BoundingBox, T: Texture> BoundingBox for AlphaMask<H, T> {
+    fn bounding_box(&self) -> AABB {
+        self.h.bounding_box()
+    }
+}
+
 // Rotation
 pub struct Rotate<H: Hitable + BoundingBox> {
     h: H,
@@ -151,12 +284,15 @@ impl<H: Hitable + BoundingBox> Hitable for Rotate<H> {
         let moved_r = Ray {
             origin: r.origin.rotate(&self.axis, self.cos_theta, -self.sin_theta),
             dir: r.dir.rotate(&self.axis, self.cos_theta, -self.sin_theta),
+            .. *r
         };
 
         self.h.hit(&moved_r, t_min, t_max).map(|x| {
             HitRecord {
                 p: x.p.rotate(&self.axis, self.cos_theta, self.sin_theta),
                 normal: x.normal.rotate(&self.axis, self.cos_theta, self.sin_theta),
+                dpdu: x.dpdu.rotate(&self.axis, self.cos_theta, self.sin_theta),
+                dpdv: x.dpdv.rotate(&self.axis, self.cos_theta, self.sin_theta),
                 .. x
             }
         })
@@ -168,3 +304,54 @@ impl<H: Hitable + BoundingBox> BoundingBox for Rotate<H> {
         self.bounding_box
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::rect::Rect;
+    use model::sphere::Sphere;
+    use shader::texture;
+
+    #[test]
+    fn test_alpha_mask_holes() {
+        // Left half of the rect cut out
+        #[derive(Debug, Clone)]
+        struct LeftHalf;
+        impl Texture for LeftHalf {
+            fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+                if u < 0.5 { Vec3::new(0.0,0.0,0.0) } else { Vec3::new(1.0,1.0,1.0) }
+            }
+        }
+        let r = alpha_mask(Rect::xy_rect(0.0, 1.0, 0.0, 1.0, 0.0), LeftHalf);
+        let ray = |x: f64| Ray::new(Vec3::new(x, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(r.hit(&ray(0.25), 0.0, 10.0).is_none());
+        assert!(r.hit(&ray(0.75), 0.0, 10.0).is_some());
+    }
+
+    #[test]
+    fn test_alpha_mask_finds_hit_behind() {
+        // Front of the sphere cut out, the ray hits the back from inside
+        #[derive(Debug, Clone)]
+        struct Back;
+        impl Texture for Back {
+            fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
+                if p.z < 0.0 { Vec3::new(0.0,0.0,0.0) } else { Vec3::new(1.0,1.0,1.0) }
+            }
+        }
+        let s = alpha_mask(Sphere::unit_sphere(), Back);
+        let h = s.hit(&Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0)), 0.0, 100.0).unwrap();
+        assert!(h.p.z > 0.99);
+    }
+
+    #[test]
+    fn test_alpha_mask_fractional() {
+        let s = alpha_mask(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(0.5,0.5,0.5)));
+        let ray = |i: i32| Ray::new(Vec3::new((i % 100) as f64 * 0.01 - 0.5, (i / 100) as f64 * 0.01 - 0.5, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let hits = (0..10000).filter(|&i| s.hit(&ray(i), 0.0, 100.0).is_some()).count();
+        // Two chances at 50%, front and back
+        assert!(hits > 7200 && hits < 7800);
+
+        // The same every time along a ray
+        assert!(s.hit(&ray(1234), 0.0, 100.0) == s.hit(&ray(1234), 0.0, 100.0));
+    }
+}
@@ -3,15 +3,85 @@ pub mod hitable;
 pub mod sphere;
 pub mod cube;
 pub mod rect;
+pub mod disk;
+pub mod cylinder;
+pub mod cone;
+pub mod torus;
+pub mod quartic;
 pub mod constant_medium;
+pub mod heterogeneous_medium;
 
 use model::bvh::{AABB, BoundingBox};
-use model::hitable::{HitRecord, Hitable};
+use light::Light;
+use light_sampler::LightSampler;
+use medium::{Interior, Medium};
+use model::hitable::{AlphaMask, HitRecord, Hitable};
 use ray::Ray;
 use shader::material::Material;
+use vec3::Vec3;
+use shader::texture::Texture;
+
+use rand::XorShiftRng;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait Renderable: Sync + Send {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(HitRecord, &Material)>;
+    // rng is the path's, for objects that hit at random like volumes, so seeded renders repeat
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)>;
+
+    // Fraction of light getting through along r between t_min and t_max, for shadow rays. Black
+    // when anything is in the way, volumes estimate how much of it they let through instead
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        match self.hit(r, t_min, t_max, rng) {
+            Some(_) => Vec3::new(0.0,0.0,0.0),
+            None => Vec3::new(1.0,1.0,1.0),
+        }
+    }
+
+    // Lights sampled explicitly at every hit, see scene::Scene
+    fn lights(&self) -> &[Box<Light>] {
+        &[]
+    }
+
+    // Radiance arriving along rays that hit nothing
+    fn background(&self, _r: &Ray) -> Vec3 {
+        Vec3::new(0.0,0.0,0.0)
+    }
+
+    // How lights() are picked from, None samples all of them
+    fn light_sampler(&self) -> Option<&LightSampler> {
+        None
+    }
+
+    // What fills the object with this id, see medium
+    fn interior(&self, _object: u32) -> Option<&Interior> {
+        None
+    }
+
+    // Medium outside of every object, e.g. fog
+    fn atmosphere(&self) -> Option<&Medium> {
+        None
+    }
+
+    // What the materials of the objects fill them with, by object id, see Material::interior
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        vec![]
+    }
+}
+
+// Identifies what was hit, for output variables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelIds {
+    pub object: u32,
+    pub material: u32,
+}
+
+// Object ids are handed out in order of construction, starting at 1
+static NEXT_OBJECT_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn next_object_id() -> u32 {
+    NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed) as u32 + 1
 }
 
 pub struct Model<G, M>
@@ -20,6 +90,7 @@ pub struct Model<G, M>
 {
     pub geometry: G,
     pub shader: M,
+    pub ids: ModelIds,
 }
 
 unsafe impl<G, M> Sync for Model<G, M>
@@ -37,7 +108,32 @@ impl<G, M> Model<G, M>
           M: Material
 {
     pub fn new(geometry: G, shader: M) -> Model<G, M> {
-        Model { geometry: geometry, shader: shader }
+        Model {
+            geometry: geometry,
+            shader: shader,
+            ids: ModelIds { object: next_object_id(), material: 0 },
+        }
+    }
+
+    // Override the generated object id, e.g. to keep it stable across animation frames
+    pub fn with_object_id(mut self, id: u32) -> Model<G, M> {
+        self.ids.object = id;
+        self
+    }
+
+    // Material ids are 0 unless set, since materials are cloned between models
+    pub fn with_material_id(mut self, id: u32) -> Model<G, M> {
+        self.ids.material = id;
+        self
+    }
+
+    // Cut out the geometry where opacity is 0, see hitable::AlphaMask
+    pub fn with_opacity<T: Texture>(self, opacity: T) -> Model<AlphaMask<G, T>, M> {
+        Model {
+            geometry: hitable::alpha_mask(self.geometry, opacity),
+            shader: self.shader,
+            ids: self.ids,
+        }
     }
 }
 
@@ -45,8 +141,12 @@ impl<G, M> Renderable for Model<G, M>
     where G: Hitable,
           M: Material
 {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(HitRecord, &Material)> {
-        self.geometry.hit(r,t_min,t_max).map(|h| (h, &self.shader as &Material))
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, _rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
+        self.geometry.hit(r,t_min,t_max).map(|h| (h, &self.shader as &Material, self.ids))
+    }
+
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        self.shader.interior().map(|i| (self.ids.object, i.clone())).into_iter().collect()
     }
 }
 
@@ -60,15 +160,65 @@ impl<G, M> BoundingBox for Model<G, M>
 }
 
 impl Renderable for Vec<Box<Renderable>> {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(HitRecord, &Material)> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
         let mut hit = None;
         let mut closest = t_max;
         for x in self {
-            if let Some((h, m)) = x.hit(r,t_min,closest) {
+            if let Some((h, m, ids)) = x.hit(r,t_min,closest,rng) {
                 closest = h.t;
-                hit = Some((h, m))
+                hit = Some((h, m, ids))
             }
         }
         hit
     }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        let mut transmittance = Vec3::new(1.0,1.0,1.0);
+        for x in self {
+            transmittance = transmittance * x.transmittance(r, t_min, t_max, rng);
+            if transmittance == Vec3::new(0.0,0.0,0.0) {
+                break;
+            }
+        }
+        transmittance
+    }
+
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        self.iter().flat_map(|x| x.interiors()).collect()
+    }
+}
+
+// Shared scenes, e.g. a BVH reused between animation frames
+impl<T: Renderable + ?Sized> Renderable for Arc<T> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
+        (**self).hit(r, t_min, t_max, rng)
+    }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        (**self).transmittance(r, t_min, t_max, rng)
+    }
+
+    fn lights(&self) -> &[Box<Light>] {
+        (**self).lights()
+    }
+
+    fn background(&self, r: &Ray) -> Vec3 {
+        (**self).background(r)
+    }
+
+    fn light_sampler(&self) -> Option<&LightSampler> {
+        (**self).light_sampler()
+    }
+
+    fn interior(&self, object: u32) -> Option<&Interior> {
+        (**self).interior(object)
+    }
+
+    fn atmosphere(&self) -> Option<&Medium> {
+        (**self).atmosphere()
+    }
+
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        (**self).interiors()
+    }
 }
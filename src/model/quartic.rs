@@ -0,0 +1,198 @@
+use vec3::Vec3;
+use ray::Ray;
+use model::bvh::{AABB, BoundingBox};
+use model::hitable::{HitRecord, Hitable};
+use util::solve_quartic;
+
+// Implicit surface where a polynomial of degree up to four in x, y and z is zero, clipped to
+// bounds, e.g. Goursat's surface, Steiner's surface, blobs, or a torus along any axis
+// terms are (coefficient, (power of x, power of y, power of z)), inside is where the polynomial is
+// negative and the normal is its gradient. No u, v parameterization
+#[derive(Debug, Clone)]
+pub struct Quartic {
+    pub terms: Vec<(f64, (u32, u32, u32))>,
+    pub bounds: AABB,
+}
+
+impl Quartic {
+    pub fn new(terms: &[(f64, (u32, u32, u32))], bounds: AABB) -> Quartic {
+        assert!(terms.iter().all(|&(_, (i, j, k))| i + j + k <= 4), "degree over four");
+        Quartic {
+            terms: terms.to_vec(),
+            bounds: bounds,
+        }
+    }
+
+    pub fn value(&self, p: &Vec3) -> f64 {
+        self.terms.iter()
+            .map(|&(c, (i, j, k))| c * p.x.powi(i as i32) * p.y.powi(j as i32) * p.z.powi(k as i32))
+            .sum()
+    }
+
+    pub fn gradient(&self, p: &Vec3) -> Vec3 {
+        // d/dx of x^i, 0 when i is 0
+        let d = |x: f64, i: u32| if i == 0 { 0.0 } else { i as f64 * x.powi(i as i32 - 1) };
+        self.terms.iter().fold(Vec3::new(0.0,0.0,0.0), |sum, &(c, (i, j, k))| {
+            let (x, y, z) = (p.x.powi(i as i32), p.y.powi(j as i32), p.z.powi(k as i32));
+            sum + Vec3::new(d(p.x, i) * y * z, x * d(p.y, j) * z, x * y * d(p.z, k)) * c
+        })
+    }
+
+    // Real roots of the polynomial along r, in units of r's parameter
+    //
+    // Shifted as in Torus::roots: the ray is normalized and moved up to the bounding sphere of the
+    // bounds before the polynomial along it is expanded
+    fn roots(&self, r: &Ray) -> Vec<f64> {
+        let speed = r.dir.length();
+        let d = r.dir / speed;
+        let center = (self.bounds.min + self.bounds.max) * 0.5;
+        let bound = 0.5 * (self.bounds.max - self.bounds.min).length();
+        let shift = (-(r.origin - center).dot(d) - bound).max(0.0);
+        let o = r.origin + d * shift;
+
+        // Powers 0 to 4 of o + s d along each axis, as polynomials in s
+        let powers = |o: f64, d: f64| {
+            let mut p = [[0.0; 5]; 5];
+            p[0][0] = 1.0;
+            for i in 1..5 {
+                p[i] = multiply(&p[i - 1], &[o, d, 0.0, 0.0, 0.0]);
+            }
+            p
+        };
+        let (px, py, pz) = (powers(o.x, d.x), powers(o.y, d.y), powers(o.z, d.z));
+        let mut poly = [0.0; 5];
+        for &(c, (i, j, k)) in &self.terms {
+            let term = multiply(&multiply(&px[i as usize], &py[j as usize]), &pz[k as usize]);
+            for n in 0..5 {
+                poly[n] += c * term[n];
+            }
+        }
+
+        let roots = solve_quartic(poly[4], poly[3], poly[2], poly[1], poly[0]);
+        roots.into_iter().map(|s| (s + shift) / speed).collect()
+    }
+
+    fn contains(&self, p: &Vec3) -> bool {
+        let eps = 1e-9 * (self.bounds.max - self.bounds.min).length();
+        p.x >= self.bounds.min.x - eps && p.x <= self.bounds.max.x + eps &&
+            p.y >= self.bounds.min.y - eps && p.y <= self.bounds.max.y + eps &&
+            p.z >= self.bounds.min.z - eps && p.z <= self.bounds.max.z + eps
+    }
+}
+
+// Product of polynomials by ascending powers, dropping powers over four
+fn multiply(a: &[f64; 5], b: &[f64; 5]) -> [f64; 5] {
+    let mut product = [0.0; 5];
+    for i in 0..5 {
+        for j in 0..5 - i {
+            product[i + j] += a[i] * b[j];
+        }
+    }
+    product
+}
+
+impl Hitable for Quartic {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if !self.bounds.hit(r, t_min, t_max) {
+            return None;
+        }
+        self.roots(r).into_iter()
+            .filter(|&t| t > t_min && t < t_max)
+            .map(|t| (t, r.point_at_parameter(t)))
+            .find(|&(_, p)| self.contains(&p))
+            .map(|(t, p)| HitRecord {
+                t: t,
+                p: p,
+                normal: self.gradient(&p).normalized(),
+                u: 0.0,
+                v: 0.0,
+                dpdu: Vec3::new(0.0,0.0,0.0),
+                dpdv: Vec3::new(0.0,0.0,0.0),
+            })
+    }
+}
+
+impl BoundingBox for Quartic {
+    fn bounding_box(&self) -> AABB {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::torus::Torus;
+    use rand::*;
+
+    // Torus around the y axis through the origin, as Torus
+    fn torus(major_radius: f64, minor_radius: f64) -> Quartic {
+        // (x^2 + y^2 + z^2 + R^2 - r^2)^2 - 4 R^2 (x^2 + z^2)
+        let (big, k) = (major_radius * major_radius, major_radius * major_radius - minor_radius * minor_radius);
+        let outer = major_radius + minor_radius;
+        Quartic::new(&[
+            (1.0, (4, 0, 0)), (1.0, (0, 4, 0)), (1.0, (0, 0, 4)),
+            (2.0, (2, 2, 0)), (2.0, (2, 0, 2)), (2.0, (0, 2, 2)),
+            (2.0 * k - 4.0 * big, (2, 0, 0)), (2.0 * k, (0, 2, 0)), (2.0 * k - 4.0 * big, (0, 0, 2)),
+            (k * k, (0, 0, 0)),
+        ], AABB { min: Vec3::new(-outer, -minor_radius, -outer), max: Vec3::new(outer, minor_radius, outer) })
+    }
+
+    #[test]
+    fn test_matches_torus() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let quartic = torus(1.5, 0.4);
+        let reference = Torus::new(Vec3::new(0.0,0.0,0.0), 1.5, 0.4);
+        let mut hits = 0;
+        for i in 0..2000 {
+            // From all around, some from inside the tube or the hole, some from far away
+            let distance_out = if i % 10 == 0 { 200.0 } else { 0.5 + 4.0 * rng.gen::<f64>() };
+            let origin = (Vec3::random(&mut rng) - Vec3::new(0.5, 0.5, 0.5)).normalized() * distance_out;
+            let target = (Vec3::random(&mut rng) - Vec3::new(0.5, 0.5, 0.5)) * 4.0;
+            let r = Ray::new(origin, (target - origin) * (0.5 + rng.gen::<f64>()));
+            let t_max = 2.0 * (target - origin).length() / r.dir.length();
+
+            match (reference.hit(&r, 1e-9, t_max), quartic.hit(&r, 1e-9, t_max)) {
+                (Some(expected), Some(h)) => {
+                    hits += 1;
+                    let tolerance = 1e-6 * (1.0 + distance_out) / r.dir.length();
+                    assert!((h.t - expected.t).abs() < tolerance, "{:?}: torus {} quartic {}", r, expected.t, h.t);
+                    assert!((h.normal - expected.normal).length() < 1e-6);
+                }
+                (None, None) => {}
+                (expected, found) => panic!("{:?}: torus {:?} quartic {:?}", r, expected, found),
+            }
+        }
+        assert!(hits > 500);
+    }
+
+    #[test]
+    fn test_clipped_to_bounds() {
+        // Infinite cylinder x^2 + z^2 = 1 cut to a unit high tube, a quadric solved as a quartic
+        let tube = Quartic::new(&[(1.0, (2, 0, 0)), (1.0, (0, 0, 2)), (-1.0, (0, 0, 0))],
+                                AABB { min: Vec3::new(-1.0, 0.0, -1.0), max: Vec3::new(1.0, 1.0, 1.0) });
+        let h = tube.hit(&Ray::new(Vec3::new(-5.0, 0.5, 0.0), Vec3::new(2.0, 0.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.t - 2.0).abs() < 1e-9);
+        assert!((h.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-9);
+        assert!(tube.value(&h.p).abs() < 1e-9);
+
+        // Above the tube, and down its open end to the far wall
+        assert!(tube.hit(&Ray::new(Vec3::new(-5.0, 1.5, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).is_none());
+        let h = tube.hit(&Ray::new(Vec3::new(0.0, 1.5, 0.0), Vec3::new(1.0, -1.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.p - Vec3::new(1.0, 0.5, 0.0)).length() < 1e-9);
+        assert!((h.normal - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-9);
+        assert!(tube.hit(&Ray::new(Vec3::new(0.0, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0).is_none());
+    }
+
+    #[test]
+    fn test_gradient() {
+        let quartic = torus(2.0, 0.7);
+        let p = Vec3::new(0.3, -0.4, 1.7);
+        let eps = 1e-6;
+        let numeric = Vec3::new(
+            quartic.value(&(p + Vec3::new(eps, 0.0, 0.0))) - quartic.value(&(p - Vec3::new(eps, 0.0, 0.0))),
+            quartic.value(&(p + Vec3::new(0.0, eps, 0.0))) - quartic.value(&(p - Vec3::new(0.0, eps, 0.0))),
+            quartic.value(&(p + Vec3::new(0.0, 0.0, eps))) - quartic.value(&(p - Vec3::new(0.0, 0.0, eps))),
+        ) / (2.0 * eps);
+        assert!((quartic.gradient(&p) - numeric).length() < 1e-6);
+    }
+}
@@ -103,6 +103,8 @@ impl Hitable for Rect {
             normal: Vec3::new(0.0,0.0,0.0).set_axis(&self.main, 1.0),
             u: (p.get_axis(&self.off_a) - self.a0) / (self.a1 - self.a0),
             v: (p.get_axis(&self.off_b) - self.b0) / (self.b1 - self.b0),
+            dpdu: Vec3::new(0.0,0.0,0.0).set_axis(&self.off_a, self.a1 - self.a0),
+            dpdv: Vec3::new(0.0,0.0,0.0).set_axis(&self.off_b, self.b1 - self.b0),
         })
     }
 }
@@ -14,15 +14,32 @@ impl Sphere {
     pub fn hit_at_t(&self, r: &Ray, t: f64) -> HitRecord {
         let surface_hit = r.point_at_parameter(t);
         let (u, v) = Sphere::get_sphere_uv(&((surface_hit - self.center) / self.radius));
+        let (dpdu, dpdv) = self.tangents(&((surface_hit - self.center) / self.radius));
         HitRecord {
             t: t,
             p: surface_hit,
             normal: (surface_hit - self.center) / self.radius,
             u: u,
             v: v,
+            dpdu: dpdu,
+            dpdv: dpdv,
         }
     }
 
+    // Derivatives of get_sphere_uv's inverse at p on the unit sphere, scaled by the radius
+    // u turns around the y axis, v goes up from the bottom pole
+    fn tangents(&self, p: &Vec3) -> (Vec3, Vec3) {
+        let cos_theta = (p.x * p.x + p.z * p.z).sqrt();
+        let dpdu = Vec3::new(p.z, 0.0, -p.x) * (2.0 * PI * self.radius);
+        let dpdv = if cos_theta > 1e-9 {
+            Vec3::new(-p.y * p.x / cos_theta, cos_theta, -p.y * p.z / cos_theta) * (PI * self.radius)
+        } else {
+            // At a pole, any direction in the tangent plane
+            Vec3::new(1.0, 0.0, 0.0) * (PI * self.radius)
+        };
+        (dpdu, dpdv)
+    }
+
     pub fn unit_sphere() -> Sphere {
         Sphere {
             center: Vec3::new(0.0,0.0,0.0),
@@ -84,6 +101,7 @@ mod tests {
         let r = Ray {
           origin: Vec3::new(0.0, 0.0, 1.0),
           dir: Vec3::new(0.0, 0.0, -1.0),
+          wavelength: 0.0,
         };
         let expected = HitRecord {
           t: 1.0,
@@ -91,6 +109,8 @@ mod tests {
           normal: Vec3::new(0.0, 0.0, 1.0),
           u: 0.0,
           v: 0.0,
+          dpdu: Vec3::new(0.0, 0.0, 0.0),
+          dpdv: Vec3::new(0.0, 0.0, 0.0),
         };
         let res = s.hit(&r, -100.0, 100.0);
         assert!(res.clone().is_some());
@@ -98,4 +118,21 @@ mod tests {
         assert!(res.clone().unwrap().p == expected.p);
         assert!(res.clone().unwrap().normal == expected.normal);
     }
+
+    #[test]
+    fn test_tangents_match_uv() {
+        // Stepping along dp/du or dp/dv moves u or v by the step
+        let s = Sphere {
+          center: Vec3::new(1.0, 2.0, 3.0),
+          radius: 2.0,
+        };
+        let h = s.hit(&Ray::new(Vec3::new(1.0, 2.5, -3.0), Vec3::new(0.1, 0.2, 1.0)), 0.0, 100.0).unwrap();
+        let uv_at = |p: Vec3| Sphere::get_sphere_uv(&(p - s.center).normalized());
+        let eps = 1e-6;
+        let (u, v) = uv_at(h.p + h.dpdu * eps);
+        assert!((u - h.u - eps).abs() < 1e-8 && (v - h.v).abs() < 1e-8);
+        let (u, v) = uv_at(h.p + h.dpdv * eps);
+        assert!((u - h.u).abs() < 1e-8 && (v - h.v - eps).abs() < 1e-8);
+        assert!(h.dpdu.dot(h.normal).abs() < 1e-9 && h.dpdv.dot(h.normal).abs() < 1e-9);
+    }
 }
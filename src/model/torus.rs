@@ -0,0 +1,211 @@
+use vec3::Vec3;
+use ray::Ray;
+use model::bvh::{AABB, BoundingBox};
+use model::hitable::{HitRecord, Hitable};
+use util::{azimuth, solve_quartic};
+
+use std::f64::consts::PI;
+
+// Torus around the y axis through center: a tube of minor_radius swept around a circle of
+// major_radius, e.g. rings and pipes
+// u goes around the y axis from +x towards +z, v around the tube from its outer edge, upwards
+#[derive(Debug, Clone)]
+pub struct Torus {
+    pub center: Vec3,
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, major_radius: f64, minor_radius: f64) -> Torus {
+        Torus {
+            center: center,
+            major_radius: major_radius,
+            minor_radius: minor_radius,
+        }
+    }
+
+    // Real roots of the quartic along r for the torus at the origin, in units of r's parameter
+    //
+    // The quartic's coefficients grow with the fourth power of the distance to the origin, so far
+    // away rays lose precision to cancellation. The ray is first normalized and moved up to the
+    // torus's bounding sphere, and solved from there
+    fn roots(&self, r: &Ray) -> Vec<f64> {
+        let speed = r.dir.length();
+        let d = r.dir / speed;
+        let o = r.origin - self.center;
+        let bound = self.major_radius + self.minor_radius;
+        let shift = (-o.dot(d) - bound).max(0.0);
+        let o = o + d * shift;
+
+        let (big, small) = (self.major_radius * self.major_radius, self.minor_radius * self.minor_radius);
+        let k = o.dot(d);
+        let sum = o.dot(o) + big - small;
+        let roots = solve_quartic(
+            1.0,
+            4.0 * k,
+            4.0 * k * k + 2.0 * sum - 4.0 * big * (d.x * d.x + d.z * d.z),
+            4.0 * k * sum - 8.0 * big * (o.x * d.x + o.z * d.z),
+            sum * sum - 4.0 * big * (o.x * o.x + o.z * o.z),
+        );
+        roots.into_iter().map(|s| (s + shift) / speed).collect()
+    }
+
+    fn hit_at_t(&self, r: &Ray, t: f64) -> HitRecord {
+        let p = r.point_at_parameter(t) - self.center;
+        let phi = azimuth(p.x, p.z);
+        let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+
+        // Out from the nearest point on the major circle
+        let ring = Vec3::new(cos_phi, 0.0, sin_phi) * self.major_radius;
+        let normal = (p - ring) / self.minor_radius;
+        let theta = normal.y.atan2(normal.x * cos_phi + normal.z * sin_phi);
+        let theta = if theta < 0.0 { theta + 2.0 * PI } else { theta };
+        let (cos_theta, sin_theta) = (theta.cos(), theta.sin());
+
+        let radius = self.major_radius + self.minor_radius * cos_theta;
+        HitRecord {
+            t: t,
+            p: self.center + p,
+            normal: normal,
+            u: phi / (2.0 * PI),
+            v: theta / (2.0 * PI),
+            dpdu: Vec3::new(-sin_phi, 0.0, cos_phi) * (2.0 * PI * radius),
+            dpdv: Vec3::new(-sin_theta * cos_phi, cos_theta, -sin_theta * sin_phi) * (2.0 * PI * self.minor_radius),
+        }
+    }
+}
+
+impl Hitable for Torus {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if !self.bounding_box().hit(r, t_min, t_max) {
+            return None;
+        }
+        self.roots(r).into_iter()
+            .find(|&t| t > t_min && t < t_max)
+            .map(|t| self.hit_at_t(r, t))
+    }
+}
+
+impl BoundingBox for Torus {
+    fn bounding_box(&self) -> AABB {
+        let outer = self.major_radius + self.minor_radius;
+        AABB {
+            min: self.center - Vec3::new(outer, self.minor_radius, outer),
+            max: self.center + Vec3::new(outer, self.minor_radius, outer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::inside_segments;
+    use rand::*;
+    use std::f64;
+
+    // Signed distance to the torus, negative inside the tube
+    fn distance(torus: &Torus, p: Vec3) -> f64 {
+        let q = p - torus.center;
+        let across = (q.x * q.x + q.z * q.z).sqrt() - torus.major_radius;
+        (across * across + q.y * q.y).sqrt() - torus.minor_radius
+    }
+
+    // First crossing of the surface along r by sphere tracing, then bisection, None past t_max
+    fn ray_march(torus: &Torus, r: &Ray, t_max: f64) -> Option<f64> {
+        let speed = r.dir.length();
+        let inside = distance(torus, r.origin) < 0.0;
+        let (mut previous, mut t) = (0.0, 0.0);
+        while t < t_max {
+            let d = distance(torus, r.point_at_parameter(t));
+            if (d < 0.0) != inside {
+                // Crossed within the last step
+                let (mut lo, mut hi) = (previous, t);
+                for _ in 0..100 {
+                    let mid = 0.5 * (lo + hi);
+                    if (distance(torus, r.point_at_parameter(mid)) < 0.0) != inside {
+                        hi = mid;
+                    } else {
+                        lo = mid;
+                    }
+                }
+                return Some(hi);
+            }
+            // Never jumps a crossing, and small steps near the surface from either side
+            previous = t;
+            t += d.abs().max(1e-4) / speed;
+        }
+        None
+    }
+
+    #[test]
+    fn test_hit() {
+        let torus = Torus::new(Vec3::new(0.0, 1.0, 0.0), 2.0, 0.5);
+        let h = torus.hit(&Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.t - 2.5).abs() < 1e-9);
+        assert!((h.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-9);
+        assert!((h.u - 0.5).abs() < 1e-9 && h.v.abs() < 1e-9);
+
+        // Through the hole, and over the top
+        assert!(torus.hit(&Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0).is_none());
+        let h = torus.hit(&Ray::new(Vec3::new(2.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)), 0.0, 100.0).unwrap();
+        assert!((h.t - 3.5).abs() < 1e-9 && (h.v - 0.25).abs() < 1e-9);
+
+        // Closed, so it can bound a medium
+        let s = inside_segments(&torus, &Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)), 0.0, f64::INFINITY);
+        assert!(s.len() == 2);
+        assert!((s[1].0 - 6.5).abs() < 1e-9 && (s[1].1 - 7.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_matches_ray_marching() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let torus = Torus::new(Vec3::new(0.5, -0.3, 1.0), 1.5, 0.4);
+        let mut hits = 0;
+        for i in 0..2000 {
+            // Aimed near the torus from all around, some from inside the tube or the hole, some
+            // from far away
+            let distance_out = if i % 10 == 0 { 200.0 } else { 0.5 + 4.0 * rng.gen::<f64>() };
+            let origin = torus.center + (Vec3::random(&mut rng) - Vec3::new(0.5, 0.5, 0.5)).normalized() * distance_out;
+            let target = torus.center + (Vec3::random(&mut rng) - Vec3::new(0.5, 0.5, 0.5)) * 4.0;
+            let r = Ray::new(origin, (target - origin) * (0.5 + rng.gen::<f64>()));
+            let t_max = 2.0 * (target - origin).length() / r.dir.length();
+
+            let expected = ray_march(&torus, &r, t_max);
+            let found = torus.hit(&r, 1e-9, t_max);
+            match (expected, found) {
+                (Some(t), Some(h)) => {
+                    hits += 1;
+                    let tolerance = 1e-6 * (1.0 + distance_out) / r.dir.length();
+                    assert!((h.t - t).abs() < tolerance, "{:?}: marched {} solved {}", r, t, h.t);
+                    assert!(distance(&torus, h.p).abs() < 1e-6);
+                    assert!((h.normal.length() - 1.0).abs() < 1e-9);
+                }
+                (None, None) => {}
+                // Grazing hits marching may step over
+                (None, Some(h)) => assert!(distance(&torus, h.p).abs() < 1e-6 && h.normal.dot(r.dir).abs() < 0.05 * r.dir.length(), "{:?} solved {}", r, h.t),
+                (Some(t), None) => panic!("{:?}: marched {} not solved", r, t),
+            }
+        }
+        assert!(hits > 500);
+    }
+
+    #[test]
+    fn test_tangents_match_uv() {
+        let torus = Torus::new(Vec3::new(1.0, -1.0, 2.0), 2.0, 0.7);
+        let h = torus.hit(&Ray::new(Vec3::new(-4.0, 0.0, 1.0), Vec3::new(1.0, -0.2, 0.3)), 0.0, 100.0).unwrap();
+        let uv_at = |p: Vec3| {
+            let q = p - torus.center;
+            let across = (q.x * q.x + q.z * q.z).sqrt() - torus.major_radius;
+            let theta = q.y.atan2(across);
+            let theta = if theta < 0.0 { theta + 2.0 * PI } else { theta };
+            (azimuth(q.x, q.z) / (2.0 * PI), theta / (2.0 * PI))
+        };
+        let eps = 1e-6;
+        let (u, v) = uv_at(h.p + h.dpdu * eps);
+        assert!((u - h.u - eps).abs() < 1e-8 && (v - h.v).abs() < 1e-8);
+        let (u, v) = uv_at(h.p + h.dpdv * eps);
+        assert!((u - h.u).abs() < 1e-8 && (v - h.v - eps).abs() < 1e-8);
+        assert!(h.dpdu.dot(h.normal).abs() < 1e-9 && h.dpdv.dot(h.normal).abs() < 1e-9);
+    }
+}
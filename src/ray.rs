@@ -3,12 +3,14 @@ use ::vec3::Vec3;
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
-    pub dir: Vec3
+    pub dir: Vec3,
+    // Wavelength in nanometers for spectral rendering, 0 when rendering RGB
+    pub wavelength: f64,
 }
 
 impl Ray {
     pub fn new(o: Vec3, d: Vec3) -> Ray {
-        Ray { origin: o, dir: d }
+        Ray { origin: o, dir: d, wavelength: 0.0 }
     }
 
     pub fn point_at_parameter(self, t: f64) -> Vec3 {
@@ -25,6 +27,7 @@ mod tests {
         let r = Ray {
             origin: Vec3::new(1.0,0.0,0.0),
             dir: Vec3::new(1.0,2.0,3.0),
+            wavelength: 0.0,
         };
         assert!(r.point_at_parameter(0.5) == Vec3::new(1.5,1.0,1.5))
     }
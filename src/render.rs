@@ -3,12 +3,20 @@
 extern crate rand;
 
 use camera::Camera;
-use model::{bvh, Renderable, Model};
+use light::{self, Light};
+use light_sampler::LightSampling;
+use medium::{Medium, MediumStack};
+use film::{Aov, Film};
+use model::{bvh, Renderable, Model, ModelIds};
+use model::hitable::HitRecord;
 use model::sphere::Sphere;
+use scene::Scene;
 use ray::Ray;
 use shader::material::Material;
+use spectrum::Wavelengths;
 use vec3::{Vec3};
 
+use std::f64;
 use std::io::Write;
 use std::thread;
 use std::sync::Arc;
@@ -26,6 +34,83 @@ const COLOR_WHITE:   Vec3 = Vec3 { x: 1.0, y: 1.0, z: 1.0 };
 const COLOR_DEFAULT: Vec3 = Vec3 { x: 0.0, y: 0.0, z: 0.0 };
 
 
+// Rectangle of pixels to render, x0..x1 by y0..y1 (exclusive) from the top left of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl Region {
+    pub fn full(nx: i32, ny: i32) -> Region {
+        Region { x0: 0, y0: 0, x1: nx, y1: ny }
+    }
+
+    // Region from a normalized crop window, each coordinate in [0,1] from the top left
+    // Rounds outward so any partially covered pixel is rendered
+    pub fn from_window(nx: i32, ny: i32, x0: f64, y0: f64, x1: f64, y1: f64) -> Region {
+        let clamp = |x: f64| x.max(0.0).min(1.0);
+        Region {
+            x0: (clamp(x0) * nx as f64).floor() as i32,
+            y0: (clamp(y0) * ny as f64).floor() as i32,
+            x1: (clamp(x1) * nx as f64).ceil() as i32,
+            y1: (clamp(y1) * ny as f64).ceil() as i32,
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> i32 {
+        self.y1 - self.y0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+}
+
+// Options for render_film
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub nx: i32,      // width of image
+    pub ny: i32,      // height of image
+    pub spp: i32,     // samples per pixel
+    pub nthreads: i32,
+    pub debug: bool,  // write progress to stderr
+    pub region: Region,
+    pub aovs: Vec<Aov>,
+
+    // Firefly suppression, both biased so off by default
+    // Maximum luminance of light reaching the first hit after more than one bounce, per sample
+    pub clamp_indirect: Option<f64>,
+    // Reject samples more than this many standard deviations brighter than the pixel's others
+    pub outlier_rejection: Option<f64>,
+
+    // Trace wavelengths instead of RGB, needed for dispersion
+    pub spectral: bool,
+}
+
+impl Settings {
+    pub fn new(nx: i32, ny: i32, spp: i32) -> Settings {
+        Settings {
+            nx: nx,
+            ny: ny,
+            spp: spp,
+            nthreads: 4,
+            debug: false,
+            region: Region::full(nx, ny),
+            aovs: vec![],
+            clamp_indirect: None,
+            outlier_rejection: None,
+            spectral: false,
+        }
+    }
+}
+
 // Renders scene
 //
 // Return buffer of RGB triples as ints from top left to bottom right
@@ -45,117 +130,566 @@ pub fn render (
     nthreads: i32,
     debug: bool,
 ) -> Vec<i32>  {
+    render_region(scene, camera, nx, ny, spp, nthreads, debug, Region::full(nx, ny))
+}
+
+// Renders only the pixels inside region of the nx by ny image
+//
+// Return buffer of RGB triples for the region, region.width() by region.height()
+pub fn render_region (
+    scene: Box<Renderable + Sync>,
+    camera: Arc<Camera>,
+    nx: i32,
+    ny: i32,
+    spp: i32,
+    nthreads: i32,
+    debug: bool,
+    region: Region,
+) -> Vec<i32>  {
+    let settings = Settings {
+        nthreads: nthreads,
+        debug: debug,
+        region: region,
+        .. Settings::new(nx, ny, spp)
+    };
+    render_film(scene, camera, &settings).to_rgb()
+}
+
+// Renders settings.region of the scene to a film of linear colors and the requested AOVs
+pub fn render_film(scene: Box<Renderable + Sync>, camera: Arc<Camera>, settings: &Settings) -> Film {
+    let (nx, ny, spp, nthreads, region) = (settings.nx, settings.ny, settings.spp, settings.nthreads, settings.region);
+    assert!(region.x0 >= 0 && region.y0 >= 0 && region.x1 <= nx && region.y1 <= ny, "Region outside of image");
+    assert!(region.width() > 0 && region.height() > 0, "Region is empty");
+
     let mut threads = vec![];
 
     let shareable_scene: Arc<Box<Renderable>> = Arc::new(scene);
 
-    // Each thread takes 1/nthreads of the image split into horizontal slices
+    // Rows are sampled from the bottom of the image, convert region to those rows
+    let (row_start, row_end) = (ny - region.y1, ny - region.y0);
+    let rows = row_end - row_start;
+
+    // Each thread takes 1/nthreads of the region split into horizontal slices
     // rev because bottom of image first
     for nth in (0..nthreads).rev() {
         {
             let camera = camera.clone();
             let s = shareable_scene.clone();
+            let settings = settings.clone();
             let mut rng = XorShiftRng::from_seed([random::<u32>(); 4]);
             threads.push(thread::spawn(move || {
                 // Get horizontal slice, final thread takes any extra due to int truncation
-                let start = (ny/nthreads) * nth;
-                let end = if nth == nthreads - 1 { ny } else { (ny/nthreads) * (nth+1) };
+                let start = row_start + (rows/nthreads) * nth;
+                let end = if nth == nthreads - 1 { row_end } else { row_start + (rows/nthreads) * (nth+1) };
                 let slice = end - start;
 
-                let capacity = slice * nx * 3;
-                let mut outbuf: Vec<i32> = Vec::with_capacity(capacity as usize);
+                let mut film = Film::new(region.width(), slice, &settings.aovs);
+
+                let mut samples: Vec<(Vec3, SampleAovs)> = Vec::with_capacity(spp as usize);
 
                 for j in (start..end).rev() {
-                    for i in 0..nx {
+                    for i in region.x0..region.x1 {
+                        samples.clear();
+                        for _s in 0..spp {
+                            let mut aovs = SampleAovs::background();
+                            let c = sample(i,j,nx,ny,&(**s),&(*camera), &mut rng, &mut aovs, &settings);
+                            samples.push((c, aovs));
+                        }
+
+                        let keep = match settings.outlier_rejection {
+                            Some(k) => reject_outliers(&samples, k),
+                            None => vec![true; samples.len()],
+                        };
+
                         let mut c = Vec3::new(0.0, 0.0, 0.0);
+                        let mut pixel_aovs = PixelAovs::new();
+                        for (&(color, ref aovs), _) in samples.iter().zip(keep.iter()).filter(|x| *x.1) {
+                            c += color;
+                            pixel_aovs.add(aovs);
+                        }
+                        film.beauty.push(c / (::std::cmp::max(pixel_aovs.count, 1) as f64));
 
-                        for _s in 0..spp {
-                            c += sample(i,j,nx,ny,&(**s),&(*camera), &mut rng);
+                        for &mut (aov, ref mut data) in film.aovs.iter_mut() {
+                            data.push(pixel_aovs.get(aov));
                         }
-                        let c = c / (spp as f64);
-
-                        // Output for PPM
-                        // Gamma correct to 2: output color ^ (1/gamma) = x^(1/2) = sqrt
-                        let c = c.map(&|x: f64| x.sqrt());
-                        let c = c * 255.99;
-                        let c = c.map(&|x: f64| if x > 255.99 { 255.99 } else { x });
-
-                        // Save to buffer for image out
-                        outbuf.push(c.x as i32);
-                        outbuf.push(c.y as i32);
-                        outbuf.push(c.z as i32);
                     }
 
                     // Write percentage progress
-                    if debug && j % ::std::cmp::max(slice / 10, 1) == 0 {
+                    if settings.debug && (j - start) % ::std::cmp::max(slice / 10, 1) == 0 {
                         writeln!(&mut ::std::io::stderr(), "{}: {}/{}", nth, slice-(j-start), slice).unwrap();
                     }
                 }
 
-                (outbuf, nth)
+                (film, nth)
             }));
         }
     }
 
-    // Join and create final buffer from results
-    let mut outbuf: Vec<i32> = Vec::with_capacity((nx * ny * 3) as usize);
+    // Join and create final film from results
+    let mut film = Film::new(region.width(), 0, &settings.aovs);
 
     for t in threads {
         let (mut res, nth) = t.join().unwrap(); // If thread fails, unrecoverable
-        if debug {
-            writeln!(&mut ::std::io::stderr(), "Thread {} done", nth);
+        if settings.debug {
+            writeln!(&mut ::std::io::stderr(), "Thread {} done", nth).unwrap();
+        }
+        film.append(&mut res);
+    }
+
+    film
+}
+
+// Paste a rendered region into a full nx by ny RGB buffer
+pub fn composite(full: &mut Vec<i32>, nx: i32, region: &Region, region_buf: &Vec<i32>) {
+    for y in 0..region.height() {
+        for x in 0..region.width() {
+            let src = ((y * region.width() + x) * 3) as usize;
+            let dst = (((y + region.y0) * nx + x + region.x0) * 3) as usize;
+            full[dst..dst+3].copy_from_slice(&region_buf[src..src+3]);
+        }
+    }
+}
+
+// Full nx by ny RGBA buffer with the region opaque and everything else transparent black
+pub fn composite_rgba(nx: i32, ny: i32, region: &Region, region_buf: &Vec<i32>) -> Vec<i32> {
+    let mut rgb = vec![0; (nx * ny * 3) as usize];
+    composite(&mut rgb, nx, region, region_buf);
+
+    let mut rgba = Vec::with_capacity((nx * ny * 4) as usize);
+    for y in 0..ny {
+        for x in 0..nx {
+            let i = ((y * nx + x) * 3) as usize;
+            rgba.extend_from_slice(&rgb[i..i+3]);
+            rgba.push(if region.contains(x, y) { 255 } else { 0 });
+        }
+    }
+    rgba
+}
+
+// Output variables of a single camera sample, recorded from the first hit
+#[derive(Debug, Clone)]
+pub struct SampleAovs {
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    pub depth: f64,
+    pub position: Vec3,
+    pub ids: ModelIds,
+    pub emission: Vec3,
+    pub direct: Vec3,
+    pub indirect: Vec3,
+}
+
+impl SampleAovs {
+    // Values for a camera ray that hits nothing
+    pub fn background() -> SampleAovs {
+        let zero = Vec3::new(0.0,0.0,0.0);
+        SampleAovs {
+            normal: zero,
+            albedo: zero,
+            depth: f64::INFINITY,
+            position: zero,
+            ids: ModelIds { object: 0, material: 0 },
+            emission: zero,
+            direct: zero,
+            indirect: zero,
+        }
+    }
+}
+
+// Accumulates SampleAovs over a pixel
+// Most variables are averaged, depth keeps the nearest sample and ids keep the first sample
+struct PixelAovs {
+    sum: SampleAovs,
+    first_ids: Option<ModelIds>,
+    count: i32,
+}
+
+impl PixelAovs {
+    fn new() -> PixelAovs {
+        PixelAovs {
+            sum: SampleAovs { depth: f64::INFINITY, .. SampleAovs::background() },
+            first_ids: None,
+            count: 0,
         }
-        outbuf.append(&mut res);
     }
 
-    outbuf
+    fn add(&mut self, s: &SampleAovs) {
+        self.sum.normal += s.normal;
+        self.sum.albedo += s.albedo;
+        self.sum.depth = self.sum.depth.min(s.depth);
+        self.sum.position += s.position;
+        self.sum.emission += s.emission;
+        self.sum.direct += s.direct;
+        self.sum.indirect += s.indirect;
+        if self.first_ids.is_none() {
+            self.first_ids = Some(s.ids);
+        }
+        self.count += 1;
+    }
+
+    fn get(&self, aov: Aov) -> Vec3 {
+        let n = ::std::cmp::max(self.count, 1) as f64;
+        let ids = self.first_ids.unwrap_or(self.sum.ids);
+        let splat = |x: f64| Vec3::new(x, x, x);
+        match aov {
+            Aov::Normal => {
+                let normal = self.sum.normal / n;
+                if normal.length() > 0.0 { normal.normalized() } else { normal }
+            }
+            Aov::Albedo => self.sum.albedo / n,
+            Aov::Depth => splat(self.sum.depth),
+            Aov::Position => self.sum.position / n,
+            Aov::ObjectId => splat(ids.object as f64),
+            Aov::MaterialId => splat(ids.material as f64),
+            Aov::Emission => self.sum.emission / n,
+            Aov::Direct => self.sum.direct / n,
+            Aov::Indirect => self.sum.indirect / n,
+            Aov::SampleCount => splat(self.count as f64),
+        }
+    }
+}
+
+// Which samples to keep, by luminance
+// A sample is an outlier if it is more than k standard deviations above the mean of the other
+// samples, so a pixel where many samples see a small bright light keeps them
+fn reject_outliers(samples: &Vec<(Vec3, SampleAovs)>, k: f64) -> Vec<bool> {
+    let n = samples.len() as f64;
+    if n < 3.0 {
+        return vec![true; samples.len()];
+    }
+
+    let lum: Vec<f64> = samples.iter().map(|s| s.0.luminance()).collect();
+    let sum: f64 = lum.iter().sum();
+    let sum_sq: f64 = lum.iter().map(|x| x * x).sum();
+
+    lum.iter().map(|x| {
+        // Mean and variance excluding this sample
+        let mean = (sum - x) / (n - 1.0);
+        let variance = ((sum_sq - x * x) / (n - 1.0) - mean * mean).max(0.0);
+        *x <= mean + k * variance.sqrt()
+    }).collect()
 }
 
-fn sample(x: i32, y: i32, nx: i32, ny: i32, scene: &Renderable, camera: &Camera, rng: &mut XorShiftRng) -> Vec3 {
+// Scale color down so its luminance is at most max
+fn clamp_luminance(c: Vec3, max: f64) -> Vec3 {
+    let l = c.luminance();
+    if l > max { c * (max / l) } else { c }
+}
+
+fn sample(x: i32, y: i32, nx: i32, ny: i32, scene: &Renderable, camera: &Camera, rng: &mut XorShiftRng, aovs: &mut SampleAovs, settings: &Settings) -> Vec3 {
     // Get percent offset from bottom left corner
     let u = (x as f64 + rng.gen::<f64>()) / (nx as f64);
     let v = (y as f64 + rng.gen::<f64>()) / (ny as f64);
 
     // Make ray
-    let r = camera.get_ray(u, v, rng);
+    let mut r = camera.get_ray(u, v, rng);
 
     // Get color
-    color(&r, scene, 0, rng)
+    if settings.spectral {
+        let wavelengths = Wavelengths::sample(rng);
+        r.wavelength = wavelengths.hero;
+        let c = color(&r, scene, PathState::camera(Some(wavelengths), &MediumStack::new()), rng, aovs, settings);
+
+        // Light carrying output variables are spectral too
+        aovs.emission = wavelengths.to_rgb(aovs.emission);
+        aovs.direct = wavelengths.to_rgb(aovs.direct);
+        aovs.indirect = wavelengths.to_rgb(aovs.indirect);
+        wavelengths.to_rgb(c)
+    } else {
+        color(&r, scene, PathState::camera(None, &MediumStack::new()), rng, aovs, settings)
+    }
 }
 
-fn color(r: &Ray, world: &Renderable, depth: i32, rng: &mut XorShiftRng) -> Vec3 {
-    match world.hit(r, MIN_DISTANCE, MAX_DISTANCE) {
-        Some((h, material)) => {
-            let emitted = material.emitted(h.u, h.v, &h.p).unwrap_or(Vec3::new(0.0,0.0,0.0));
+// Where a path is along the way, see color
+#[derive(Clone, Copy)]
+struct PathState<'a> {
+    depth: i32,
+    wavelengths: Option<Wavelengths>,
+    last: Option<Scatter>,
+    media: &'a MediumStack,
+}
+
+// Where a path last scattered off a lobe that isn't perfectly specular, and the density of the
+// direction it took (see Material::pdf)
+#[derive(Clone, Copy)]
+struct Scatter {
+    p: Vec3,
+    normal: Vec3,
+    pdf: f64,
+}
+
+impl<'a> PathState<'a> {
+    fn camera(wavelengths: Option<Wavelengths>, media: &'a MediumStack) -> PathState<'a> {
+        PathState {
+            depth: 0,
+            wavelengths: wavelengths,
+            last: None,
+            media: media,
+        }
+    }
+
+    // RGB from materials to what the path carries
+    fn to_path(&self, c: Vec3) -> Vec3 {
+        match self.wavelengths {
+            Some(w) => w.from_rgb(c),
+            None => c,
+        }
+    }
+}
+
+// aovs is filled in from the first hit (depth 0). Emission seen by the second hit (depth 1) is
+// stashed in aovs.direct, then weighted by the first hit's attenuation on the way back up. Delta
+// lights sampled at the first hit are direct light too
+//
+// With path.wavelengths the returned color and light carrying aovs are radiance at each wavelength
+//
+// path.last is None for camera rays and rays scattered by a specular lobe, which count all the light
+// they find. Other rays may find the background or an emissive model standing in for a light that
+// was also sampled at the hit they left from, both are weighted by multiple importance sampling
+// (see scatter_weight)
+//
+// path.media holds the objects the path is inside of (see medium). In a medium the path may scatter
+// before reaching the hit, which is then shaded like a hit on a material of its phase function, and
+// adds what the medium gives off along the way
+fn color(r: &Ray, world: &Renderable, path: PathState, rng: &mut XorShiftRng, aovs: &mut SampleAovs, settings: &Settings) -> Vec3 {
+    let hit = world.hit(r, MIN_DISTANCE, MAX_DISTANCE, rng);
+    let medium = match current_medium(world, path.media) {
+        Some(m) => m,
+        None => return shade(r, hit, world, path, rng, aovs, settings),
+    };
+
+    let t_max = hit.as_ref().map_or(MAX_DISTANCE, |&(ref h, _, _)| h.t);
+    let s = medium.sample(r, t_max, rng);
+    let weight = path.to_path(s.weight);
+    let emitted = path.to_path(medium.emitted(r, t_max, rng));
+    match s.t {
+        Some(t) => {
+            let h = HitRecord {
+                t: t,
+                p: r.point_at_parameter(t),
+                normal: Vec3::new(0.0,0.0,0.0),
+                u: 0.0,
+                v: 0.0,
+                dpdu: Vec3::new(0.0,0.0,0.0),
+                dpdv: Vec3::new(0.0,0.0,0.0),
+            };
+            let phase = Material::isotropic_constant(Vec3::new(1.0,1.0,1.0)).with_phase(medium.phase());
+            let ids = ModelIds { object: 0, material: 0 };
+            emitted + weight * shade(r, Some((h, &phase, ids)), world, path, rng, aovs, settings)
+        }
+        None => emitted + weight * shade(r, hit, world, path, rng, aovs, settings),
+    }
+}
+
+// Medium around a path inside of these objects
+fn current_medium<'a>(world: &'a Renderable, media: &MediumStack) -> Option<&'a Medium> {
+    match media.top() {
+        Some(object) => world.interior(object).and_then(|i| i.medium.as_ref().map(|m| &**m)),
+        None => world.atmosphere(),
+    }
+}
+
+// Light leaving the hit towards the origin of r, or the background without one
+fn shade(r: &Ray, hit: Option<(HitRecord, &Material, ModelIds)>, world: &Renderable, path: PathState, rng: &mut XorShiftRng, aovs: &mut SampleAovs, settings: &Settings) -> Vec3 {
+    let depth = path.depth;
+    match hit {
+        Some((h, material, ids)) => {
+            // Crossing into or out of an object with an interior
+            let entering = r.dir.dot(h.normal) < 0.0;
+            let crossed = match world.interior(ids.object) {
+                Some(interior) if h.normal.squared_length() > 0.0 => {
+                    let crossed = path.media.crossed(ids.object, interior.priority, entering);
+                    // Inside something with a higher priority the surface isn't there
+                    if path.media.highest_priority_besides(ids.object).map_or(false, |p| p > interior.priority) {
+                        let through = Ray { origin: h.p, .. *r };
+                        return color(&through, world, PathState { media: &crossed, .. path }, rng, aovs, settings);
+                    }
+                    Some(crossed)
+                }
+                _ => None,
+            };
+
+            let sampled = path.last.is_some() && world.light_sampler().map_or(false, |s| s.is_light_object(ids.object));
+            let weight = if sampled {
+                scatter_weight(world, &path, r.dir, &|light| light.object_id() == Some(ids.object))
+            } else {
+                1.0
+            };
+            let emitted = match material.emitted(r, &h) {
+                Some(e) if weight > 0.0 => path.to_path(e) * weight,
+                _ => Vec3::new(0.0,0.0,0.0),
+            };
+            if depth == 0 {
+                aovs.normal = if h.normal.squared_length() > 0.0 { h.normal.normalized() } else { h.normal };
+                aovs.albedo = material.albedo(&h);
+                aovs.depth = h.t * r.dir.length();
+                aovs.position = h.p;
+                aovs.ids = ids;
+                aovs.emission = emitted;
+            } else if depth == 1 {
+                aovs.direct = emitted;
+            }
+
+            let media = (path.media, crossed.as_ref().unwrap_or(path.media));
+            let lights = path.to_path(sample_lights(r, &h, material, world, rng, media));
+
             if depth < DEPTH_MAX {
                 match material.scatter(r, &h, rng) {
-                    Some((attentuation, scattered)) => {
-                        emitted + attentuation * color(&scattered, world, depth+1, rng)
+                    Some((attentuation, mut scattered)) => {
+                        scattered.wavelength = r.wavelength;
+                        let mut attentuation = path.to_path(attentuation);
+
+                        // Only the hero wavelength followed the dispersed direction
+                        let mut wavelengths = path.wavelengths;
+                        if let Some(ref mut w) = wavelengths {
+                            if material.is_dispersive() {
+                                attentuation = attentuation * w.terminate_secondary();
+                            }
+                        }
+
+                        let last = if material.is_specular(r, &h, &scattered) {
+                            None
+                        } else {
+                            Some(Scatter { p: h.p, normal: h.normal, pdf: material.pdf(r, &h, scattered.dir.normalized()) })
+                        };
+                        let next = PathState {
+                            depth: depth + 1,
+                            wavelengths: wavelengths,
+                            last: last,
+                            media: if scattered.dir.dot(h.normal) * r.dir.dot(h.normal) > 0.0 { media.1 } else { media.0 },
+                        };
+                        let incoming = color(&scattered, world, next, rng, aovs, settings);
+                        if depth == 0 {
+                            aovs.direct = attentuation * aovs.direct;
+                            aovs.indirect = attentuation * incoming - aovs.direct;
+                            aovs.direct = aovs.direct + lights;
+                            if let Some(max) = settings.clamp_indirect {
+                                aovs.indirect = clamp_luminance(aovs.indirect, max);
+                                return emitted + aovs.direct + aovs.indirect;
+                            }
+                        }
+                        emitted + lights + attentuation * incoming
                     }
                     // No scatter ray produced
-                    None => emitted
+                    None => {
+                        if depth == 0 {
+                            aovs.direct = lights;
+                        }
+                        emitted + lights
+                    }
                 }
             } else {
-                // Depth exceeded default color
-                COLOR_DEFAULT
+                // Depth exceeded, only what the hit gives off, like the background would be
+                emitted
             }
         }
         None => {
-            // Background
+            // Background, e.g. a scene::Scene's sky
+            let weight = scatter_weight(world, &path, r.dir, &|light| light.bounds().is_none());
+            if weight <= 0.0 {
+                return COLOR_DEFAULT;
+            }
+            let background = path.to_path(world.background(r)) * weight;
+            if depth == 0 {
+                aovs.emission = background;
+            } else if depth == 1 {
+                aovs.direct = background;
+            }
+            background
+        }
+    }
+}
 
-            // Sky
-            // let unit_dir = r.dir.normalized();
-            // let t = 0.5 * (unit_dir.y + 1.0);
-            // COLOR_WHITE * (1.0-t) + COLOR_BLUE * (t)
+// Light from the world's delta lights reflected towards the origin of r, one shadow ray each
+// media is what the path is inside of on r's side of the hit's surface and past it, for light
+// arriving through it
+fn sample_lights(r: &Ray, h: &HitRecord, material: &Material, world: &Renderable, rng: &mut XorShiftRng, media: (&MediumStack, &MediumStack)) -> Vec3 {
+    let lights = world.lights();
+    if let Some(sampler) = world.light_sampler() {
+        if sampler.strategy != LightSampling::All {
+            return match sampler.pick(&h.p, &h.normal, rng) {
+                Some((i, probability)) => sample_light((&*lights[i], probability), r, h, material, world, rng, media) / probability,
+                None => Vec3::new(0.0,0.0,0.0),
+            };
+        }
+    }
+
+    let mut sum = Vec3::new(0.0,0.0,0.0);
+    for light in lights {
+        sum += sample_light((&**light, 1.0), r, h, material, world, rng, media);
+    }
+    sum
+}
 
-            // Black
-            COLOR_DEFAULT
+// Shadow rays are dimmed by the medium they start in and volumes on the way (see
+// Renderable::transmittance), any surface blocks them. The light comes with how likely it was
+// picked, light that scattering could have found too is weighted against it (see scatter_weight)
+fn sample_light((light, probability): (&Light, f64), r: &Ray, h: &HitRecord, material: &Material, world: &Renderable, rng: &mut XorShiftRng, media: (&MediumStack, &MediumStack)) -> Vec3 {
+    if let Some(s) = light.sample(&h.p, rng) {
+        let f = material.eval(r, h, s.dir);
+        if f.x <= 0.0 && f.y <= 0.0 && f.z <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        let light_pdf = light.pdf(&h.p, s.dir) * probability;
+        let f = if light_pdf > 0.0 { f * power_heuristic(light_pdf, material.pdf(r, h, s.dir)) } else { f };
+        let shadow = Ray { origin: h.p, dir: s.dir, .. *r };
+        let t_max = s.distance.min(MAX_DISTANCE) * (1.0 - MIN_DISTANCE);
+        let occlusion = world.transmittance(&shadow, MIN_DISTANCE, t_max, rng);
+        if occlusion != Vec3::new(0.0,0.0,0.0) {
+            let media = if s.dir.dot(h.normal) * r.dir.dot(h.normal) > 0.0 { media.1 } else { media.0 };
+            let transmittance = match current_medium(world, media) {
+                Some(m) => m.transmittance(&shadow, t_max, rng),
+                None => Vec3::new(1.0,1.0,1.0),
+            };
+            return f * s.radiance * transmittance * occlusion;
         }
     }
+    Vec3::new(0.0,0.0,0.0)
+}
+
+// Multiple importance sampling weight of light found along dir by scattering from path.last,
+// against sampling the lights is_source picks out at path.last
+fn scatter_weight(world: &Renderable, path: &PathState, dir: Vec3, is_source: &Fn(&Light) -> bool) -> f64 {
+    let last = match path.last {
+        Some(last) => last,
+        None => return 1.0,
+    };
+    let dir = dir.normalized();
+    let mut light_pdf = 0.0;
+    for (i, light) in world.lights().iter().enumerate() {
+        if is_source(&**light) {
+            let pdf = light.pdf(&last.p, dir);
+            if pdf > 0.0 {
+                light_pdf += pdf * world.light_sampler().map_or(1.0, |s| s.probability(&last.p, &last.normal, i));
+            }
+        }
+    }
+    power_heuristic(last.pdf, light_pdf)
+}
+
+// Weight of a sample taken with density a, that a strategy with density b could have taken too
+fn power_heuristic(a: f64, b: f64) -> f64 {
+    if b <= 0.0 {
+        return 1.0;
+    }
+    a * a / (a * a + b * b)
 }
 
 pub fn random_scene() -> Box<Renderable + Sync> {
+    let (items, _) = random_spheres(0.0);
+    Box::new(bvh::Node::new(items))
+}
+
+// random_scene at night, lit only by the small spheres that glow
+pub fn random_lights_scene(strategy: LightSampling) -> Box<Renderable + Sync> {
+    let (items, lights) = random_spheres(0.3);
+    Box::new(Scene::new(bvh::Node::new(items)).with_lights(lights).with_light_sampling(strategy))
+}
+
+// A fraction emissive of the small spheres glow, each also returned as a light
+fn random_spheres(emissive: f64) -> (Vec<Box<bvh::BVHItem>>, Vec<Box<Light>>) {
     let mut items: Vec<Box<bvh::BVHItem>> = Vec::new();
+    let mut lights: Vec<Box<Light>> = Vec::new();
 
     // Ground
     items.push(Box::new(Model::new(
@@ -198,7 +732,18 @@ pub fn random_scene() -> Box<Renderable + Sync> {
                 b as f64 + 0.9 * random::<f64>(),
             );
             if (center - Vec3::new(4.0,0.2,0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
+                if choose_mat < emissive {
+                    // light
+                    let sphere = Sphere {
+                        center: center,
+                        radius: 0.2,
+                    };
+                    let radiance = (random::<Vec3>() + 1.0) * 2.0;
+                    let light = light::sphere(&sphere, radiance);
+                    let model = Model::new(sphere, Material::diffuse_light_constant(radiance));
+                    lights.push(Box::new(light.with_object_id(model.ids.object)));
+                    items.push(Box::new(model));
+                } else if choose_mat < 0.8 {
                     // diffuse
                     items.push(Box::new(Model::new(
                         Sphere {
@@ -235,5 +780,283 @@ pub fn random_scene() -> Box<Renderable + Sync> {
         }
     }
 
-    Box::new(bvh::Node::new(items))
+    (items, lights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_from_window() {
+        let r = Region::from_window(200, 100, 0.25, 0.5, 0.501, 2.0);
+        assert!(r == Region { x0: 50, y0: 50, x1: 101, y1: 100 });
+    }
+
+    #[test]
+    fn test_composite_rgba() {
+        let region = Region { x0: 1, y0: 0, x1: 2, y1: 1 };
+        let res = composite_rgba(2, 2, &region, &vec![10, 20, 30]);
+        assert!(res == vec![0,0,0,0, 10,20,30,255, 0,0,0,0, 0,0,0,0]);
+    }
+
+    fn samples(lum: &[f64]) -> Vec<(Vec3, SampleAovs)> {
+        lum.iter().map(|&x| (Vec3::new(x, x, x), SampleAovs::background())).collect()
+    }
+
+    #[test]
+    fn test_reject_outliers_firefly() {
+        let mut lum = vec![0.5; 100];
+        lum[7] = 1000.0;
+        let keep = reject_outliers(&samples(&lum), 5.0);
+        assert!(!keep[7]);
+        assert!(keep.iter().filter(|x| **x).count() == 99);
+    }
+
+    #[test]
+    fn test_reject_outliers_keeps_common_highlights() {
+        // A third of the samples see a bright light, that is signal not noise
+        let lum: Vec<f64> = (0..99).map(|i| if i % 3 == 0 { 50.0 } else { 0.1 }).collect();
+        let keep = reject_outliers(&samples(&lum), 5.0);
+        assert!(keep.iter().all(|x| *x));
+    }
+
+    #[test]
+    fn test_clamp_luminance() {
+        let c = clamp_luminance(Vec3::new(20.0, 10.0, 0.0), 1.0);
+        assert!(::util::approx_float_eq(c.luminance(), 1.0));
+        assert!(::util::approx_float_eq(c.x, 2.0 * c.y));
+    }
+
+    #[test]
+    fn test_point_light_on_lambertian() {
+        // Radiance albedo / pi * I / d^2 straight below the light, none when something is between
+        use light;
+        use model::rect::Rect;
+        use scene::Scene;
+
+        let floor: Box<Renderable> = Box::new(Model::new(Rect::xz_rect(-10.0,10.0,-10.0,10.0,0.0), Material::lambertian_constant(Vec3::new(0.5,0.5,0.5))));
+        let scene = Scene::new(vec![floor]).with_light(light::point(Vec3::new(0.0,2.0,0.0), Vec3::new(4.0,4.0,4.0)));
+        let r = Ray::new(Vec3::new(0.0,1.0,-1.0), Vec3::new(0.0,-1.0,1.0));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let settings = Settings::new(1, 1, 1);
+        let c = color(&r, &scene, PathState { depth: DEPTH_MAX - 1, .. PathState::camera(None, &MediumStack::new()) }, &mut rng, &mut SampleAovs::background(), &settings);
+        assert!((c.x - 0.5 / f64::consts::PI).abs() < 1e-9);
+
+        let blocker: Box<Renderable> = Box::new(Model::new(Rect::xz_rect(-1.0,1.0,-1.0,1.0,1.5), Material::lambertian_constant(Vec3::new(0.5,0.5,0.5))));
+        let floor: Box<Renderable> = Box::new(Model::new(Rect::xz_rect(-10.0,10.0,-10.0,10.0,0.0), Material::lambertian_constant(Vec3::new(0.5,0.5,0.5))));
+        let scene = Scene::new(vec![floor, blocker]).with_light(light::point(Vec3::new(0.0,2.0,0.0), Vec3::new(4.0,4.0,4.0)));
+        let r = Ray::new(Vec3::new(2.0,1.0,0.0), Vec3::new(-2.0,-1.0,0.0));
+        let c = color(&r, &scene, PathState { depth: DEPTH_MAX - 1, .. PathState::camera(None, &MediumStack::new()) }, &mut rng, &mut SampleAovs::background(), &settings);
+        assert!(c == Vec3::new(0.0,0.0,0.0));
+    }
+
+    // Radiance reaching a camera on the z axis from a white light behind spheres of index matched
+    // glass at the origin, with radius and interior (priority, absorbed color over distance 1)
+    fn through_media(spheres: &[(f64, i32, Option<f64>)], fog: Option<f64>) -> f64 {
+        use medium;
+        use model::rect::Rect;
+        use scene::Scene;
+
+        let mut world: Vec<Box<Renderable>> = vec![
+            Box::new(Model::new(Rect::xy_rect(-10.0,10.0,-10.0,10.0,5.0), Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)))),
+        ];
+        let mut interiors = vec![];
+        for &(radius, priority, color) in spheres {
+            let model = Model::new(Sphere { center: Vec3::new(0.0,0.0,0.0), radius: radius }, Material::dielectric(1.0));
+            interiors.push((model.ids.object, priority, color));
+            world.push(Box::new(model));
+        }
+        let mut scene = Scene::new(world);
+        for (object, priority, color) in interiors {
+            scene = match color {
+                Some(c) => scene.with_medium_inside(object, medium::absorbing(Vec3::new(c, c, c), 1.0), priority),
+                None => scene.with_priority(object, priority),
+            };
+        }
+        if let Some(sigma) = fog {
+            scene = scene.with_atmosphere(medium::homogeneous(Vec3::new(sigma, sigma, sigma), Vec3::new(0.0,0.0,0.0)));
+        }
+
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let settings = Settings::new(1, 1, 1);
+        color(&r, &scene, PathState::camera(None, &MediumStack::new()), &mut rng, &mut SampleAovs::background(), &settings).x
+    }
+
+    #[test]
+    fn test_medium_inside_glass() {
+        // Two units through a medium keeping half per unit
+        assert!((through_media(&[(1.0, 1, Some(0.5))], None) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_medium_of_dielectric() {
+        use model::rect::Rect;
+        use scene::Scene;
+
+        // Tinted glass registers its interior with the scene, no object ids needed
+        let world: Vec<Box<Renderable>> = vec![
+            Box::new(Model::new(Rect::xy_rect(-10.0,10.0,-10.0,10.0,5.0), Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)))),
+            Box::new(Model::new(Sphere { center: Vec3::new(0.0,0.0,0.0), radius: 1.0 }, Material::dielectric(1.0).with_transmission_color(Vec3::new(0.5,0.5,0.5), 1.0))),
+        ];
+        let scene = Scene::new(world);
+
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let settings = Settings::new(1, 1, 1);
+        let c = color(&r, &scene, PathState::camera(None, &MediumStack::new()), &mut rng, &mut SampleAovs::background(), &settings);
+        assert!((c.x - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_nested_media_priorities() {
+        // Higher priority inside: one unit of each
+        assert!((through_media(&[(1.0, 1, Some(0.5)), (0.5, 2, Some(0.8))], None) - 0.5 * 0.8).abs() < 1e-9);
+        // Lower priority inside: its surfaces aren't there, two units of the outer
+        assert!((through_media(&[(1.0, 1, Some(0.5)), (0.5, 0, Some(0.8))], None) - 0.25).abs() < 1e-9);
+        // A glass with air inside holding a liquid
+        assert!((through_media(&[(1.0, 1, None), (0.5, 2, Some(0.8))], None) - 0.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_atmosphere() {
+        // Ten units of fog to the light, except inside the glass
+        let fog = 0.1;
+        assert!((through_media(&[], Some(fog)) - (-fog * 10.0).exp()).abs() < 1e-9);
+        assert!((through_media(&[(1.0, 1, None)], Some(fog)) - (-fog * 8.0).exp()).abs() < 1e-9);
+    }
+
+    // Radiance looking up through a glass slab, smooth without a roughness, under a sky with the
+    // sun set or a sphere light filling much of the view
+    fn through_slab(roughness: Option<f64>, sky: bool) -> f64 {
+        use light;
+        use model::cube::Cube;
+        use scene::Scene;
+        use sky::Sky;
+
+        let slab = Cube::new_from_min_max(Vec3::new(-50.0,0.0,-50.0), Vec3::new(50.0,0.2,50.0));
+        let glass: Box<Renderable> = match roughness {
+            Some(roughness) => Box::new(Model::new(slab, Material::rough_dielectric(1.5, roughness))),
+            None => Box::new(Model::new(slab, Material::dielectric(1.5))),
+        };
+        let scene = if sky {
+            Scene::new(vec![glass]).with_sky(Sky::new(Vec3::new(1.0,-0.1,0.0), 3.0, Vec3::new(0.0,0.0,0.0)))
+        } else {
+            let sphere = Sphere { center: Vec3::new(0.0,30.0,0.0), radius: 20.0 };
+            let light = light::sphere(&sphere, Vec3::new(1.0,1.0,1.0));
+            let model = Model::new(sphere, Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)));
+            let light = light.with_object_id(model.ids.object);
+            Scene::new(vec![glass, Box::new(model)]).with_light(light)
+        };
+
+        let r = Ray::new(Vec3::new(0.0,-1.0,0.0), Vec3::new(0.0,1.0,0.0));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let settings = Settings::new(1, 1, 1);
+        let n = 4000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += color(&r, &scene, PathState::camera(None, &MediumStack::new()), &mut rng, &mut SampleAovs::background(), &settings).y;
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn test_light_through_rough_glass() {
+        // Scattering through the glass finds the light as well as sampling it from the far side does
+        for &sky in &[true, false] {
+            let smooth = through_slab(None, sky);
+            for &roughness in &[0.05, 0.3] {
+                let rough = through_slab(Some(roughness), sky);
+                assert!(rough > 0.0);
+                assert!((rough - smooth).abs() < 0.1 * smooth, "rough {} smooth {}", rough, smooth);
+            }
+        }
+    }
+
+    // Mean radiance from a floor of material under, or inside, a sphere light of radiance 1
+    fn under_sphere_light<M: Material + 'static>(floor: M, inside: bool) -> f64 {
+        use light;
+        use model::rect::Rect;
+        use scene::Scene;
+
+        let sphere = if inside {
+            Sphere { center: Vec3::new(0.0,0.0,0.0), radius: 5.0 }
+        } else {
+            Sphere { center: Vec3::new(0.0,30.0,0.0), radius: 20.0 }
+        };
+        let light = light::sphere(&sphere, Vec3::new(1.0,1.0,1.0));
+        let model = Model::new(sphere, Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)));
+        let light = light.with_object_id(model.ids.object);
+        let floor: Box<Renderable> = Box::new(Model::new(Rect::xz_rect(-1.0,1.0,-1.0,1.0,0.0), floor));
+        let scene = Scene::new(vec![floor, Box::new(model)]).with_light(light);
+
+        let r = Ray::new(Vec3::new(0.0,1.0,-0.2), Vec3::new(0.0,-1.0,0.2));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let settings = Settings::new(1, 1, 1);
+        let n = 4000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += color(&r, &scene, PathState { depth: DEPTH_MAX - 2, .. PathState::camera(None, &MediumStack::new()) }, &mut rng, &mut SampleAovs::background(), &settings).y;
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn test_inside_sphere_light() {
+        // Lit from every direction, the light can't be sampled from inside but is found by scattering
+        let lit = under_sphere_light(Material::lambertian_constant(Vec3::new(0.5,0.5,0.5)), true);
+        assert!((lit - 0.5).abs() < 0.02, "lit {}", lit);
+    }
+
+    #[test]
+    fn test_near_mirror_reflects_sphere_light() {
+        // The light fills the mirror direction, which sampling the light hardly ever hits
+        let mirror = Material::principled_constant(Vec3::new(1.0,1.0,1.0)).with_metallic(1.0).with_roughness(0.0);
+        let lit = under_sphere_light(mirror, false);
+        assert!((lit - 1.0).abs() < 0.05, "lit {}", lit);
+    }
+
+    #[test]
+    fn test_light_sampling_strategies_agree() {
+        // Sphere lights over a lambertian floor give albedo * L * (r/d)^2 * cos each, whichever
+        // way they are picked
+        use light;
+        use light_sampler::LightSampling;
+        use model::rect::Rect;
+        use scene::Scene;
+
+        let spheres = [(Vec3::new(0.0,2.0,0.0), 2.0), (Vec3::new(3.0,1.0,0.0), 1.0), (Vec3::new(-1.0,3.0,2.0), 4.0)];
+        let mut expected = 0.0;
+        for &(center, radiance) in &spheres {
+            let d = center.length();
+            expected += 0.5 * radiance * 0.25 / (d * d) * (center.y / d);
+        }
+
+        for &strategy in &[LightSampling::All, LightSampling::Uniform, LightSampling::Power, LightSampling::Tree] {
+            let mut world: Vec<Box<Renderable>> = vec![
+                Box::new(Model::new(Rect::xz_rect(-10.0,10.0,-10.0,10.0,0.0), Material::lambertian_constant(Vec3::new(0.5,0.5,0.5)))),
+            ];
+            let mut lights: Vec<Box<Light>> = vec![];
+            for &(center, radiance) in &spheres {
+                let sphere = Sphere { center: center, radius: 0.5 };
+                let light = light::sphere(&sphere, Vec3::new(radiance, radiance, radiance));
+                let model = Model::new(sphere, Material::diffuse_light_constant(Vec3::new(radiance, radiance, radiance)));
+                lights.push(Box::new(light.with_object_id(model.ids.object)));
+                world.push(Box::new(model));
+            }
+            let scene = Scene::new(world).with_lights(lights).with_light_sampling(strategy);
+
+            let r = Ray::new(Vec3::new(0.0,1.0,-1.0), Vec3::new(0.0,-1.0,1.0));
+            let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+            let settings = Settings::new(1, 1, 1);
+            let n = 20000;
+            let mut sum = 0.0;
+            for _ in 0..n {
+                sum += color(&r, &scene, PathState { depth: DEPTH_MAX - 1, .. PathState::camera(None, &MediumStack::new()) }, &mut rng, &mut SampleAovs::background(), &settings).x;
+            }
+            let mean = sum / n as f64;
+            assert!((mean - expected).abs() < 0.02 * expected);
+        }
+    }
 }
@@ -0,0 +1,137 @@
+// Scene: the world's renderable objects, the lights that are not objects and the sky
+//
+// Render reads lights and the background from the root of the scene passed to it, so wrap the
+// whole world (a Vec or a BVH) in a Scene rather than nesting one inside.
+//
+// With many lights, pick one per hit instead of sampling them all, see light_sampler.
+//
+// Media fill the scene (with_atmosphere) or the inside of objects named by their ids
+// (with_medium_inside), or come with a dielectric's material (Dielectric::with_transmission_color),
+// see medium.
+
+use light::Light;
+use light_sampler::{LightSampler, LightSampling};
+use medium::{Interior, Medium};
+use model::{Renderable, ModelIds};
+use model::hitable::HitRecord;
+use ray::Ray;
+use shader::material::Material;
+use sky::Sky;
+use vec3::Vec3;
+
+use rand::XorShiftRng;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct Scene<R: Renderable> {
+    pub world: R,
+    pub lights: Vec<Box<Light>>,
+    pub sky: Option<Sky>,
+    sampler: LightSampler,
+    pub atmosphere: Option<Arc<Medium>>,
+    pub interiors: HashMap<u32, Interior>,
+}
+
+impl<R: Renderable> Scene<R> {
+    // Objects whose material has an interior (Material::interior) are filled with it
+    pub fn new(world: R) -> Scene<R> {
+        let interiors = world.interiors().into_iter().collect();
+        Scene {
+            world: world,
+            lights: vec![],
+            sky: None,
+            sampler: LightSampler::new(&[], LightSampling::All),
+            atmosphere: None,
+            interiors: interiors,
+        }
+    }
+
+    pub fn with_light<L: Light + 'static>(mut self, light: L) -> Scene<R> {
+        self.lights.push(Box::new(light));
+        self.update_sampler();
+        self
+    }
+
+    pub fn with_lights(mut self, lights: Vec<Box<Light>>) -> Scene<R> {
+        self.lights.extend(lights);
+        self.update_sampler();
+        self
+    }
+
+    // Sky as the background, and sampled as a light
+    pub fn with_sky(mut self, sky: Sky) -> Scene<R> {
+        self.lights.push(Box::new(sky.clone()));
+        self.sky = Some(sky);
+        self.update_sampler();
+        self
+    }
+
+    // All by default
+    pub fn with_light_sampling(mut self, strategy: LightSampling) -> Scene<R> {
+        self.sampler.strategy = strategy;
+        self.update_sampler();
+        self
+    }
+
+    // Fog and the like around everything, the camera is in it
+    pub fn with_atmosphere<M: Medium + 'static>(mut self, medium: M) -> Scene<R> {
+        self.atmosphere = Some(Arc::new(medium));
+        self
+    }
+
+    // Medium inside the closed model with this object id (its ids.object), e.g. a dielectric.
+    // Higher priorities win where objects overlap
+    pub fn with_medium_inside<M: Medium + 'static>(mut self, object: u32, medium: M, priority: i32) -> Scene<R> {
+        self.interiors.insert(object, Interior { medium: Some(Arc::new(medium)), priority: priority });
+        self
+    }
+
+    // Priority of an object with nothing but air inside, e.g. a glass holding a liquid with a
+    // higher priority
+    pub fn with_priority(mut self, object: u32, priority: i32) -> Scene<R> {
+        self.interiors.insert(object, Interior { medium: None, priority: priority });
+        self
+    }
+
+    fn update_sampler(&mut self) {
+        self.sampler = LightSampler::new(&self.lights, self.sampler.strategy);
+    }
+}
+
+impl<R: Renderable> Renderable for Scene<R> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Option<(HitRecord, &Material, ModelIds)> {
+        self.world.hit(r, t_min, t_max, rng)
+    }
+
+    fn transmittance(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut XorShiftRng) -> Vec3 {
+        self.world.transmittance(r, t_min, t_max, rng)
+    }
+
+    fn lights(&self) -> &[Box<Light>] {
+        &self.lights
+    }
+
+    fn light_sampler(&self) -> Option<&LightSampler> {
+        Some(&self.sampler)
+    }
+
+    fn interior(&self, object: u32) -> Option<&Interior> {
+        self.interiors.get(&object)
+    }
+
+    fn interiors(&self) -> Vec<(u32, Interior)> {
+        self.interiors.iter().map(|(&object, interior)| (object, interior.clone())).collect()
+    }
+
+    fn atmosphere(&self) -> Option<&Medium> {
+        self.atmosphere.as_ref().map(|m| &**m)
+    }
+
+    fn background(&self, r: &Ray) -> Vec3 {
+        match self.sky {
+            Some(ref sky) => sky.radiance(r.dir),
+            None => Vec3::new(0.0,0.0,0.0),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+// Materials combining other materials
+//
+// Mix and Add pick one of two materials per scatter, weighted so the average is the blend or the
+// sum. Coated puts a clear dielectric layer over any base: light either reflects off the coat or
+// refracts through it, scatters off the base and refracts back out.
+
+use model::hitable::HitRecord;
+use ray::Ray;
+use shader::material::{Material, reflect, same_direction};
+use shader::microfacet::{Ggx, fresnel_dielectric, refract_across};
+use shader::texture::Texture;
+use vec3::{Vec3, Onb};
+use rand::*;
+
+// Blend of a and b, factor 0 is all a and 1 is all b, read from the texture's first channel
+#[derive(Debug, Clone)]
+pub struct Mix<A: Material, B: Material, T: Texture> {
+    pub a: A,
+    pub b: B,
+    pub factor: T,
+}
+
+// Sum of a and b, e.g. a glossy layer over a diffuse one that already accounts for it
+#[derive(Debug, Clone)]
+pub struct Add<A: Material, B: Material> {
+    pub a: A,
+    pub b: B,
+}
+
+// Clear coat over base, for car paint and varnished wood
+// color is the coat's tint seen at normal incidence, the light having passed through it twice
+#[derive(Debug, Clone)]
+pub struct Coated<M: Material> {
+    pub base: M,
+    pub ior: f64,
+    pub distribution: Ggx,
+    pub color: Vec3,
+}
+
+impl<A: Material, B: Material, T: Texture> Mix<A, B, T> {
+    fn factor_at(&self, hit: &HitRecord) -> f64 {
+        self.factor.value(hit.u, hit.v, &hit.p).x.max(0.0).min(1.0)
+    }
+}
+
+impl<A: Material, B: Material, T: Texture> Material for Mix<A, B, T> {
+    // The probability of each material cancels with its weight in the blend
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        if rng.gen::<f64>() < self.factor_at(hit) {
+            self.b.scatter(r, hit, rng)
+        } else {
+            self.a.scatter(r, hit, rng)
+        }
+    }
+
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        let f = self.factor_at(hit);
+        match (self.a.emitted(r, hit), self.b.emitted(r, hit)) {
+            (None, None) => None,
+            (a, b) => {
+                let zero = Vec3::new(0.0,0.0,0.0);
+                Some(a.unwrap_or(zero) * (1.0 - f) + b.unwrap_or(zero) * f)
+            }
+        }
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let f = self.factor_at(hit);
+        self.a.eval(r, hit, wi) * (1.0 - f) + self.b.eval(r, hit, wi) * f
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let f = self.factor_at(hit);
+        self.a.pdf(r, hit, wi) * (1.0 - f) + self.b.pdf(r, hit, wi) * f
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        self.a.is_specular(r, hit, scattered) || self.b.is_specular(r, hit, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        let f = self.factor_at(hit);
+        self.a.albedo(hit) * (1.0 - f) + self.b.albedo(hit) * f
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.a.is_dispersive() || self.b.is_dispersive()
+    }
+}
+
+impl<A: Material, B: Material> Material for Add<A, B> {
+    // Each material is picked half the time so its weight is doubled
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let scattered = if rng.gen::<f64>() < 0.5 {
+            self.a.scatter(r, hit, rng)
+        } else {
+            self.b.scatter(r, hit, rng)
+        };
+        scattered.map(|(attenuation, ray)| (attenuation * 2.0, ray))
+    }
+
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        match (self.a.emitted(r, hit), self.b.emitted(r, hit)) {
+            (None, None) => None,
+            (a, b) => {
+                let zero = Vec3::new(0.0,0.0,0.0);
+                Some(a.unwrap_or(zero) + b.unwrap_or(zero))
+            }
+        }
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        self.a.eval(r, hit, wi) + self.b.eval(r, hit, wi)
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        0.5 * (self.a.pdf(r, hit, wi) + self.b.pdf(r, hit, wi))
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        self.a.is_specular(r, hit, scattered) || self.b.is_specular(r, hit, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        (self.a.albedo(hit) + self.b.albedo(hit)).map(&|x| x.min(1.0))
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.a.is_dispersive() || self.b.is_dispersive()
+    }
+}
+
+impl<M: Material> Coated<M> {
+    pub fn with_color(mut self, color: Vec3) -> Coated<M> {
+        self.color = color;
+        self
+    }
+}
+
+impl<M: Material> Material for Coated<M> {
+    // Reflect off the coat or refract in, picked by Fresnel as for Dielectric. Light under the coat
+    // scatters off the base, then leaves through the flat coat weighted by its Fresnel
+    // transmittance. Light reflected back down under the coat is lost, so the layer is a little
+    // darker than a true multiple scattering one
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let smooth = self.distribution.is_smooth();
+        let wm = if smooth {
+            Vec3::new(0.0,0.0,1.0)
+        } else {
+            self.distribution.sample_visible_normal(&wo, rng)
+        };
+
+        if rng.gen::<f64>() < fresnel_dielectric(wo.dot(wm), self.ior) {
+            // Return REFLECT off the coat
+            let wi = reflect(-wo, wm);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            let weight = if smooth { 1.0 } else { self.distribution.g2(&wo, &wi) / self.distribution.g1(&wo) };
+            return Some((Vec3::new(weight, weight, weight), Ray::new(hit.p, frame.to_world(wi))));
+        }
+
+        // Under the coat, the base sees the refracted ray
+        let down = match refract_across(&wo, &wm, self.ior) {
+            Some(down) if down.z < 0.0 => down,
+            _ => return None,
+        };
+        let inner = Ray {
+            origin: hit.p - frame.to_world(down),
+            dir: frame.to_world(down),
+            .. *r
+        };
+        let (attenuation, scattered) = match self.base.scatter(&inner, hit, rng) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        let up = frame.to_local(scattered.dir.normalized());
+        if up.z <= 0.0 {
+            // Transmitted through the base, the coat only tints it
+            return Some((attenuation * self.tint(-down.z, -up.z), scattered));
+        }
+
+        // Leave through the flat coat
+        let sin2_out = (up.x * up.x + up.y * up.y) * self.ior * self.ior;
+        if sin2_out >= 1.0 {
+            // Total internal reflection
+            return None;
+        }
+        let out = Vec3::new(up.x * self.ior, up.y * self.ior, (1.0 - sin2_out).sqrt());
+        let transmittance = 1.0 - fresnel_dielectric(up.z, 1.0 / self.ior);
+
+        Some((attenuation * self.tint(-down.z, up.z) * transmittance, Ray::new(scattered.origin, frame.to_world(out))))
+    }
+
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        self.base.emitted(r, hit)
+    }
+
+    // Reflection off the coat plus the base seen through it, as scatter: the base is evaluated with
+    // both directions refracted under the flat coat, and the solid angle under it shrinks by 1/eta^2
+    // on the way out. Light leaving the base beyond the critical angle never reaches wi
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let zero = Vec3::new(0.0,0.0,0.0);
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        let wi_local = frame.to_local(wi);
+        if wo.z <= 0.0 {
+            return zero;
+        }
+
+        let down = Vec3::new(-wo.x / self.ior, -wo.y / self.ior, -self.refracted_cos(wo.z));
+        let inner = Ray {
+            origin: hit.p - frame.to_world(down),
+            dir: frame.to_world(down),
+            .. *r
+        };
+        let entered = 1.0 - fresnel_dielectric(wo.z, self.ior);
+        if wi_local.z <= 0.0 {
+            // Only a base transmitting light through the coat, tinted on the way
+            return self.base.eval(&inner, hit, wi) * self.tint(-down.z, -wi_local.z) * entered;
+        }
+
+        let wm = (wo + wi_local).normalized();
+        let coat = fresnel_dielectric(wo.dot(wm), self.ior) * self.distribution.reflection(&wo, &wi_local) * wi_local.z;
+        let up = Vec3::new(wi_local.x / self.ior, wi_local.y / self.ior, self.refracted_cos(wi_local.z));
+        let left = 1.0 - fresnel_dielectric(up.z, 1.0 / self.ior);
+        let compressed = wi_local.z / (self.ior * self.ior * up.z);
+        let base = self.base.eval(&inner, hit, frame.to_world(up)) * self.tint(-down.z, up.z) * (entered * left * compressed);
+        Vec3::new(coat, coat, coat) + base
+    }
+
+    // A smooth coat reflects in the mirror direction, otherwise up to the base
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let mirror = same_direction(scattered.dir, reflect(r.dir.normalized(), n.normalized()));
+        (self.distribution.is_smooth() && mirror) || self.base.is_specular(r, hit, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.base.albedo(hit) * self.color
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+}
+
+impl<M: Material> Coated<M> {
+    // Cosine under the flat coat of a direction with cosine cos outside
+    fn refracted_cos(&self, cos: f64) -> f64 {
+        (1.0 - (1.0 - cos * cos) / (self.ior * self.ior)).max(0.0).sqrt()
+    }
+
+    // Absorption through the coat along directions with these cosines, in then out
+    fn tint(&self, cos_in: f64, cos_out: f64) -> Vec3 {
+        let path = 0.5 * (1.0 / cos_in.max(0.01) + 1.0 / cos_out.max(0.01));
+        self.color.map(&|c| c.max(0.0).powf(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::hit_xz_plane;
+    use shader::material::eval_matches_scatter;
+
+    #[test]
+    fn test_mix_picks_by_factor() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let red = Vec3::new(1.0,0.0,0.0);
+        let blue = Vec3::new(0.0,0.0,1.0);
+        let r = Ray::new(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,-1.0,0.0));
+        for &(factor, expected) in &[(0.0, red), (1.0, blue)] {
+            let m = Material::mix_constant(Material::lambertian_constant(red), Material::lambertian_constant(blue), factor);
+            for _ in 0..100 {
+                assert!(m.scatter(&r, &hit_xz_plane(), &mut rng).unwrap().0 == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_coat_over_black_reflects_fresnel() {
+        // Only the coat reflects, about 4% at normal incidence
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::coated(Material::lambertian_constant(Vec3::new(0.0,0.0,0.0)), 1.5, 0.0);
+        let r = Ray::new(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,-1.0,0.0));
+        let n = 10000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            if let Some((attenuation, _)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+                sum += attenuation.x;
+            }
+        }
+        let e = sum / n as f64;
+        assert!(e > 0.03 && e < 0.05);
+    }
+
+    #[test]
+    fn test_coat_over_white_conserves_energy() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::coated(Material::lambertian_constant(Vec3::new(1.0,1.0,1.0)), 1.5, 0.2);
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        let n = 10000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            if let Some((attenuation, scattered)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+                assert!(scattered.dir.y > 0.0);
+                sum += attenuation.x;
+            }
+        }
+        // Light off the base beyond the coat's critical angle is lost, a little over half of it
+        let e = sum / n as f64;
+        assert!(e > 0.4 && e <= 1.0);
+    }
+
+    #[test]
+    fn test_coated_eval_matches_scatter() {
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        for &roughness in &[0.0, 0.3] {
+            eval_matches_scatter(&Material::coated(Material::lambertian_constant(Vec3::new(0.8,0.8,0.8)), 1.5, roughness), &r);
+            eval_matches_scatter(&Material::coated(Material::metal(Vec3::new(0.9,0.6,0.3), 0.5), 1.5, roughness), &r);
+            eval_matches_scatter(&Material::coated(Material::lambertian_constant(Vec3::new(0.8,0.8,0.8)), 1.5, roughness).with_color(Vec3::new(0.6,0.8,0.9)), &r);
+        }
+    }
+}
@@ -1,35 +1,127 @@
-use vec3::{Vec3, random_in_unit_sphere};
+use vec3::{Vec3, Onb, random_cosine_direction};
 use ray::Ray;
-use model::hitable::HitRecord;
+use model::hitable::{HitRecord, Hitable};
+use shader::microfacet::{Ggx, fresnel_conductor, fresnel_dielectric, fresnel_schlick, refract_across};
+use shader::layered::{Add, Coated, Mix};
+use shader::normal_map::{BumpMap, NormalMap};
+use shader::phase::Phase;
+use shader::principled::Principled;
+use shader::subsurface::Subsurface;
 use shader::texture;
 use shader::texture::Texture;
+use spectrum::Ior;
+use medium::{self, Interior, Medium};
 use rand::*;
 
+use std::f64;
+use std::sync::Arc;
+
 #[derive(Debug, Clone)]
 pub struct Lambertian<T: Texture> {
     pub albedo: T,
 }
 
+// Rough metal, GGX microfacets with Smith masking-shadowing
 #[derive(Debug, Clone)]
-pub struct Metal {
-    pub albedo: Vec3,
-    fuzz: f64,
+pub struct Conductor {
+    pub fresnel: ConductorFresnel,
+    pub distribution: Ggx,
 }
 
+#[derive(Debug, Clone)]
+pub enum ConductorFresnel {
+    // Complex index of refraction eta + ik per channel
+    Complex { eta: Vec3, k: Vec3 },
+    // Color at normal incidence, for artist picked metals
+    Schlick { f0: Vec3 },
+}
+
+// Measured metals, complex index of refraction at roughly 650, 550 and 450nm
+#[derive(Debug, Clone, Copy)]
+pub enum MetalPreset {
+    Gold,
+    Copper,
+    Aluminium,
+    Silver,
+}
+
+impl MetalPreset {
+    // Returns (eta, k)
+    pub fn ior(&self) -> (Vec3, Vec3) {
+        match *self {
+            MetalPreset::Gold => (Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603)),
+            MetalPreset::Copper => (Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142)),
+            MetalPreset::Aluminium => (Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837)),
+            MetalPreset::Silver => (Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147)),
+        }
+    }
+}
+
+// Glass like interface, rough when distribution is not smooth
+// interior fills a closed object of it, e.g. tinted glass (with_transmission_color), it is
+// registered for the model by scene::Scene::new, see medium
+// A wavelength dependent index only disperses light when rendering spectrally
 #[derive(Debug, Clone)]
 pub struct Dielectric {
-    index: f64,
+    ior: Ior,
+    pub distribution: Ggx,
+    pub interior: Option<Interior>,
 }
 
+// Area light, emit is radiance unless a power is given, see with_nits and with_watts
+// Emits from both sides unless one_sided, then only to the side the normal points to
 #[derive(Debug, Clone)]
 pub struct DiffuseLight<T: Texture> {
-    pub emit: T
+    pub emit: T,
+    pub two_sided: bool,
+    pub distribution: Emission,
+    power: Power,
+    scale: f64,
+}
+
+// How radiance falls off with the angle from the normal
+#[derive(Debug, Clone)]
+pub enum Emission {
+    // Equal in all directions
+    Lambertian,
+    // Full inside the inner cone, smoothly falling to none at the outer cone
+    Spot { cos_inner: f64, cos_outer: f64 },
+    // Relative intensity at angles (degrees from the normal, increasing) as in an IES photometric
+    // file's vertical angles, linearly interpolated and none past the last angle
+    Profile { angles: Vec<f64>, intensities: Vec<f64> },
+}
+
+// Units emit is scaled to
+#[derive(Debug, Clone, Copy)]
+enum Power {
+    // emit is the radiance
+    Radiance,
+    // Radiance of white emit along the normal in cd/m^2, taking 1 as one nit
+    Nits(f64),
+    // Total power of white emit leaving a light of this area, taking radiance 1 as 1 W/(sr m^2)
+    Watts { watts: f64, area: f64 },
 }
 
-// Isotropic for volume
+// Isotropic for volume, scattering uniformly unless given another phase function
 #[derive(Debug, Clone)]
 pub struct Isotropic<T: Texture> {
     pub albedo: T,
+    pub phase: Phase,
+    pub emit: Vec3,
+}
+
+impl<T: Texture> Isotropic<T> {
+    pub fn with_phase(mut self, phase: Phase) -> Isotropic<T> {
+        self.phase = phase;
+        self
+    }
+
+    // Glowing gas: light given off at each collision, the emission of what absorbs there over the
+    // extinction
+    pub fn with_emission(mut self, emit: Vec3) -> Isotropic<T> {
+        self.emit = emit;
+        self
+    }
 }
 
 impl Material {
@@ -44,16 +136,125 @@ impl Material {
         }
     }
 
-    pub fn metal(albedo: Vec3, fuzz: f64) -> Metal {
-        Metal {
-            albedo: albedo,
-            fuzz: if fuzz < 1.0 { fuzz } else { 1.0 }
+    // Metal colored albedo at normal incidence, fuzz is used as the roughness
+    pub fn metal(albedo: Vec3, fuzz: f64) -> Conductor {
+        Conductor {
+            fresnel: ConductorFresnel::Schlick { f0: albedo },
+            distribution: Ggx::isotropic(fuzz),
+        }
+    }
+
+    pub fn conductor(eta: Vec3, k: Vec3, roughness: f64) -> Conductor {
+        Material::conductor_anisotropic(eta, k, roughness, roughness)
+    }
+
+    // roughness_u along the surface tangent, roughness_v along the bitangent
+    pub fn conductor_anisotropic(eta: Vec3, k: Vec3, roughness_u: f64, roughness_v: f64) -> Conductor {
+        Conductor {
+            fresnel: ConductorFresnel::Complex { eta: eta, k: k },
+            distribution: Ggx::new(roughness_u, roughness_v),
         }
     }
 
+    pub fn metal_preset(preset: MetalPreset, roughness: f64) -> Conductor {
+        let (eta, k) = preset.ior();
+        Material::conductor(eta, k, roughness)
+    }
+
     pub fn dielectric(index: f64) -> Dielectric {
+        Material::rough_dielectric(index, 0.0)
+    }
+
+    // Frosted glass, sandblasted acrylic, rough ice
+    pub fn rough_dielectric(index: f64, roughness: f64) -> Dielectric {
+        Material::dispersive_dielectric(Ior::Constant(index), roughness)
+    }
+
+    // Prisms, diamonds, anything splitting white light into a spectrum
+    pub fn dispersive_dielectric(ior: Ior, roughness: f64) -> Dielectric {
         Dielectric {
-            index: index
+            ior: ior,
+            distribution: Ggx::isotropic(roughness),
+            interior: None,
+        }
+    }
+
+    // Disney style uber material, defaults to rough white plastic, see shader::principled
+    pub fn principled<T: Texture + Send + Sync + 'static>(base_color: T) -> Principled {
+        let scalar = |x: f64| texture::boxed_texture(texture::constant_texture(Vec3::new(x, x, x)));
+        Principled {
+            base_color: texture::boxed_texture(base_color),
+            metallic: scalar(0.0),
+            roughness: scalar(0.5),
+            specular: scalar(0.5),
+            sheen: scalar(0.0),
+            clearcoat: scalar(0.0),
+            clearcoat_roughness: scalar(0.1),
+            transmission: scalar(0.0),
+            ior: 1.5,
+        }
+    }
+
+    pub fn principled_constant(base_color: Vec3) -> Principled {
+        Material::principled(texture::constant_texture(base_color))
+    }
+
+    // factor 0 is all a, 1 is all b
+    pub fn mix<A: Material, B: Material, T: Texture>(a: A, b: B, factor: T) -> Mix<A, B, T> {
+        Mix {
+            a: a,
+            b: b,
+            factor: factor,
+        }
+    }
+
+    pub fn mix_constant<A: Material, B: Material>(a: A, b: B, factor: f64) -> Mix<A, B, texture::ConstantTexture> {
+        Material::mix(a, b, texture::constant_texture(Vec3::new(factor, factor, factor)))
+    }
+
+    pub fn add<A: Material, B: Material>(a: A, b: B) -> Add<A, B> {
+        Add {
+            a: a,
+            b: b,
+        }
+    }
+
+    // Clear dielectric coat over base, roughness of the coat's surface
+    pub fn coated<M: Material>(base: M, ior: f64, roughness: f64) -> Coated<M> {
+        Coated {
+            base: base,
+            ior: ior,
+            distribution: Ggx::isotropic(roughness),
+            color: Vec3::new(1.0,1.0,1.0),
+        }
+    }
+
+    // Shade material with normals from a tangent space normal map image
+    pub fn normal_map<M: Material, T: Texture>(material: M, normals: T, strength: f64) -> NormalMap<M, T> {
+        NormalMap {
+            material: material,
+            normals: normals,
+            strength: strength,
+        }
+    }
+
+    // Shade material as if displaced by a height texture, e.g. Perlin noise
+    pub fn bump_map<M: Material, T: Texture>(material: M, height: T, scale: f64) -> BumpMap<M, T> {
+        BumpMap {
+            material: material,
+            height: height,
+            scale: scale,
+        }
+    }
+
+    // Translucent solid like marble or wax, see shader::subsurface for sharing the boundary
+    pub fn subsurface<H: Hitable, T: Texture>(boundary: H, albedo: T, mean_free_path: Vec3) -> Subsurface<H, T> {
+        Subsurface {
+            boundary: boundary,
+            albedo: albedo,
+            mean_free_path: mean_free_path,
+            ior: 1.4,
+            distribution: Ggx::isotropic(0.0),
         }
     }
 
@@ -64,6 +265,10 @@ impl Material {
     pub fn diffuse_light<T: Texture>(emit: T) -> DiffuseLight<T> {
         DiffuseLight {
             emit: emit,
+            two_sided: true,
+            distribution: Emission::Lambertian,
+            power: Power::Radiance,
+            scale: 1.0,
         }
     }
 
@@ -74,6 +279,8 @@ impl Material {
     pub fn isotropic<T: Texture>(t: T) -> Isotropic<T> {
         Isotropic {
             albedo: t,
+            phase: Phase::Isotropic,
+            emit: Vec3::new(0.0,0.0,0.0),
         }
     }
 }
@@ -82,79 +289,386 @@ pub trait Material {
     // Return attentuation vector and outgoing ray if produced
     fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)>;
 
-    // Light emitted
-    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Option<Vec3>;
+    // Light emitted towards the origin of r, which hit the surface at hit
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3>;
+
+    // BSDF times the cosine with the normal, for light arriving from unit direction wi (pointing
+    // away from the surface) and leaving towards the origin of r. Black for perfectly specular
+    // materials, light from a single direction can only reach them by scattering
+    fn eval(&self, _r: &Ray, _hit: &HitRecord, _wi: Vec3) -> Vec3 {
+        Vec3::new(0.0,0.0,0.0)
+    }
+
+    // Density scatter picks unit direction wi with, per steradian, to weigh light found by
+    // scattering against sampling the lights. 0 if unknown, such light is then left to sampling
+    fn pdf(&self, _r: &Ray, _hit: &HitRecord, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    // What fills a closed object of this material, registered for its model by scene::Scene::new
+    fn interior(&self) -> Option<&Interior> {
+        None
+    }
+
+    // True if scattered came from a perfectly specular lobe, which eval can't see. Light the ray
+    // then finds by chance is counted, otherwise sampling the lights already accounted for it
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let f = self.eval(r, hit, scattered.dir.normalized());
+        f.x <= 0.0 && f.y <= 0.0 && f.z <= 0.0
+    }
+
+    // Surface color for output variables and denoising, white unless the material has one
+    fn albedo(&self, _hit: &HitRecord) -> Vec3 {
+        Vec3::new(1.0,1.0,1.0)
+    }
+
+    // True if scatter depends on the ray's wavelength, so a spectral path can only carry one
+    fn is_dispersive(&self) -> bool {
+        false
+    }
 }
 
 impl<T> Material for Lambertian<T>
     where T: Texture
 {
-    fn scatter(&self, _r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
-        let target = hit.p + hit.normal + random_in_unit_sphere(rng);
-        let scattered = Ray::new(hit.p, target - hit.p);
+    // Cosine weighted on the side the ray came from, which cancels in the weight
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let scattered = Ray::new(hit.p, Onb::from_w(n.normalized()).to_world(random_cosine_direction(rng)));
         let attentuation = self.albedo.value(hit.u,hit.v,&hit.p);
         Some((attentuation, scattered))
     }
 
-    fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Option<Vec3> {
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
         None
     }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let cos = wi.dot(n.normalized());
+        if cos <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        self.albedo.value(hit.u, hit.v, &hit.p) * (cos / f64::consts::PI)
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        wi.dot(n.normalized()).max(0.0) / f64::consts::PI
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.albedo.value(hit.u, hit.v, &hit.p)
+    }
 }
 
-impl Material for Metal {
+impl Conductor {
+    fn fresnel(&self, cos_i: f64) -> Vec3 {
+        match self.fresnel {
+            ConductorFresnel::Complex { eta, k } => fresnel_conductor(cos_i, eta, k),
+            ConductorFresnel::Schlick { f0 } => fresnel_schlick(cos_i, f0),
+        }
+    }
+}
+
+impl Material for Conductor {
+    // Sample a visible microfacet normal and reflect about it
+    // The weight f * cos / pdf reduces to F * G2 / G1
     fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
-        let v = r.dir.normalized();
-        let reflected = reflect(v, hit.normal);
-        let scattered = Ray::new(hit.p, reflected + random_in_unit_sphere(rng) * self.fuzz);
-        let attentuation = self.albedo;
-        if scattered.dir.dot(hit.normal) > 0.0 {
-            Some((attentuation, scattered))
+        // Shade the side of the surface the ray came from, anisotropic roughness follows dp/du
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = hit.tangent_frame(n);
+        let wo = frame.to_local(-r.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let wm = if self.distribution.is_smooth() {
+            Vec3::new(0.0,0.0,1.0)
         } else {
-            None
+            self.distribution.sample_visible_normal(&wo, rng)
+        };
+        let wi = reflect(-wo, wm);
+        if wi.z <= 0.0 {
+            // Reflected below the surface, would need multiple scattering between microfacets
+            return None;
         }
+
+        let weight = self.fresnel(wo.dot(wm)) * (self.distribution.g2(&wo, &wi) / self.distribution.g1(&wo));
+        Some((weight, Ray::new(hit.p, frame.to_world(wi))))
     }
 
-    fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Option<Vec3> {
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
         None
     }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = hit.tangent_frame(n);
+        let wo = frame.to_local(-r.dir.normalized());
+        let wi = frame.to_local(wi);
+        let f = self.distribution.reflection(&wo, &wi);
+        if f == 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        self.fresnel(wo.dot((wo + wi).normalized())) * (f * wi.z)
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = hit.tangent_frame(n);
+        self.distribution.reflection_pdf(&frame.to_local(-r.dir.normalized()), &frame.to_local(wi))
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        self.distribution.is_smooth() && same_direction(scattered.dir, reflect(r.dir.normalized(), n.normalized()))
+    }
+
+    fn albedo(&self, _hit: &HitRecord) -> Vec3 {
+        self.fresnel(1.0)
+    }
+}
+
+impl Dielectric {
+    // Beer-Lambert coefficient per unit distance travelled inside
+    pub fn with_absorption(self, absorption: Vec3) -> Dielectric {
+        self.with_medium_inside(medium::homogeneous(absorption, Vec3::new(0.0,0.0,0.0)), 0)
+    }
+
+    // Absorption so light travelling distance inside is left with color
+    pub fn with_transmission_color(self, color: Vec3, distance: f64) -> Dielectric {
+        self.with_medium_inside(medium::absorbing(color, distance), 0)
+    }
+
+    // Any medium inside, higher priorities win where objects overlap
+    pub fn with_medium_inside<M: Medium + 'static>(mut self, medium: M, priority: i32) -> Dielectric {
+        self.interior = Some(Interior { medium: Some(Arc::new(medium)), priority: priority });
+        self
+    }
 }
 
 impl Material for Dielectric {
+    // Sample a visible microfacet normal, then reflect or refract through it chosen by the exact
+    // Fresnel term, which cancels out of the weight leaving G2 / G1 (1 when smooth)
+    //
+    // The radiance scaling by 1/eta^2 when crossing the interface is not applied, it cancels out
+    // for rays that enter and leave a closed object
     fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
-        let reflected = reflect(r.dir, hit.normal);
-        let attentuation = Vec3::new(1.0,1.0,1.0);
+        // Work on the side the ray came from, eta is the index ratio across the interface
+        let entering = r.dir.dot(hit.normal) < 0.0;
+        let index = self.ior.at(r.wavelength);
+        let (n, eta) = if entering { (hit.normal, index) } else { (-hit.normal, 1.0 / index) };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+
+        let smooth = self.distribution.is_smooth();
+        let wm = if smooth {
+            Vec3::new(0.0,0.0,1.0)
+        } else {
+            self.distribution.sample_visible_normal(&wo, rng)
+        };
 
-        let (outward_normal, ni_over_nt, cosine) =
-            if r.dir.dot(hit.normal) > 0.0 {
-                let cosine = self.index * r.dir.dot(hit.normal) / r.dir.length();
-                (-hit.normal, self.index, cosine)
-            } else {
-                let cosine = -r.dir.dot(hit.normal) / r.dir.length();
-                (hit.normal, 1.0 / self.index, cosine)
-            };
-
-        let out_ray = match refract(r.dir, outward_normal, ni_over_nt) {
-            Some(refracted) => {
-                if random::<f64>() < schlick(cosine, self.index) {
-                    // Return REFLECT
-                    Ray::new(hit.p, reflected)
-                } else {
-                    // Return REFRACT
-                    Ray::new(hit.p, refracted)
-                }
-            },
-            None => {
-                // No refracted ray, return REFLECT
-                Ray::new(hit.p, reflected)
+        let reflectance = fresnel_dielectric(wo.dot(wm), eta);
+        let wi = if rng.gen::<f64>() < reflectance {
+            // Return REFLECT
+            let wi = reflect(-wo, wm);
+            if wi.z <= 0.0 {
+                return None;
             }
+            wi
+        } else {
+            // Return REFRACT, reflectance is 1 under total internal reflection so always refracts
+            let wi = refract_across(&wo, &wm, eta).unwrap();
+            if wi.z >= 0.0 {
+                return None;
+            }
+            wi
         };
 
-        Some((attentuation, out_ray))
+        let weight = if smooth { 1.0 } else { self.distribution.g2(&wo, &wi) / self.distribution.g1(&wo) };
+        Some((Vec3::new(weight, weight, weight), Ray::new(hit.p, frame.to_world(wi))))
     }
 
-    fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Option<Vec3> {
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
         None
     }
+
+    // Reflection, or transmission through the generalized half vector (Walter et al. 2007),
+    // without the 1/eta^2 scaling as in scatter
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let zero = Vec3::new(0.0,0.0,0.0);
+        if self.distribution.is_smooth() {
+            return zero;
+        }
+        let entering = r.dir.dot(hit.normal) < 0.0;
+        let index = self.ior.at(r.wavelength);
+        let (n, eta) = if entering { (hit.normal, index) } else { (-hit.normal, 1.0 / index) };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        let wi = frame.to_local(wi);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return zero;
+        }
+
+        let f = if wi.z > 0.0 {
+            let wm = (wo + wi).normalized();
+            fresnel_dielectric(wo.dot(wm), eta) * self.distribution.reflection(&wo, &wi)
+        } else {
+            let wm = (wi * eta + wo).normalized();
+            let wm = if wm.z < 0.0 { -wm } else { wm };
+            let (cos_o, cos_i) = (wo.dot(wm), wi.dot(wm));
+            if cos_o <= 0.0 || cos_i >= 0.0 {
+                return zero;
+            }
+            let denom = (cos_i + cos_o / eta) * (cos_i + cos_o / eta);
+            let g = self.distribution.d(&wm) * self.distribution.g2(&wo, &(-wi));
+            (1.0 - fresnel_dielectric(cos_o, eta)) * g * (cos_i * cos_o / (wo.z * wi.z * denom)).abs()
+        };
+
+        Vec3::new(f, f, f) * wi.z.abs()
+    }
+
+    // The visible normal's density through the reflection or refraction, times the Fresnel odds
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+        let entering = r.dir.dot(hit.normal) < 0.0;
+        let index = self.ior.at(r.wavelength);
+        let (n, eta) = if entering { (hit.normal, index) } else { (-hit.normal, 1.0 / index) };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        let wi = frame.to_local(wi);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return 0.0;
+        }
+
+        if wi.z > 0.0 {
+            let wm = (wo + wi).normalized();
+            return fresnel_dielectric(wo.dot(wm), eta) * self.distribution.reflection_pdf(&wo, &wi);
+        }
+        let wm = (wi * eta + wo).normalized();
+        let wm = if wm.z < 0.0 { -wm } else { wm };
+        let (cos_o, cos_i) = (wo.dot(wm), wi.dot(wm));
+        if cos_o <= 0.0 || cos_i >= 0.0 {
+            return 0.0;
+        }
+        let denom = (cos_i + cos_o / eta) * (cos_i + cos_o / eta);
+        (1.0 - fresnel_dielectric(cos_o, eta)) * self.distribution.visible_normal_pdf(&wo, &wm) * (-cos_i / denom)
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        if !self.distribution.is_smooth() {
+            return false;
+        }
+        let entering = r.dir.dot(hit.normal) < 0.0;
+        let index = self.ior.at(r.wavelength);
+        let (n, eta) = if entering { (hit.normal, index) } else { (-hit.normal, 1.0 / index) };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        let wi = frame.to_local(scattered.dir.normalized());
+        let normal = Vec3::new(0.0,0.0,1.0);
+        same_direction(wi, reflect(-wo, normal)) || refract_across(&wo, &normal, eta).map_or(false, |t| same_direction(wi, t))
+    }
+
+    fn interior(&self) -> Option<&Interior> {
+        self.interior.as_ref()
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.ior.is_dispersive()
+    }
+}
+
+impl<T: Texture> DiffuseLight<T> {
+    pub fn one_sided(mut self) -> DiffuseLight<T> {
+        self.two_sided = false;
+        self.update_scale()
+    }
+
+    // Radiance falls off from full at inner to none at outer, both half angles in degrees
+    pub fn with_spot(mut self, inner: f64, outer: f64) -> DiffuseLight<T> {
+        let outer = outer.max(inner);
+        self.distribution = Emission::Spot {
+            cos_inner: inner.to_radians().cos(),
+            cos_outer: outer.to_radians().cos(),
+        };
+        self.update_scale()
+    }
+
+    // Tabulated distribution, e.g. the candela values of an IES file for one horizontal angle
+    pub fn with_profile(mut self, angles: Vec<f64>, intensities: Vec<f64>) -> DiffuseLight<T> {
+        assert!(angles.len() == intensities.len() && angles.len() > 0, "Profile needs an intensity for each angle");
+        let peak = intensities.iter().cloned().fold(0.0, f64::max);
+        let intensities = intensities.iter().map(|i| if peak > 0.0 { i / peak } else { 0.0 }).collect();
+        self.distribution = Emission::Profile {
+            angles: angles,
+            intensities: intensities,
+        };
+        self.update_scale()
+    }
+
+    pub fn with_nits(mut self, nits: f64) -> DiffuseLight<T> {
+        self.power = Power::Nits(nits);
+        self.update_scale()
+    }
+
+    // Area is the light's surface area in scene units squared, the power is spread over it
+    pub fn with_watts(mut self, watts: f64, area: f64) -> DiffuseLight<T> {
+        self.power = Power::Watts { watts: watts, area: area };
+        self.update_scale()
+    }
+
+    // Relative radiance leaving at cos_theta from the normal
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        match self.distribution {
+            Emission::Lambertian => 1.0,
+            Emission::Spot { cos_inner, cos_outer } => {
+                if cos_theta >= cos_inner {
+                    1.0
+                } else if cos_theta <= cos_outer {
+                    0.0
+                } else {
+                    let x = (cos_theta - cos_outer) / (cos_inner - cos_outer);
+                    x * x * (3.0 - 2.0 * x)
+                }
+            }
+            Emission::Profile { ref angles, ref intensities } => {
+                let angle = cos_theta.max(-1.0).min(1.0).acos().to_degrees();
+                if angle <= angles[0] {
+                    return intensities[0];
+                }
+                for i in 1..angles.len() {
+                    if angle <= angles[i] {
+                        let x = (angle - angles[i-1]) / (angles[i] - angles[i-1]);
+                        return intensities[i-1] * (1.0 - x) + intensities[i] * x;
+                    }
+                }
+                0.0
+            }
+        }
+    }
+
+    fn update_scale(mut self) -> DiffuseLight<T> {
+        self.scale = match self.power {
+            Power::Radiance => 1.0,
+            Power::Nits(nits) => nits,
+            Power::Watts { watts, area } => {
+                // Power per unit radiance is area * sides * integral of falloff * cos over the
+                // hemisphere, pi for a Lambertian emitter
+                let steps = 1024;
+                let mut integral = 0.0;
+                for i in 0..steps {
+                    let cos_theta = (i as f64 + 0.5) / steps as f64;
+                    integral += 2.0 * ::std::f64::consts::PI * self.falloff(cos_theta) * cos_theta / steps as f64;
+                }
+                let sides = if self.two_sided { 2.0 } else { 1.0 };
+                if integral > 0.0 { watts / (area * sides * integral) } else { 0.0 }
+            }
+        };
+        self
+    }
 }
 
 impl<T: Texture> Material for DiffuseLight<T> {
@@ -163,21 +677,51 @@ impl<T: Texture> Material for DiffuseLight<T> {
     }
 
     // Light emitted
-    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Option<Vec3> {
-        Some(self.emit.value(u,v,p))
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        let cos_theta = -r.dir.normalized().dot(hit.normal.normalized());
+        if cos_theta <= 0.0 && !self.two_sided {
+            return None;
+        }
+        let falloff = self.falloff(cos_theta.abs());
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(self.emit.value(hit.u, hit.v, &hit.p) * (falloff * self.scale))
+    }
+
+    // Clamped so bright lights do not dominate denoising features
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.emit.value(hit.u, hit.v, &hit.p).map(&|x| x.min(1.0))
     }
 }
 
 impl<T: Texture> Material for Isotropic<T> {
-    fn scatter(&self, _r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
-        let scattered = Ray::new(hit.p, ::vec3::random_in_unit_sphere(rng));
+    // Sampled in proportion to the phase function, which cancels in the weight
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let scattered = Ray { origin: hit.p, dir: self.phase.sample(r.dir, rng), .. *r };
         let attentuation = self.albedo.value(hit.u, hit.v, &hit.p);
         return Some((attentuation, scattered))
     }
 
     // Light emitted
-    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Option<Vec3> {
-        None
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
+        if self.emit == Vec3::new(0.0,0.0,0.0) {
+            return None;
+        }
+        Some(self.emit)
+    }
+
+    // Phase function, no cosine inside a volume
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        self.albedo.value(hit.u, hit.v, &hit.p) * self.phase.eval_directions(r.dir, wi)
+    }
+
+    fn pdf(&self, r: &Ray, _hit: &HitRecord, wi: Vec3) -> f64 {
+        self.phase.eval_directions(r.dir, wi)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.albedo.value(hit.u, hit.v, &hit.p)
     }
 }
 
@@ -187,28 +731,38 @@ pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.0 * v.dot(n) * n
 }
 
-pub fn refract(v: Vec3, n: Vec3, ni_over_nt: f64) -> Option<Vec3> {
-    let uv = v.normalized();
-    let dt = uv.dot(n);
-    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
-    if discriminant > 0.0 {
-        let refracted = ni_over_nt * (uv - n * dt) - n * discriminant.sqrt();
-        Some(refracted)
-    } else {
-        None
-    }
+// True if a and b point the same way, to recognize directions scattered by specular lobes
+pub fn same_direction(a: Vec3, b: Vec3) -> bool {
+    a.normalized().dot(b.normalized()) > 1.0 - 1e-9
 }
 
-pub fn schlick(cosine: f64, index: f64) -> f64 {
-    let r0 = (1.0 - index) / (1.0 + index);
-    let r0 = r0 * r0;
-    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+// Integral of eval over the sphere by uniform sampling, against the average weight of scatter
+// leaving out the specular lobes eval can't see
+#[cfg(test)]
+pub fn eval_matches_scatter(m: &Material, r: &Ray) {
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let n = 200000;
+    let hit = ::model::hitable::hit_xz_plane();
+    let mut integral = 0.0;
+    let mut scattered = 0.0;
+    for _ in 0..n {
+        let wi = ::vec3::random_in_unit_sphere(&mut rng).normalized();
+        integral += m.eval(r, &hit, wi).x * 4.0 * f64::consts::PI;
+        if let Some((attenuation, ray)) = m.scatter(r, &hit, &mut rng) {
+            if !m.is_specular(r, &hit, &ray) {
+                scattered += attenuation.x;
+            }
+        }
+    }
+    let (integral, scattered) = (integral / n as f64, scattered / n as f64);
+    assert!((integral - scattered).abs() < 0.03, "eval {} scatter {}", integral, scattered);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ::vec3::Vec3;
+    use ::vec3::{Vec3, random_in_unit_sphere};
+    use model::hitable::hit_xz_plane;
 
     #[test]
     fn test_reflect() {
@@ -216,4 +770,170 @@ mod tests {
         let n = Vec3::new(0.0,1.0,0.0);
         assert!(reflect(v,n) == Vec3::new(1.0,1.0,0.0))
     }
+
+    #[test]
+    fn test_smooth_conductor_is_mirror() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::metal(Vec3::new(1.0,1.0,1.0), 0.0);
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        let (attenuation, scattered) = m.scatter(&r, &hit_xz_plane(), &mut rng).unwrap();
+        assert!(Vec3::approx_float_eq(&scattered.dir, &Vec3::new(1.0,1.0,0.0).normalized()));
+        assert!(Vec3::approx_float_eq(&attenuation, &Vec3::new(1.0,1.0,1.0)));
+    }
+
+    #[test]
+    fn test_smooth_dielectric_normal_incidence() {
+        // About 4% reflected straight back, the rest passes straight through
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::dielectric(1.5);
+        let r = Ray::new(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,-1.0,0.0));
+        let n = 10000;
+        let mut reflected = 0;
+        for _ in 0..n {
+            let (attenuation, scattered) = m.scatter(&r, &hit_xz_plane(), &mut rng).unwrap();
+            assert!(attenuation == Vec3::new(1.0,1.0,1.0));
+            if scattered.dir.y > 0.0 {
+                reflected += 1;
+                assert!(Vec3::approx_float_eq(&scattered.dir, &Vec3::new(0.0,1.0,0.0)));
+            } else {
+                assert!(Vec3::approx_float_eq(&scattered.dir, &Vec3::new(0.0,-1.0,0.0)));
+            }
+        }
+        let ratio = reflected as f64 / n as f64;
+        assert!(ratio > 0.03 && ratio < 0.05);
+    }
+
+    #[test]
+    fn test_rough_dielectric_sides() {
+        // Leaving the glass at a grazing angle, rays reflect back inside or refract out
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::rough_dielectric(1.5, 0.4);
+        let r = Ray::new(Vec3::new(0.0,-1.0,0.0), Vec3::new(0.5,1.0,0.0));
+        for _ in 0..1000 {
+            if let Some((attenuation, scattered)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+                assert!(attenuation.x > 0.0 && attenuation.x <= 1.0);
+                assert!(scattered.dir.y != 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rough_conductor_energy() {
+        // A white rough metal only loses the energy of multiple scattering, around 11% here
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::metal(Vec3::new(1.0,1.0,1.0), 0.5);
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        let n = 10000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            if let Some((attenuation, scattered)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+                assert!(scattered.dir.y > 0.0);
+                sum += attenuation.x;
+            }
+        }
+        let albedo = sum / n as f64;
+        assert!(albedo > 0.85 && albedo < 0.92);
+    }
+
+    // Ray leaving the xz plane at angle degrees from the normal, hitting it from above or below
+    fn ray_at(degrees: f64, below: bool) -> Ray {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let y = if below { -cos } else { cos };
+        Ray::new(Vec3::new(sin, y, 0.0), Vec3::new(-sin, -y, 0.0))
+    }
+
+    #[test]
+    fn test_one_sided_light() {
+        let white = Vec3::new(1.0,1.0,1.0);
+        let two = Material::diffuse_light_constant(white);
+        let one = Material::diffuse_light_constant(white).one_sided();
+        assert!(two.emitted(&ray_at(30.0, true), &hit_xz_plane()) == Some(white));
+        assert!(one.emitted(&ray_at(30.0, false), &hit_xz_plane()) == Some(white));
+        assert!(one.emitted(&ray_at(30.0, true), &hit_xz_plane()).is_none());
+    }
+
+    #[test]
+    fn test_spot_falloff() {
+        let m = Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)).with_spot(20.0, 40.0);
+        let at = |degrees: f64| m.emitted(&ray_at(degrees, false), &hit_xz_plane()).map(|e| e.x).unwrap_or(0.0);
+        assert!(at(10.0) == 1.0);
+        assert!(at(30.0) > 0.0 && at(30.0) < 1.0);
+        assert!(at(25.0) > at(35.0));
+        assert!(at(50.0) == 0.0);
+    }
+
+    #[test]
+    fn test_profile_interpolates() {
+        let m = Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0))
+            .with_profile(vec![0.0, 45.0, 90.0], vec![200.0, 100.0, 0.0]);
+        let e = m.emitted(&ray_at(22.5, false), &hit_xz_plane()).unwrap();
+        assert!((e.x - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_watts_integrate_to_power() {
+        // Sum radiance * cos over the hemisphere and both sides of a light of area 2
+        for m in &[
+            Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)).with_watts(100.0, 2.0),
+            Material::diffuse_light_constant(Vec3::new(1.0,1.0,1.0)).with_spot(10.0, 30.0).one_sided().with_watts(100.0, 2.0),
+        ] {
+            let steps = 2000;
+            let mut power = 0.0;
+            for &below in &[false, true] {
+                for i in 0..steps {
+                    let theta = (i as f64 + 0.5) / steps as f64 * 90.0;
+                    let radiance = m.emitted(&ray_at(theta, below), &hit_xz_plane()).map(|e| e.x).unwrap_or(0.0);
+                    let (sin, cos) = theta.to_radians().sin_cos();
+                    power += radiance * cos * sin * 2.0 * ::std::f64::consts::PI * (::std::f64::consts::PI / 2.0 / steps as f64);
+                }
+            }
+            assert!((power * 2.0 - 100.0).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_eval_matches_scatter() {
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        eval_matches_scatter(&Material::metal(Vec3::new(1.0,0.8,0.6), 0.5), &r);
+        eval_matches_scatter(&Material::rough_dielectric(1.5, 0.5), &r);
+        eval_matches_scatter(&Material::principled_constant(Vec3::new(0.8,0.8,0.8)).with_roughness(0.4), &r);
+        eval_matches_scatter(&Material::principled_constant(Vec3::new(0.8,0.8,0.8)).with_transmission(1.0, 1.5).with_roughness(0.6), &r);
+        eval_matches_scatter(&Material::isotropic_constant(Vec3::new(0.8,0.8,0.8)).with_phase(Phase::HenyeyGreenstein(0.8)), &r);
+    }
+
+    // pdf integrates to how often scatter returns a ray, and eval / pdf along the rays it returns
+    // averages to its weight
+    fn pdf_matches_scatter(m: &Material, r: &Ray) {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 200000;
+        let (mut integral, mut returned, mut weighted, mut scattered) = (0.0, 0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let wi = random_in_unit_sphere(&mut rng).normalized();
+            integral += m.pdf(r, &hit_xz_plane(), wi) * 4.0 * f64::consts::PI;
+            if let Some((attenuation, ray)) = m.scatter(r, &hit_xz_plane(), &mut rng) {
+                let wi = ray.dir.normalized();
+                let pdf = m.pdf(r, &hit_xz_plane(), wi);
+                assert!(pdf > 0.0);
+                returned += 1.0;
+                weighted += m.eval(r, &hit_xz_plane(), wi).x / pdf;
+                scattered += attenuation.x;
+            }
+        }
+        let (integral, returned) = (integral / n as f64, returned / n as f64);
+        assert!((integral - returned).abs() < 0.03, "pdf {} returned {}", integral, returned);
+        let (weighted, scattered) = (weighted / n as f64, scattered / n as f64);
+        assert!((weighted - scattered).abs() < 0.03, "eval / pdf {} scatter {}", weighted, scattered);
+    }
+
+    #[test]
+    fn test_pdf_matches_scatter() {
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        pdf_matches_scatter(&Material::lambertian_constant(Vec3::new(0.8,0.8,0.8)), &r);
+        pdf_matches_scatter(&Material::metal(Vec3::new(1.0,0.8,0.6), 0.5), &r);
+        pdf_matches_scatter(&Material::rough_dielectric(1.5, 0.5), &r);
+        pdf_matches_scatter(&Material::principled_constant(Vec3::new(0.8,0.8,0.8)).with_roughness(0.4), &r);
+        pdf_matches_scatter(&Material::principled_constant(Vec3::new(0.8,0.8,0.8)).with_transmission(1.0, 1.5).with_roughness(0.6), &r);
+        pdf_matches_scatter(&Material::mix_constant(Material::lambertian_constant(Vec3::new(0.8,0.8,0.8)), Material::metal(Vec3::new(1.0,1.0,1.0), 0.5), 0.3), &r);
+        pdf_matches_scatter(&Material::isotropic_constant(Vec3::new(0.8,0.8,0.8)).with_phase(Phase::HenyeyGreenstein(0.5)), &r);
+    }
 }
@@ -0,0 +1,250 @@
+// Microfacet theory shared by rough materials
+//
+// Directions are in the local shading frame (see vec3::Onb) with z along the normal, and point
+// away from the surface.
+
+use vec3::Vec3;
+use rand::*;
+
+use std::f64::consts::PI;
+
+// Smallest alpha, so perfectly smooth surfaces do not divide by zero
+const MIN_ALPHA: f64 = 0.0001;
+
+// Below this alpha surfaces are treated as perfectly smooth
+const SMOOTH_ALPHA: f64 = 0.001;
+
+//
+// GGX / Trowbridge-Reitz distribution of microfacet normals
+// alpha_x and alpha_y are the roughness along the tangent and bitangent
+//
+#[derive(Debug, Clone, Copy)]
+pub struct Ggx {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl Ggx {
+    // Perceptual roughness in [0,1] is squared, so it looks linear
+    pub fn new(roughness_x: f64, roughness_y: f64) -> Ggx {
+        let alpha = |r: f64| (r.max(0.0).min(1.0) * r.max(0.0).min(1.0)).max(MIN_ALPHA);
+        Ggx {
+            alpha_x: alpha(roughness_x),
+            alpha_y: alpha(roughness_y),
+        }
+    }
+
+    pub fn isotropic(roughness: f64) -> Ggx {
+        Ggx::new(roughness, roughness)
+    }
+
+    // Close enough to a perfect mirror to skip sampling microfacets
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    // Density of microfacet normal wm
+    pub fn d(&self, wm: &Vec3) -> f64 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let s = x*x + y*y + wm.z*wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * s * s)
+    }
+
+    // Smith auxiliary function
+    pub fn lambda(&self, w: &Vec3) -> f64 {
+        if w.z == 0.0 {
+            return 0.0;
+        }
+        let a2 = (self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2);
+        ((1.0 + a2 / (w.z * w.z)).sqrt() - 1.0) / 2.0
+    }
+
+    // Smith masking
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height correlated Smith masking-shadowing
+    pub fn g2(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Sample a microfacet normal visible from wo (Heitz 2018)
+    pub fn sample_visible_normal(&self, wo: &Vec3, rng: &mut XorShiftRng) -> Vec3 {
+        // Stretch view direction to the hemisphere configuration
+        let vh = Vec3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalized();
+
+        // Orthonormal basis around vh
+        let lensq = vh.x*vh.x + vh.y*vh.y;
+        let t1 = if lensq > 0.0 { Vec3::new(-vh.y, vh.x, 0.0) / lensq.sqrt() } else { Vec3::new(1.0, 0.0, 0.0) };
+        let t2 = vh.cross(t1);
+
+        // Sample the projected area of the visible hemisphere
+        let r = rng.gen::<f64>().sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        let p1 = r * phi.cos();
+        let p2 = r * phi.sin();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1*p1).sqrt() + s * p2;
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1*p1 - p2*p2).max(0.0).sqrt();
+
+        // Unstretch
+        Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalized()
+    }
+
+    // Reflection without Fresnel, D * G2 / (4 cos_o cos_i), zero when smooth as the reflection
+    // is then a single direction
+    pub fn reflection(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        if self.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (*wo + *wi).normalized();
+        self.d(&wm) * self.g2(wo, wi) / (4.0 * wo.z * wi.z)
+    }
+
+    // Density of sample_visible_normal returning wm
+    pub fn visible_normal_pdf(&self, wo: &Vec3, wm: &Vec3) -> f64 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(*wm).max(0.0) * self.d(wm) / wo.z
+    }
+
+    // Density of reflecting wo about a visible normal into wi, 0 when smooth
+    pub fn reflection_pdf(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        if self.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (*wo + *wi).normalized();
+        self.visible_normal_pdf(wo, &wm) / (4.0 * wo.dot(wm))
+    }
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction eta + ik, per channel
+pub fn fresnel_conductor(cos_i: f64, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos_i = cos_i.max(0.0).min(1.0);
+    let per_channel = |eta: f64, k: f64| {
+        let cos2 = cos_i * cos_i;
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+
+        0.5 * (rp + rs)
+    };
+    Vec3::new(per_channel(eta.x, k.x), per_channel(eta.y, k.y), per_channel(eta.z, k.z))
+}
+
+// Fresnel reflectance of a dielectric interface, eta is the index beyond over the index before
+// 1 under total internal reflection
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.max(0.0).min(1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+// Refract wo through the microfacet normal wm (both on the same side), eta as above
+// None under total internal reflection
+pub fn refract_across(wo: &Vec3, wm: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = wo.dot(*wm);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*wo / eta + *wm * (cos_i / eta - cos_t))
+}
+
+// Schlick's approximation with a colored reflectance at normal incidence
+pub fn fresnel_schlick(cos_i: f64, f0: Vec3) -> Vec3 {
+    f0 + (Vec3::new(1.0, 1.0, 1.0) - f0) * (1.0 - cos_i.max(0.0).min(1.0)).powf(5.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::approx_float_eq;
+
+    #[test]
+    fn test_fresnel_conductor_normal_incidence() {
+        let (eta, k) = (0.2, 3.9);
+        let expected = ((eta - 1.0) * (eta - 1.0) + k * k) / ((eta + 1.0) * (eta + 1.0) + k * k);
+        let f = fresnel_conductor(1.0, Vec3::new(eta, eta, eta), Vec3::new(k, k, k));
+        assert!(approx_float_eq(f.x, expected));
+    }
+
+    #[test]
+    fn test_fresnel_conductor_grazing() {
+        let f = fresnel_conductor(0.0, Vec3::new(1.5, 1.5, 1.5), Vec3::new(2.0, 2.0, 2.0));
+        assert!(approx_float_eq(f.x, 1.0));
+    }
+
+    #[test]
+    fn test_fresnel_dielectric() {
+        assert!(approx_float_eq(fresnel_dielectric(1.0, 1.5), 0.04));
+        // Total internal reflection leaving glass at a grazing angle
+        assert!(fresnel_dielectric(0.1, 1.0 / 1.5) == 1.0);
+    }
+
+    #[test]
+    fn test_refract_across() {
+        // Snell's law: sin_i = eta * sin_t
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let wi = refract_across(&wo, &Vec3::new(0.0, 0.0, 1.0), 1.5).unwrap();
+        assert!(approx_float_eq(wi.length(), 1.0));
+        assert!(approx_float_eq(0.6, 1.5 * -wi.x));
+        assert!(wi.z < 0.0);
+        assert!(refract_across(&wo, &Vec3::new(0.0, 0.0, 1.0), 0.5).is_none());
+    }
+
+    #[test]
+    fn test_visible_normals_face_viewer() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let ggx = Ggx::new(0.8, 0.3);
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        for _ in 0..1000 {
+            let wm = ggx.sample_visible_normal(&wo, &mut rng);
+            assert!(wm.z > 0.0);
+            assert!(wo.dot(wm) >= -1e-9);
+            assert!(approx_float_eq(wm.length(), 1.0));
+        }
+    }
+
+    #[test]
+    fn test_d_normalized() {
+        // Projected area of microfacets integrates to 1 over the hemisphere
+        let ggx = Ggx::new(0.5, 0.7);
+        let n = 400;
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let cos_theta = (i as f64 + 0.5) / n as f64;
+                let phi = 2.0 * PI * (j as f64 + 0.5) / n as f64;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let wm = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                sum += ggx.d(&wm) * cos_theta * (1.0 / n as f64) * (2.0 * PI / n as f64);
+            }
+        }
+        assert!((sum - 1.0).abs() < 0.01);
+    }
+}
@@ -1,2 +1,8 @@
+pub mod layered;
 pub mod material;
+pub mod microfacet;
+pub mod normal_map;
+pub mod phase;
+pub mod principled;
+pub mod subsurface;
 pub mod texture;
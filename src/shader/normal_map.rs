@@ -0,0 +1,210 @@
+// Materials perturbing the shading normal of another material
+//
+// The wrapped material shades with the perturbed normal, built in the hit's tangent frame (see
+// HitRecord::dpdu). The geometric normal is still used to push scattered rays off the surface to
+// the side they leave from, as a perturbed normal can send a ray below the real surface.
+//
+// Hitables without tangents (dpdu of zero) get an arbitrary frame, fine for bump maps of noise but
+// normal maps will be rotated.
+
+use medium::Interior;
+use model::hitable::HitRecord;
+use ray::Ray;
+use shader::material::Material;
+use shader::texture::Texture;
+use vec3::Vec3;
+use rand::*;
+
+// Distance scattered rays are pushed off the surface, relative to the hit's distance from origin
+const OFFSET: f64 = 1e-7;
+
+// Step in u and v for finite differences of the height texture
+const BUMP_DELTA: f64 = 0.0005;
+
+// Tangent space normal map, colors map [0,1] to [-1,1] with x along u, y along v, z along the normal
+// strength scales the tangential part, 0 is the unperturbed normal
+#[derive(Debug, Clone)]
+pub struct NormalMap<M: Material, T: Texture> {
+    pub material: M,
+    pub normals: T,
+    pub strength: f64,
+}
+
+// Height field displacing the surface along the normal for shading only, read from the first
+// channel and multiplied by scale in world units
+#[derive(Debug, Clone)]
+pub struct BumpMap<M: Material, T: Texture> {
+    pub material: M,
+    pub height: T,
+    pub scale: f64,
+}
+
+impl<M: Material, T: Texture> NormalMap<M, T> {
+    fn shading_normal(&self, hit: &HitRecord) -> Vec3 {
+        let n = hit.normal.normalized();
+        let frame = hit.tangent_frame(n);
+        // Bitangent on the side of increasing v
+        let v = if frame.v.dot(hit.dpdv) < 0.0 { -frame.v } else { frame.v };
+
+        let c = self.normals.value(hit.u, hit.v, &hit.p) * 2.0 - 1.0;
+        let local = Vec3::new(c.x * self.strength, c.y * self.strength, c.z.max(0.0));
+        if local.squared_length() == 0.0 {
+            return n;
+        }
+        let local = local.normalized();
+        (frame.u * local.x + v * local.y + n * local.z).normalized()
+    }
+}
+
+impl<M: Material, T: Texture> BumpMap<M, T> {
+    fn shading_normal(&self, hit: &HitRecord) -> Vec3 {
+        let n = hit.normal.normalized();
+        if hit.dpdu.cross(hit.dpdv).squared_length() == 0.0 {
+            return n;
+        }
+
+        let height = |u: f64, v: f64, p: &Vec3| self.height.value(u, v, p).x * self.scale;
+        let h = height(hit.u, hit.v, &hit.p);
+        let dhdu = (height(hit.u + BUMP_DELTA, hit.v, &(hit.p + hit.dpdu * BUMP_DELTA)) - h) / BUMP_DELTA;
+        let dhdv = (height(hit.u, hit.v + BUMP_DELTA, &(hit.p + hit.dpdv * BUMP_DELTA)) - h) / BUMP_DELTA;
+
+        // Tangents of the displaced surface p + h * n, ignoring the change of n itself
+        let dpdu = hit.dpdu + n * dhdu;
+        let dpdv = hit.dpdv + n * dhdv;
+        let bumped = dpdu.cross(dpdv).normalized();
+        if bumped.dot(n) < 0.0 { -bumped } else { bumped }
+    }
+}
+
+// Scatter with the shading normal, keeping the geometric normal for offsetting the ray
+fn scatter_with_normal(material: &Material, r: &Ray, hit: &HitRecord, shading_normal: Vec3, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+    let shading = HitRecord { normal: shading_normal, .. hit.clone() };
+    material.scatter(r, &shading, rng).map(|(attenuation, mut scattered)| {
+        let n = hit.normal.normalized();
+        let side = if scattered.dir.dot(n) >= 0.0 { 1.0 } else { -1.0 };
+        scattered.origin = scattered.origin + n * (side * OFFSET * (1.0 + hit.p.length()));
+        (attenuation, scattered)
+    })
+}
+
+impl<M: Material, T: Texture> Material for NormalMap<M, T> {
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        scatter_with_normal(&self.material, r, hit, self.shading_normal(hit), rng)
+    }
+
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        self.material.emitted(r, hit)
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.eval(r, &shading, wi)
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.pdf(r, &shading, wi)
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.is_specular(r, &shading, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.material.albedo(hit)
+    }
+
+    fn interior(&self) -> Option<&Interior> {
+        self.material.interior()
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+}
+
+impl<M: Material, T: Texture> Material for BumpMap<M, T> {
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        scatter_with_normal(&self.material, r, hit, self.shading_normal(hit), rng)
+    }
+
+    fn emitted(&self, r: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        self.material.emitted(r, hit)
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.eval(r, &shading, wi)
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.pdf(r, &shading, wi)
+    }
+
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let shading = HitRecord { normal: self.shading_normal(hit), .. hit.clone() };
+        self.material.is_specular(r, &shading, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.material.albedo(hit)
+    }
+
+    fn interior(&self) -> Option<&Interior> {
+        self.material.interior()
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::hit_xz_plane;
+    use shader::texture;
+
+    #[test]
+    fn test_flat_normal_map_keeps_normal() {
+        let m = Material::normal_map(Material::metal(Vec3::new(1.0,1.0,1.0), 0.0), texture::constant_texture(Vec3::new(0.5,0.5,1.0)), 1.0);
+        assert!(Vec3::approx_float_eq(&m.shading_normal(&hit_xz_plane()), &Vec3::new(0.0,1.0,0.0)));
+    }
+
+    #[test]
+    fn test_normal_map_tilts_towards_u() {
+        // Tangent space x is along dp/du
+        let m = Material::normal_map(Material::metal(Vec3::new(1.0,1.0,1.0), 0.0), texture::constant_texture(Vec3::new(1.0,0.5,1.0)), 1.0);
+        let n = m.shading_normal(&hit_xz_plane());
+        assert!(Vec3::approx_float_eq(&n, &Vec3::new(1.0,1.0,0.0).normalized()));
+    }
+
+    #[derive(Debug, Clone)]
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+            Vec3::new(u, u, u)
+        }
+    }
+
+    #[test]
+    fn test_bump_slope() {
+        // Height rising by 1 per unit u leans the normal back by 45 degrees
+        let m = Material::bump_map(Material::metal(Vec3::new(1.0,1.0,1.0), 0.0), Ramp, 1.0);
+        let n = m.shading_normal(&hit_xz_plane());
+        assert!(Vec3::approx_float_eq(&n, &Vec3::new(-1.0,1.0,0.0).normalized()));
+    }
+
+    #[test]
+    fn test_scattered_ray_offset_to_its_side() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::normal_map(Material::metal(Vec3::new(1.0,1.0,1.0), 0.0), texture::constant_texture(Vec3::new(1.0,0.5,0.6)), 1.0);
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        if let Some((_, scattered)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+            assert!((scattered.origin.y > 0.0) == (scattered.dir.y >= 0.0));
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Phase functions: how light scatters inside a medium
+//
+// Functions of the angle between the direction light travelled in and the direction it leaves in,
+// normalized over the sphere. Each samples exactly in proportion to its value, so a sampled
+// direction's weight is the medium's albedo alone.
+
+use vec3::{Vec3, Onb};
+use rand::*;
+
+use std::f64;
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Isotropic,
+    // g in (-1, 1) is the average cosine, positive scatters forward, e.g. 0.85 for clouds
+    HenyeyGreenstein(f64),
+    // weight of the first lobe, e.g. a forward and a backward lobe for haze
+    DoubleHenyeyGreenstein { g1: f64, g2: f64, weight: f64 },
+    // Small particles like air molecules, as much backward as forward
+    Rayleigh,
+}
+
+impl Phase {
+    // Per steradian, cos_theta between the directions light travels in before and after
+    pub fn eval(&self, cos_theta: f64) -> f64 {
+        match *self {
+            Phase::Isotropic => 1.0 / (4.0 * PI),
+            Phase::HenyeyGreenstein(g) => henyey_greenstein(g, cos_theta),
+            Phase::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                weight * henyey_greenstein(g1, cos_theta) + (1.0 - weight) * henyey_greenstein(g2, cos_theta)
+            }
+            Phase::Rayleigh => 3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta),
+        }
+    }
+
+    // A unit direction to leave in for light travelling along dir, with pdf eval
+    pub fn sample(&self, dir: Vec3, rng: &mut XorShiftRng) -> Vec3 {
+        let cos_theta = match *self {
+            Phase::Isotropic => 1.0 - 2.0 * rng.gen::<f64>(),
+            Phase::HenyeyGreenstein(g) => sample_henyey_greenstein(g, rng.gen::<f64>()),
+            Phase::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                let g = if rng.gen::<f64>() < weight { g1 } else { g2 };
+                sample_henyey_greenstein(g, rng.gen::<f64>())
+            }
+            Phase::Rayleigh => {
+                // Inverse of the cdf (mu^3 + 3 mu + 4) / 8 by Cardano's formula
+                let q = 8.0 * rng.gen::<f64>() - 4.0;
+                let a = (0.5 * q + (0.25 * q * q + 1.0).sqrt()).cbrt();
+                a - 1.0 / a
+            }
+        };
+        let cos_theta = cos_theta.max(-1.0).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<f64>();
+        Onb::from_w(dir.normalized()).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+    }
+
+    // For light arriving at a point from unit direction wi (pointing away from the point) and
+    // leaving along -r_dir, i.e. back towards the origin of a ray with direction r_dir
+    pub fn eval_directions(&self, r_dir: Vec3, wi: Vec3) -> f64 {
+        self.eval(r_dir.normalized().dot(wi))
+    }
+}
+
+fn henyey_greenstein(g: f64, cos_theta: f64) -> f64 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+fn sample_henyey_greenstein(g: f64, u: f64) -> f64 {
+    if g.abs() < 1e-3 {
+        return 1.0 - 2.0 * u;
+    }
+    let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+    (1.0 + g * g - s * s) / (2.0 * g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases() -> Vec<Phase> {
+        vec![
+            Phase::Isotropic,
+            Phase::HenyeyGreenstein(0.7),
+            Phase::HenyeyGreenstein(-0.4),
+            Phase::DoubleHenyeyGreenstein { g1: 0.8, g2: -0.3, weight: 0.7 },
+            Phase::Rayleigh,
+        ]
+    }
+
+    #[test]
+    fn test_normalized() {
+        let steps = 10000;
+        for phase in phases() {
+            let mut sum = 0.0;
+            for i in 0..steps {
+                let cos_theta = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+                sum += phase.eval(cos_theta) * 2.0 * PI * (2.0 / steps as f64);
+            }
+            assert!((sum - 1.0).abs() < 1e-3, "{:?} integrates to {}", phase, sum);
+        }
+    }
+
+    #[test]
+    fn test_sampling_matches_eval() {
+        // Fraction of samples in bands of cos theta against the integral of eval over them
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let dir = Vec3::new(0.3,-1.0,0.2).normalized();
+        let bands = 8;
+        let n = 100000;
+        for phase in phases() {
+            let mut counts = vec![0; bands];
+            let mut mean_cos = 0.0;
+            for _ in 0..n {
+                let wi = phase.sample(dir, &mut rng);
+                assert!((wi.length() - 1.0).abs() < 1e-9);
+                let cos_theta = wi.dot(dir);
+                mean_cos += cos_theta / n as f64;
+                counts[(((cos_theta + 1.0) / 2.0 * bands as f64) as usize).min(bands - 1)] += 1;
+            }
+            for b in 0..bands {
+                let steps = 100;
+                let mut expected = 0.0;
+                for i in 0..steps {
+                    let cos_theta = -1.0 + 2.0 * (b as f64 + (i as f64 + 0.5) / steps as f64) / bands as f64;
+                    expected += phase.eval(cos_theta) * 2.0 * PI * (2.0 / bands as f64 / steps as f64);
+                }
+                assert!((counts[b] as f64 / n as f64 - expected).abs() < 0.005, "{:?} band {}", phase, b);
+            }
+            if let Phase::HenyeyGreenstein(g) = phase {
+                assert!((mean_cos - g).abs() < 0.01);
+            }
+        }
+    }
+}
@@ -0,0 +1,353 @@
+// Principled material, a Disney style uber shader
+//
+// Artist friendly parameters instead of separate Lambertian, Conductor and Dielectric structs.
+// Every parameter is a texture, scalar parameters read its first channel.
+//
+// The surface is rough glass, weighted by (1 - metallic) * transmission, mixed with an opaque layer
+// of Burley diffuse and sheen, GGX specular tinted by the base color as it becomes metallic, and a
+// clearcoat on top. A lobe of the opaque layer is picked by its rough contribution, and the sample
+// is weighted by the combined pdf of every lobe (one sample MIS), so a lobe that is rarely picked
+// does not cause fireflies where another lobe is bright.
+
+use model::hitable::HitRecord;
+use ray::Ray;
+use shader::material::{Material, reflect};
+use shader::microfacet::{Ggx, fresnel_schlick};
+use shader::texture::{self, BoxedTexture, Texture};
+use vec3::{Vec3, Onb, random_cosine_direction};
+use rand::*;
+
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrincipledParam {
+    BaseColor,
+    Metallic,
+    Roughness,
+    Specular,
+    Sheen,
+    Clearcoat,
+    ClearcoatRoughness,
+    Transmission,
+}
+
+#[derive(Debug, Clone)]
+pub struct Principled {
+    pub base_color: BoxedTexture,
+    pub metallic: BoxedTexture,
+    pub roughness: BoxedTexture,
+    pub specular: BoxedTexture,            // dielectric reflectance, 0.5 is 4% at normal incidence
+    pub sheen: BoxedTexture,               // grazing highlight for cloth
+    pub clearcoat: BoxedTexture,
+    pub clearcoat_roughness: BoxedTexture,
+    pub transmission: BoxedTexture,
+    pub ior: f64,                          // index of refraction of the transmissive part
+}
+
+// Opaque layer lobes at a hit
+struct Lobes {
+    base_color: Vec3,
+    roughness: f64,
+    diffuse: f64,  // weight of diffuse and sheen
+    sheen: Vec3,
+    f0: Vec3,      // specular reflectance at normal incidence
+    specular: Ggx,
+    clearcoat: f64,
+    coat: Ggx,
+}
+
+impl Principled {
+    pub fn with_metallic(self, metallic: f64) -> Principled {
+        self.with_texture(PrincipledParam::Metallic, scalar(metallic))
+    }
+
+    pub fn with_roughness(self, roughness: f64) -> Principled {
+        self.with_texture(PrincipledParam::Roughness, scalar(roughness))
+    }
+
+    pub fn with_specular(self, specular: f64) -> Principled {
+        self.with_texture(PrincipledParam::Specular, scalar(specular))
+    }
+
+    pub fn with_sheen(self, sheen: f64) -> Principled {
+        self.with_texture(PrincipledParam::Sheen, scalar(sheen))
+    }
+
+    pub fn with_clearcoat(self, clearcoat: f64, roughness: f64) -> Principled {
+        self.with_texture(PrincipledParam::Clearcoat, scalar(clearcoat))
+            .with_texture(PrincipledParam::ClearcoatRoughness, scalar(roughness))
+    }
+
+    pub fn with_transmission(mut self, transmission: f64, ior: f64) -> Principled {
+        self.ior = ior;
+        self.with_texture(PrincipledParam::Transmission, scalar(transmission))
+    }
+
+    // Drive a parameter with any texture, e.g. a roughness map
+    pub fn with_texture<T: Texture + Send + Sync + 'static>(mut self, param: PrincipledParam, t: T) -> Principled {
+        let t = texture::boxed_texture(t);
+        match param {
+            PrincipledParam::BaseColor => self.base_color = t,
+            PrincipledParam::Metallic => self.metallic = t,
+            PrincipledParam::Roughness => self.roughness = t,
+            PrincipledParam::Specular => self.specular = t,
+            PrincipledParam::Sheen => self.sheen = t,
+            PrincipledParam::Clearcoat => self.clearcoat = t,
+            PrincipledParam::ClearcoatRoughness => self.clearcoat_roughness = t,
+            PrincipledParam::Transmission => self.transmission = t,
+        }
+        self
+    }
+
+    fn scalar_at(t: &BoxedTexture, hit: &HitRecord) -> f64 {
+        t.value(hit.u, hit.v, &hit.p).x.max(0.0).min(1.0)
+    }
+
+    // Probability of the rough glass, and the opaque layer's lobes
+    fn lobes(&self, hit: &HitRecord) -> (f64, Lobes) {
+        let base_color = self.base_color.value(hit.u, hit.v, &hit.p);
+        let metallic = Principled::scalar_at(&self.metallic, hit);
+        let roughness = Principled::scalar_at(&self.roughness, hit);
+        let transmission = Principled::scalar_at(&self.transmission, hit);
+        let glass = (1.0 - metallic) * transmission;
+
+        // The glass takes the non metallic part, so the opaque layer is relatively more metallic
+        let metallic = if glass < 1.0 { metallic / (1.0 - glass) } else { 1.0 };
+
+        // Sheen is tinted halfway towards the hue of the base color
+        let l = base_color.luminance();
+        let tint = if l > 0.0 { base_color / l } else { Vec3::new(1.0,1.0,1.0) };
+        let sheen_color = (Vec3::new(1.0,1.0,1.0) + tint) * 0.5;
+
+        let dielectric_f0 = 0.08 * Principled::scalar_at(&self.specular, hit);
+        let lobes = Lobes {
+            base_color: base_color,
+            roughness: roughness,
+            diffuse: 1.0 - metallic,
+            sheen: sheen_color * Principled::scalar_at(&self.sheen, hit),
+            f0: Vec3::new(dielectric_f0, dielectric_f0, dielectric_f0) * (1.0 - metallic) + base_color * metallic,
+            specular: Ggx::isotropic(roughness),
+            clearcoat: Principled::scalar_at(&self.clearcoat, hit),
+            coat: Ggx::isotropic(Principled::scalar_at(&self.clearcoat_roughness, hit)),
+        };
+        (glass, lobes)
+    }
+}
+
+impl Lobes {
+    // Probability of sampling the diffuse, specular and clearcoat lobes, None if all are black
+    fn probabilities(&self, wo: &Vec3) -> Option<(f64, f64, f64)> {
+        let diffuse = self.diffuse * (self.base_color.luminance() + self.sheen.luminance());
+        let specular = fresnel_schlick(wo.z, self.f0).luminance();
+        let clearcoat = self.clearcoat * fresnel_schlick(wo.z, COAT_F0).x;
+        let total = diffuse + specular + clearcoat;
+        if total > 0.0 {
+            Some((diffuse / total, specular / total, clearcoat / total))
+        } else {
+            None
+        }
+    }
+
+    // BSDF, wo and wi above the surface in the local frame
+    fn eval(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let wh = (*wo + *wi).normalized();
+        let cos_d = wi.dot(wh);
+
+        // Burley diffuse, retroreflective when rough
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fresnel_weight = |cos: f64| 1.0 + (fd90 - 1.0) * (1.0 - cos).powi(5);
+        let diffuse = self.base_color * (fresnel_weight(wo.z) * fresnel_weight(wi.z) / PI);
+        let sheen = self.sheen * (1.0 - cos_d).powi(5);
+
+        let microfacet = |distribution: &Ggx| {
+            distribution.d(&wh) * distribution.g2(wo, wi) / (4.0 * wo.z * wi.z)
+        };
+        let specular = fresnel_schlick(wo.dot(wh), self.f0) * microfacet(&self.specular);
+        let clearcoat = fresnel_schlick(wo.dot(wh), COAT_F0) * (self.clearcoat * microfacet(&self.coat));
+
+        (diffuse + sheen) * self.diffuse + specular + clearcoat
+    }
+
+    // Density of sampling wi with the given lobe probabilities
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, p: (f64, f64, f64)) -> f64 {
+        let wh = (*wo + *wi).normalized();
+        let reflected = |distribution: &Ggx| distribution.visible_normal_pdf(wo, &wh) / (4.0 * wo.dot(wh));
+        p.0 * wi.z / PI + p.1 * reflected(&self.specular) + p.2 * reflected(&self.coat)
+    }
+}
+
+// Polyurethane like clearcoat, index of refraction 1.5
+const COAT_F0: Vec3 = Vec3 { x: 0.04, y: 0.04, z: 0.04 };
+
+fn scalar(x: f64) -> texture::ConstantTexture {
+    texture::constant_texture(Vec3::new(x, x, x))
+}
+
+impl Material for Principled {
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let (glass, lobes) = self.lobes(hit);
+
+        // Rough glass, its weight cancels with the probability of picking it
+        if rng.gen::<f64>() < glass {
+            return Material::rough_dielectric(self.ior, lobes.roughness).scatter(r, hit, rng).map(|(attenuation, scattered)| {
+                // Tinted by the square root of base color for each crossing, so light through a
+                // closed object is tinted by base color
+                let transmitted = scattered.dir.dot(hit.normal) * r.dir.dot(hit.normal) > 0.0;
+                if transmitted {
+                    (attenuation * lobes.base_color.map(&|x| x.max(0.0).sqrt()), scattered)
+                } else {
+                    (attenuation, scattered)
+                }
+            });
+        }
+
+        // Shade the side of the surface the ray came from
+        let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+        let frame = Onb::from_w(n.normalized());
+        let wo = frame.to_local(-r.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let p = match lobes.probabilities(&wo) {
+            Some(p) => p,
+            None => return None,
+        };
+        let u = rng.gen::<f64>();
+        let wi = if u < p.0 {
+            random_cosine_direction(rng)
+        } else if u < p.0 + p.1 {
+            reflect(-wo, lobes.specular.sample_visible_normal(&wo, rng))
+        } else {
+            reflect(-wo, lobes.coat.sample_visible_normal(&wo, rng))
+        };
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = lobes.pdf(&wo, &wi, p);
+        if !(pdf > 0.0) {
+            return None;
+        }
+        Some((lobes.eval(&wo, &wi) * (wi.z / pdf), Ray::new(hit.p, frame.to_world(wi))))
+    }
+
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
+        None
+    }
+
+    fn eval(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> Vec3 {
+        let (glass, lobes) = self.lobes(hit);
+        let mut f = Vec3::new(0.0,0.0,0.0);
+
+        if glass > 0.0 {
+            let transmitted = wi.dot(hit.normal) * r.dir.dot(hit.normal) > 0.0;
+            let g = Material::rough_dielectric(self.ior, lobes.roughness).eval(r, hit, wi);
+            let g = if transmitted { g * lobes.base_color.map(&|x| x.max(0.0).sqrt()) } else { g };
+            f = f + g * glass;
+        }
+
+        if glass < 1.0 {
+            let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+            let frame = Onb::from_w(n.normalized());
+            let wo = frame.to_local(-r.dir.normalized());
+            let wi = frame.to_local(wi);
+            if wo.z > 0.0 && wi.z > 0.0 {
+                f = f + lobes.eval(&wo, &wi) * (wi.z * (1.0 - glass));
+            }
+        }
+        f
+    }
+
+    fn pdf(&self, r: &Ray, hit: &HitRecord, wi: Vec3) -> f64 {
+        let (glass, lobes) = self.lobes(hit);
+        let mut pdf = 0.0;
+        if glass > 0.0 {
+            pdf += glass * Material::rough_dielectric(self.ior, lobes.roughness).pdf(r, hit, wi);
+        }
+        if glass < 1.0 {
+            let n = if r.dir.dot(hit.normal) > 0.0 { -hit.normal } else { hit.normal };
+            let frame = Onb::from_w(n.normalized());
+            let wo = frame.to_local(-r.dir.normalized());
+            let wi = frame.to_local(wi);
+            if wo.z > 0.0 && wi.z > 0.0 {
+                if let Some(p) = lobes.probabilities(&wo) {
+                    pdf += (1.0 - glass) * lobes.pdf(&wo, &wi, p);
+                }
+            }
+        }
+        pdf
+    }
+
+    // Only the glass can be perfectly smooth, the opaque lobes have a minimum roughness
+    fn is_specular(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> bool {
+        let (glass, lobes) = self.lobes(hit);
+        glass > 0.0 && Material::rough_dielectric(self.ior, lobes.roughness).is_specular(r, hit, scattered)
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.base_color.value(hit.u, hit.v, &hit.p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hitable::hit_xz_plane;
+
+    // Average scatter weight of rays arriving at 45 degrees
+    fn mean_weight(m: &Principled, n: i32) -> Vec3 {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let r = Ray::new(Vec3::new(-1.0,1.0,0.0), Vec3::new(1.0,-1.0,0.0));
+        let mut sum = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..n {
+            if let Some((attenuation, _)) = m.scatter(&r, &hit_xz_plane(), &mut rng) {
+                sum += attenuation;
+            }
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn test_metal_matches_ggx_albedo() {
+        // Single scattering albedo of GGX with alpha 0.25 at 45 degrees, as for Conductor
+        let m = Material::principled_constant(Vec3::new(1.0,1.0,1.0)).with_metallic(1.0).with_roughness(0.5);
+        let e = mean_weight(&m, 100000);
+        assert!(e.x > 0.85 && e.x < 0.92);
+    }
+
+    #[test]
+    fn test_sampling_matches_bsdf() {
+        // Mean of f * cos / pdf over the mixture of lobes equals integrating f * cos directly
+        let m = Material::principled_constant(Vec3::new(0.6,0.4,0.2))
+            .with_roughness(0.6)
+            .with_sheen(0.5)
+            .with_clearcoat(1.0, 0.3);
+        let (_, lobes) = m.lobes(&hit_xz_plane());
+        let wo = Vec3::new(0.5f64.sqrt(), 0.0, 0.5f64.sqrt());
+
+        let n = 400;
+        let mut expected = Vec3::new(0.0,0.0,0.0);
+        for i in 0..n {
+            for j in 0..n {
+                let cos_theta = (i as f64 + 0.5) / n as f64;
+                let phi = 2.0 * PI * (j as f64 + 0.5) / n as f64;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let wi = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                expected += lobes.eval(&wo, &wi) * (cos_theta * (1.0 / n as f64) * (2.0 * PI / n as f64));
+            }
+        }
+
+        let e = mean_weight(&m, 200000);
+        assert!((e - expected).length() < 0.01);
+    }
+
+    #[test]
+    fn test_full_transmission_refracts() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::principled_constant(Vec3::new(1.0,1.0,1.0)).with_transmission(1.0, 1.5).with_roughness(0.0);
+        let r = Ray::new(Vec3::new(0.0,1.0,0.0), Vec3::new(0.0,-1.0,0.0));
+        let through = (0..1000).filter(|_| m.scatter(&r, &hit_xz_plane(), &mut rng).unwrap().1.dir.y < 0.0).count();
+        assert!(through > 900);
+    }
+}
@@ -0,0 +1,276 @@
+// Subsurface scattering by a random walk inside the material's boundary
+//
+// Light refracts in through a dielectric surface, then scatters isotropically through a
+// homogeneous medium until it refracts back out, all within one call to scatter. The walk needs to
+// find where it leaves, so the material holds the boundary itself: share the model's geometry with
+// an Arc, e.g.
+//
+//     let sphere = Arc::new(Sphere::new(...));
+//     Model::new(sphere.clone(), Material::subsurface(sphere, marble, Vec3::new(8.0,5.0,3.0)))
+//
+// The boundary has to be closed, and nothing else is seen from inside it.
+
+use model::hitable::{HitRecord, Hitable};
+use ray::Ray;
+use shader::material::{Material, reflect};
+use shader::microfacet::{Ggx, fresnel_dielectric, refract_across};
+use shader::texture::Texture;
+use vec3::{Vec3, Onb, random_in_unit_sphere};
+use rand::*;
+use std::f64;
+
+// Walks still inside after this many scattering events are dropped
+const MAX_STEPS: i32 = 1024;
+
+// Ignore hits with the boundary closer than this to where the walk is, as render's MIN_DISTANCE
+const MIN_DISTANCE: f64 = 1e-6;
+
+// albedo is the color of a thick slab (multiple scattering), read once where the walk enters so
+// image textures get the u, v of a point on the surface, solid textures like PerlinNoise color the
+// surface, not the inside. mean_free_path is the average distance between scattering events per
+// channel in world units, longer for red makes skin and wax glow red
+#[derive(Debug, Clone)]
+pub struct Subsurface<H: Hitable, T: Texture> {
+    pub boundary: H,
+    pub albedo: T,
+    pub mean_free_path: Vec3,
+    pub ior: f64,
+    pub distribution: Ggx,
+}
+
+impl<H: Hitable, T: Texture> Subsurface<H, T> {
+    pub fn with_ior(mut self, ior: f64) -> Subsurface<H, T> {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Subsurface<H, T> {
+        self.distribution = Ggx::isotropic(roughness);
+        self
+    }
+
+    // Single scattering albedo giving the multiple scattering albedo a, inverting van de Hulst's
+    // fit for a semi-infinite medium as in Cycles
+    fn single_scattering_albedo(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.albedo.value(u, v, p).map(&|a| {
+            let a = a.max(0.0).min(0.999);
+            let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            1.0 - s * s
+        })
+    }
+
+    // Sample a microfacet normal on the side of wo (local to the interface) and reflect or
+    // refract through it by Fresnel. The weight is G2 / G1, 1 when smooth
+    fn interface(&self, wo: &Vec3, eta: f64, rng: &mut XorShiftRng) -> Option<(Vec3, f64)> {
+        let smooth = self.distribution.is_smooth();
+        let wm = if smooth {
+            Vec3::new(0.0,0.0,1.0)
+        } else {
+            self.distribution.sample_visible_normal(wo, rng)
+        };
+
+        let wi = if rng.gen::<f64>() < fresnel_dielectric(wo.dot(wm), eta) {
+            let wi = reflect(-*wo, wm);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            let wi = refract_across(wo, &wm, eta).unwrap();
+            if wi.z >= 0.0 {
+                return None;
+            }
+            wi
+        };
+
+        let weight = if smooth { 1.0 } else { self.distribution.g2(wo, &wi) / self.distribution.g1(wo) };
+        Some((wi, weight))
+    }
+}
+
+impl<H: Hitable, T: Texture> Material for Subsurface<H, T> {
+    // Distances are sampled from one channel's extinction, weighted by the pdf averaged over the
+    // channels (spectral MIS) so the walk is unbiased for all three. Channels are picked in
+    // proportion to their throughput so far, otherwise a channel with a short path picks up huge
+    // and tiny weights in turn over a long walk
+    fn scatter(&self, r: &Ray, hit: &HitRecord, rng: &mut XorShiftRng) -> Option<(Vec3, Ray)> {
+        let sigma_t = self.mean_free_path.map(&|l| 1.0 / l.max(1e-9));
+        let albedo = self.single_scattering_albedo(hit.u, hit.v, &hit.p);
+        let mut throughput = Vec3::new(1.0,1.0,1.0);
+
+        // Where the walk starts and which way it goes
+        let (mut x, mut w) = if r.dir.dot(hit.normal) < 0.0 {
+            let frame = Onb::from_w(hit.normal.normalized());
+            let wo = frame.to_local(-r.dir.normalized());
+            let (wi, weight) = match self.interface(&wo, self.ior, rng) {
+                Some(s) => s,
+                None => return None,
+            };
+            if wi.z > 0.0 {
+                // Return REFLECT off the surface
+                return Some((Vec3::new(weight, weight, weight), Ray::new(hit.p, frame.to_world(wi))));
+            }
+            throughput = throughput * weight;
+            (hit.p, frame.to_world(wi))
+        } else {
+            // Ray started inside, e.g. the camera is in the medium
+            (r.origin, r.dir.normalized())
+        };
+
+        for _ in 0..MAX_STEPS {
+            let exit = match self.boundary.hit(&Ray::new(x, w), MIN_DISTANCE, f64::INFINITY) {
+                Some(h) => h,
+                // Leaked through an open or numerically missed boundary
+                None => return None,
+            };
+
+            let total = throughput.x + throughput.y + throughput.z;
+            if total <= 0.0 {
+                return None;
+            }
+            let pick = throughput / total;
+            let xi = rng.gen::<f64>();
+            let channel = if xi < pick.x {
+                sigma_t.x
+            } else if xi < pick.x + pick.y {
+                sigma_t.y
+            } else {
+                sigma_t.z
+            };
+            let distance = -(1.0 - rng.gen::<f64>()).ln() / channel;
+
+            if distance < exit.t {
+                // Scatter inside
+                x = x + w * distance;
+                let transmittance = sigma_t.map(&|s| (-s * distance).exp());
+                let pdf = pick.dot(sigma_t * transmittance);
+                throughput = throughput * albedo * sigma_t * transmittance / pdf;
+                w = random_in_unit_sphere(rng).normalized();
+                continue;
+            }
+
+            let transmittance = sigma_t.map(&|s| (-s * exit.t).exp());
+            let probability = pick.dot(transmittance);
+            throughput = throughput * transmittance / probability;
+
+            // At the boundary from inside, leave or reflect back in
+            let n = if w.dot(exit.normal) > 0.0 { -exit.normal } else { exit.normal };
+            let frame = Onb::from_w(n.normalized());
+            let wo = frame.to_local(-w);
+            let (wi, weight) = match self.interface(&wo, 1.0 / self.ior, rng) {
+                Some(s) => s,
+                None => return None,
+            };
+            throughput = throughput * weight;
+            x = exit.p;
+            w = frame.to_world(wi).normalized();
+            if wi.z < 0.0 {
+                return Some((throughput, Ray { origin: x, dir: w, .. *r }));
+            }
+        }
+
+        None
+    }
+
+    fn emitted(&self, _r: &Ray, _hit: &HitRecord) -> Option<Vec3> {
+        None
+    }
+
+    fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        self.albedo.value(hit.u, hit.v, &hit.p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::sphere::Sphere;
+    use shader::texture;
+
+    // Average weight of light scattered by a unit sphere, and whether it all left from the surface
+    fn sphere_albedo(albedo: f64, mean_free_path: f64, ior: f64) -> f64 {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::subsurface(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(albedo, albedo, albedo)), Vec3::new(mean_free_path, mean_free_path, mean_free_path))
+            .with_ior(ior);
+        let r = Ray::new(Vec3::new(0.2,0.3,-5.0), Vec3::new(0.0,0.0,1.0));
+        let hit = Sphere::unit_sphere().hit(&r, 0.0, 10.0).unwrap();
+        let n = 5000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            if let Some((attenuation, scattered)) = m.scatter(&r, &hit, &mut rng) {
+                assert!((scattered.origin.length() - 1.0).abs() < 1e-6);
+                assert!(scattered.dir.dot(scattered.origin) > 0.0);
+                sum += attenuation.x;
+            }
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn test_white_conserves_energy() {
+        // Nothing absorbed, everything that goes in comes back out
+        let e = sphere_albedo(0.999, 0.2, 1.0);
+        assert!(e > 0.95 && e < 1.05);
+    }
+
+    #[test]
+    fn test_black_only_reflects_surface() {
+        let e = sphere_albedo(0.0, 0.2, 1.5);
+        assert!(e < 0.1);
+    }
+
+    #[test]
+    fn test_albedo_of_thick_medium() {
+        // Deep in the diffusion regime the slab albedo should come out roughly as asked
+        let e = sphere_albedo(0.6, 0.02, 1.0);
+        assert!(e > 0.45 && e < 0.75);
+    }
+
+    // White on the side facing -z and black everywhere else, inside included
+    #[derive(Debug, Clone)]
+    struct WhiteFront;
+
+    impl Texture for WhiteFront {
+        fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
+            if p.z < -0.9 { Vec3::new(0.999,0.999,0.999) } else { Vec3::new(0.0,0.0,0.0) }
+        }
+    }
+
+    #[test]
+    fn test_albedo_read_where_the_walk_enters() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::subsurface(Sphere::unit_sphere(), WhiteFront, Vec3::new(0.2,0.2,0.2)).with_ior(1.0);
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+        let hit = Sphere::unit_sphere().hit(&r, 0.0, 10.0).unwrap();
+        let n = 5000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            if let Some((attenuation, _)) = m.scatter(&r, &hit, &mut rng) {
+                sum += attenuation.x;
+            }
+        }
+        // As white as the surface where the light went in
+        let e = sum / n as f64;
+        assert!(e > 0.95 && e < 1.05);
+    }
+
+    #[test]
+    fn test_channels_with_different_paths_conserve_energy() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let m = Material::subsurface(Sphere::unit_sphere(), texture::constant_texture(Vec3::new(0.999,0.999,0.999)), Vec3::new(0.5,0.2,0.05))
+            .with_ior(1.0);
+        let r = Ray::new(Vec3::new(0.0,0.0,-5.0), Vec3::new(0.0,0.0,1.0));
+        let hit = Sphere::unit_sphere().hit(&r, 0.0, 10.0).unwrap();
+        let n = 5000;
+        let mut sum = Vec3::new(0.0,0.0,0.0);
+        for _ in 0..n {
+            if let Some((attenuation, _)) = m.scatter(&r, &hit, &mut rng) {
+                sum = sum + attenuation;
+            }
+        }
+        let e = sum / n as f64;
+        for &c in &[e.x, e.y, e.z] {
+            assert!(c > 0.9 && c < 1.1);
+        }
+    }
+}
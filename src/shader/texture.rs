@@ -1,9 +1,13 @@
 extern crate image;
 extern crate rand;
 
+use model::bvh::AABB;
+use spectrum;
 use vec3::Vec3;
 
 use std::fmt::Debug;
+use std::ops::{Add, Mul};
+use std::sync::Arc;
 use rand::distributions::{IndependentSample, Range};
 use image::Pixel;
 
@@ -38,6 +42,32 @@ pub fn image_texture(path: &str) -> ImageTexture {
     ImageTexture::new(path)
 }
 
+// Image's alpha channel in every channel, e.g. for opacity masks
+pub fn image_alpha_texture(path: &str) -> ImageTexture {
+    ImageTexture::alpha(path)
+}
+
+// Color of each voxel, x fastest then y then z, spread over bounds
+pub fn voxel_grid_texture(resolution: (usize, usize, usize), bounds: AABB, data: Vec<Vec3>) -> VoxelGrid {
+    VoxelGrid::new(resolution, bounds, data)
+}
+
+// Glow of the temperature in kelvin in the first channel of temperature, scale at 1000K
+pub fn blackbody_texture<T: Texture>(temperature: T, scale: f64) -> BlackbodyTexture<T> {
+    let table = (0..BLACKBODY_ENTRIES).map(|i| spectrum::blackbody(i as f64 * BLACKBODY_STEP)).collect();
+    BlackbodyTexture {
+        temperature: temperature,
+        scale: scale,
+        colors: Arc::new(table),
+    }
+}
+
+pub fn boxed_texture<T: Texture + Send + Sync + 'static>(t: T) -> BoxedTexture {
+    BoxedTexture {
+        inner: Arc::new(t),
+    }
+}
+
 //
 // Constant Texture
 //
@@ -229,6 +259,21 @@ impl ImageTexture {
             height: height,
         }
     }
+
+    pub fn alpha(path: &str) -> Self {
+        let img = image::open(&::std::path::Path::new(path))
+          .expect(&format!("Could not open image texture: {}", path)).to_rgba();
+        let (width, height) = img.dimensions();
+        let alpha = image::RgbImage::from_fn(width, height, |x, y| {
+            let a = img.get_pixel(x, y).channels()[3];
+            image::Rgb([a, a, a])
+        });
+        ImageTexture {
+            img: Box::new(alpha),
+            width: width,
+            height: height,
+        }
+    }
 }
 
 impl Texture for ImageTexture {
@@ -247,10 +292,182 @@ impl Texture for ImageTexture {
     }
 }
 
+//
+// Voxel Grid Texture
+// Values at voxel centers, trilinearly interpolated and clamped at the edges, e.g. a medium's
+// density or per-voxel color
+//
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    pub resolution: (usize, usize, usize),
+    pub bounds: AABB,
+    pub data: Arc<Vec<Vec3>>,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: (usize, usize, usize), bounds: AABB, data: Vec<Vec3>) -> VoxelGrid {
+        let (nx, ny, nz) = resolution;
+        assert!(nx > 0 && ny > 0 && nz > 0, "Empty voxel grid");
+        assert!(data.len() == nx * ny * nz, "Voxel grid has {} values for {}x{}x{} voxels", data.len(), nx, ny, nz);
+        VoxelGrid {
+            resolution: resolution,
+            bounds: bounds,
+            data: Arc::new(data),
+        }
+    }
+
+    // Evaluate f at every voxel center
+    pub fn from_fn(resolution: (usize, usize, usize), bounds: AABB, f: &Fn(Vec3) -> Vec3) -> VoxelGrid {
+        let (nx, ny, nz) = resolution;
+        let size = bounds.max - bounds.min;
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for k in 0..nz {
+            for j in 0..ny {
+                for i in 0..nx {
+                    let p = Vec3::new(
+                        (i as f64 + 0.5) / nx as f64,
+                        (j as f64 + 0.5) / ny as f64,
+                        (k as f64 + 0.5) / nz as f64,
+                    );
+                    data.push(f(bounds.min + p * size));
+                }
+            }
+        }
+        VoxelGrid::new(resolution, bounds, data)
+    }
+
+    pub fn voxel(&self, i: usize, j: usize, k: usize) -> Vec3 {
+        let (nx, ny, _) = self.resolution;
+        self.data[(k * ny + j) * nx + i]
+    }
+
+    // Largest value in any channel, e.g. the majorant of a density grid
+    pub fn max(&self) -> f64 {
+        self.data.iter().fold(0.0, |m: f64, v| m.max(v.x).max(v.y).max(v.z))
+    }
+
+    // Value at p, outside the bounds the nearest edge
+    pub fn lookup(&self, p: &Vec3) -> Vec3 {
+        trilinear(self.resolution, &self.bounds, p, &|i, j, k| self.voxel(i, j, k))
+    }
+}
+
+// Interpolate voxel(i, j, k) of a grid of resolution over bounds at p, between voxel centers and
+// clamped to the nearest edge voxels outside of them
+pub fn trilinear<T>(resolution: (usize, usize, usize), bounds: &AABB, p: &Vec3, voxel: &Fn(usize, usize, usize) -> T) -> T
+    where T: Copy + Add<Output = T> + Mul<f64, Output = T>
+{
+    let (nx, ny, nz) = resolution;
+    let local = (*p - bounds.min) / (bounds.max - bounds.min);
+
+    // Continuous voxel coordinates, centers at integers
+    let coordinate = |x: f64, n: usize| {
+        let x = (x * n as f64 - 0.5).max(0.0).min((n - 1) as f64);
+        let i = (x.floor() as usize).min(n.saturating_sub(2));
+        (i, (i + 1).min(n - 1), x - i as f64)
+    };
+    let (i0, i1, fx) = coordinate(local.x, nx);
+    let (j0, j1, fy) = coordinate(local.y, ny);
+    let (k0, k1, fz) = coordinate(local.z, nz);
+
+    let lerp = |a: T, b: T, t: f64| a * (1.0 - t) + b * t;
+    let x00 = lerp(voxel(i0, j0, k0), voxel(i1, j0, k0), fx);
+    let x10 = lerp(voxel(i0, j1, k0), voxel(i1, j1, k0), fx);
+    let x01 = lerp(voxel(i0, j0, k1), voxel(i1, j0, k1), fx);
+    let x11 = lerp(voxel(i0, j1, k1), voxel(i1, j1, k1), fx);
+    lerp(lerp(x00, x10, fy), lerp(x01, x11, fy), fz)
+}
+
+impl Texture for VoxelGrid {
+    fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
+        self.lookup(p)
+    }
+}
+
+//
+// Blackbody Texture
+// Light given off by something hot, e.g. a fire from a VoxelGrid of temperatures as a medium's
+// emission. Colored as a blackbody and brighter with the fourth power of the temperature
+// (Stefan-Boltzmann), scale times that color at 1000K
+//
+const BLACKBODY_STEP: f64 = 50.0;
+const BLACKBODY_ENTRIES: usize = 801;
+
+#[derive(Debug, Clone)]
+pub struct BlackbodyTexture<T: Texture> {
+    pub temperature: T,
+    pub scale: f64,
+    colors: Arc<Vec<Vec3>>, // every BLACKBODY_STEP kelvin, integrating the spectrum is slow
+}
+
+impl<T: Texture> Texture for BlackbodyTexture<T> {
+    fn value(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        let temperature = self.temperature.value(u, v, p).x;
+        if temperature <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        let x = (temperature / BLACKBODY_STEP).min((BLACKBODY_ENTRIES - 1) as f64);
+        let i = (x as usize).min(BLACKBODY_ENTRIES - 2);
+        let f = x - i as f64;
+        let color = self.colors[i] * (1.0 - f) + self.colors[i + 1] * f;
+        color * self.scale * (temperature / 1000.0).powi(4)
+    }
+}
+
+//
+// Boxed Texture
+// Any texture behind one type, for materials with many independently textured parameters
+//
+#[derive(Debug, Clone)]
+pub struct BoxedTexture {
+    inner: Arc<DynTexture + Send + Sync>,
+}
+
+// Object safe part of Texture
+trait DynTexture: Debug {
+    fn dyn_value(&self, u: f64, v: f64, p: &Vec3) -> Vec3;
+}
+
+impl<T: Texture> DynTexture for T {
+    fn dyn_value(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.value(u, v, p)
+    }
+}
+
+impl Texture for BoxedTexture {
+    fn value(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.inner.dyn_value(u, v, p)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_blackbody_texture() {
+        let glow = |t: f64| blackbody_texture(constant_texture(Vec3::new(t, t, t)), 2.0).value(0.0, 0.0, &Vec3::new(0.0,0.0,0.0));
+        assert!(Vec3::approx_float_eq(&glow(1000.0), &(spectrum::blackbody(1000.0) * 2.0)));
+        // Between table entries
+        let c = glow(1525.0) / (2.0 * 1.525f64.powi(4));
+        assert!((c - spectrum::blackbody(1525.0)).length() < 1e-3);
+        assert!(glow(0.0) == Vec3::new(0.0,0.0,0.0));
+        assert!(glow(-10.0) == Vec3::new(0.0,0.0,0.0));
+    }
+
+    #[test]
+    fn test_voxel_grid_trilinear() {
+        let bounds = AABB { min: Vec3::new(0.0,0.0,0.0), max: Vec3::new(4.0,2.0,2.0) };
+        let grid = VoxelGrid::from_fn((4, 2, 2), bounds, &|p| Vec3::new(p.x, p.y + p.z, 1.0));
+        // Linear data comes back exactly between the outermost centers
+        let p = Vec3::new(1.7, 0.8, 1.2);
+        assert!(Vec3::approx_float_eq(&grid.lookup(&p), &Vec3::new(1.7, 2.0, 1.0)));
+        assert!(grid.lookup(&Vec3::new(2.5,0.5,0.5)) == grid.voxel(2, 0, 0));
+        // Clamped beyond them
+        assert!(Vec3::approx_float_eq(&grid.lookup(&Vec3::new(-3.0,0.5,0.5)), &Vec3::new(0.5, 1.0, 1.0)));
+        assert!(grid.max() == 3.5);
+    }
+
     fn test_perlin_generate() {
         let v = PerlinNoise::generate();
         for i in v {
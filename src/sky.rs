@@ -0,0 +1,357 @@
+// Daylight: Preetham et al. 1999 analytic sky and an attenuated sun disk
+//
+// Radiance is in kcd/m^2 (the model's luminance units) times intensity. Directions have +y up,
+// +z to the south and +x to the west. Below the horizon the sky sees a ground lit by the sky and
+// sun, a uniform Lambertian of ground_albedo.
+//
+// As a background it is seen by camera rays and specular paths, as a light it is sampled at every
+// other hit from a table of its luminance, with the sun disk picked by its share of the power.
+
+extern crate rand;
+
+use light::{Light, LightBounds, LightSample};
+use spectrum::xyz_to_srgb;
+use vec3::{Vec3, Onb};
+use rand::*;
+
+use std::f64;
+use std::f64::consts::PI;
+
+// Resolution of the importance sampling table over zenith angle and azimuth
+const N_THETA: usize = 64;
+const N_PHI: usize = 128;
+
+// Sun's angular radius, and its luminance above the atmosphere in kcd/m^2
+const SUN_RADIUS: f64 = 0.2665 * PI / 180.0;
+const SUN_LUMINANCE: f64 = 2.0e6;
+
+// Wavelengths in micrometers for the red, green and blue sun transmittance
+const SUN_WAVELENGTHS: [f64; 3] = [0.680, 0.550, 0.440];
+
+#[derive(Debug, Clone)]
+pub struct Sky {
+    pub turbidity: f64,
+    pub ground_albedo: Vec3,
+    pub intensity: f64,
+    sun: Vec3,           // unit direction towards the sun
+    zenith: Vec3,        // Y, x, y at the zenith
+    perez: [[f64; 5]; 3], // A to E for Y, x, y
+    sun_radiance: Vec3,
+    ground: Vec3,
+    // Importance sampling, cdf over every cell then the sun disk last
+    cdf: Vec<f64>,
+    sun_probability: f64,
+}
+
+impl Sky {
+    // Turbidity from 2 (very clear) to 10 (hazy)
+    pub fn new(sun_direction: Vec3, turbidity: f64, ground_albedo: Vec3) -> Sky {
+        let sun = sun_direction.normalized();
+        let t = turbidity.max(1.7).min(10.0);
+        let theta_s = sun.y.max(-1.0).min(1.0).acos().min(PI / 2.0);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let chromaticity = |m: [[f64; 4]; 3]| {
+            let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let row = |r: [f64; 4]| r[0] * th[0] + r[1] * th[1] + r[2] * th[2] + r[3] * th[3];
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_chroma_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut sky = Sky {
+            turbidity: t,
+            ground_albedo: ground_albedo,
+            intensity: 1.0,
+            sun: sun,
+            zenith: Vec3::new(zenith_y, zenith_x, zenith_chroma_y),
+            perez: perez,
+            sun_radiance: sun_radiance(theta_s, t),
+            ground: Vec3::new(0.0,0.0,0.0),
+            cdf: vec![],
+            sun_probability: 0.0,
+        };
+        if sun.y <= 0.0 {
+            sky.sun_radiance = Vec3::new(0.0,0.0,0.0);
+        }
+        sky.ground = sky.ground_radiance();
+        sky.build_table();
+        sky
+    }
+
+    // Sun position at a latitude (degrees), day of the year (1 to 365) and solar time (hours)
+    pub fn from_time(latitude: f64, day: f64, hour: f64, turbidity: f64, ground_albedo: Vec3) -> Sky {
+        let l = latitude.to_radians();
+        let declination = 0.4093 * (2.0 * PI * (day - 81.0) / 368.0).sin();
+        let hour_angle = PI * hour / 12.0;
+        let theta = PI / 2.0 - (l.sin() * declination.sin() - l.cos() * declination.cos() * hour_angle.cos()).asin();
+        let phi = (-declination.cos() * hour_angle.sin())
+            .atan2(l.cos() * declination.sin() + l.sin() * declination.cos() * hour_angle.cos());
+        let direction = Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos());
+        Sky::new(direction, turbidity, ground_albedo)
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Sky {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun
+    }
+
+    // Radiance arriving from direction dir, including the sun disk
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let dir = dir.normalized();
+        let mut radiance = self.sky_radiance(dir);
+        if dir.dot(self.sun) >= SUN_RADIUS.cos() {
+            radiance = radiance + self.sun_radiance;
+        }
+        radiance * self.intensity
+    }
+
+    // Perez luminance distribution relative to its value at the zenith
+    fn perez(&self, c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+        let f = |cos_theta: f64, gamma: f64| {
+            (1.0 + c[0] * (c[1] / cos_theta.max(0.01)).exp())
+                * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+        };
+        let theta_s = self.sun.y.max(-1.0).min(1.0).acos().min(PI / 2.0);
+        f(cos_theta, gamma) / f(1.0, theta_s)
+    }
+
+    // Sky without the sun, unscaled by intensity
+    fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        if dir.y < 0.0 {
+            return self.ground;
+        }
+        let gamma = dir.dot(self.sun).max(-1.0).min(1.0).acos();
+        let luminance = self.zenith.x * self.perez(&self.perez[0], dir.y, gamma);
+        let x = self.zenith.y * self.perez(&self.perez[1], dir.y, gamma);
+        let y = self.zenith.z * self.perez(&self.perez[2], dir.y, gamma);
+        if y <= 0.0 {
+            return Vec3::new(0.0,0.0,0.0);
+        }
+        let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        xyz_to_srgb(xyz).map(&|c| c.max(0.0))
+    }
+
+    // Lambertian ground lit by the upper hemisphere
+    fn ground_radiance(&self) -> Vec3 {
+        let steps = 32;
+        let mut irradiance = self.sun_radiance * (PI * SUN_RADIUS * SUN_RADIUS * self.sun.y.max(0.0));
+        for i in 0..steps {
+            for j in 0..steps * 2 {
+                let cos_theta = (i as f64 + 0.5) / steps as f64;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let phi = PI * (j as f64 + 0.5) / steps as f64;
+                let dir = Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                // Uniform in cos_theta over the hemisphere, each cell 2 pi / (2 steps^2) steradians
+                irradiance = irradiance + self.sky_radiance(dir) * (cos_theta * PI / (steps * steps) as f64);
+            }
+        }
+        self.ground_albedo * irradiance / PI
+    }
+
+    fn cell_direction(theta: f64, phi: f64) -> Vec3 {
+        Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos())
+    }
+
+    // Cells weighted by luminance times solid angle, then the sun disk
+    fn build_table(&mut self) {
+        let (d_theta, d_phi) = (PI / N_THETA as f64, 2.0 * PI / N_PHI as f64);
+        let mut cdf = Vec::with_capacity(N_THETA * N_PHI + 1);
+        let mut total = 0.0;
+        for i in 0..N_THETA {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..N_PHI {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let radiance = self.sky_radiance(Sky::cell_direction(theta, phi));
+                total += radiance.luminance().max(0.0) * theta.sin() * d_theta * d_phi;
+                cdf.push(total);
+            }
+        }
+        let sun = self.sun_radiance.luminance() * PI * SUN_RADIUS * SUN_RADIUS;
+        total += sun;
+        cdf.push(total);
+        if total > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        }
+        self.sun_probability = if total > 0.0 { sun / total } else { 0.0 };
+        self.cdf = cdf;
+    }
+
+    // Probability of cell i in the table
+    fn cell_probability(&self, i: usize) -> f64 {
+        if i == 0 { self.cdf[0] } else { self.cdf[i] - self.cdf[i - 1] }
+    }
+
+    // Density of sample returning dir, per steradian
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let dir = dir.normalized();
+        let (d_theta, d_phi) = (PI / N_THETA as f64, 2.0 * PI / N_PHI as f64);
+        let theta = dir.y.max(-1.0).min(1.0).acos();
+        let phi = dir.x.atan2(dir.z);
+        let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+        let i = ((theta / d_theta) as usize).min(N_THETA - 1);
+        let j = ((phi / d_phi) as usize).min(N_PHI - 1);
+        let sin_theta = theta.sin();
+        let mut pdf = if sin_theta > 0.0 {
+            self.cell_probability(i * N_PHI + j) / (sin_theta * d_theta * d_phi)
+        } else {
+            0.0
+        };
+        if dir.dot(self.sun) >= SUN_RADIUS.cos() {
+            pdf += self.sun_probability / (2.0 * PI * (1.0 - SUN_RADIUS.cos()));
+        }
+        pdf
+    }
+}
+
+impl Light for Sky {
+    fn sample(&self, _p: &Vec3, rng: &mut XorShiftRng) -> Option<LightSample> {
+        if self.cdf.is_empty() || *self.cdf.last().unwrap() <= 0.0 {
+            return None;
+        }
+        let u = rng.gen::<f64>();
+        let i = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+            Ok(i) => i,
+            Err(i) => i,
+        }.min(self.cdf.len() - 1);
+
+        let dir = if i == N_THETA * N_PHI {
+            // Uniform over the sun's cone
+            let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - SUN_RADIUS.cos());
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            Onb::from_w(self.sun).to_world(Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+        } else {
+            let (d_theta, d_phi) = (PI / N_THETA as f64, 2.0 * PI / N_PHI as f64);
+            let theta = ((i / N_PHI) as f64 + rng.gen::<f64>()) * d_theta;
+            let phi = ((i % N_PHI) as f64 + rng.gen::<f64>()) * d_phi;
+            Sky::cell_direction(theta, phi)
+        };
+
+        let pdf = self.pdf(dir);
+        if !(pdf > 0.0) {
+            return None;
+        }
+        Some(LightSample {
+            dir: dir,
+            distance: f64::INFINITY,
+            radiance: self.radiance(dir) / pdf,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn pdf(&self, _p: &Vec3, wi: Vec3) -> f64 {
+        Sky::pdf(self, wi)
+    }
+}
+
+// Sun above the atmosphere dimmed by Rayleigh and aerosol scattering along the air mass at zenith
+// angle theta_s, per Preetham et al.'s appendix
+fn sun_radiance(theta_s: f64, turbidity: f64) -> Vec3 {
+    let degrees = theta_s.to_degrees();
+    let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - degrees).max(1e-3).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let transmittance = |lambda: f64| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    };
+    Vec3::new(
+        transmittance(SUN_WAVELENGTHS[0]),
+        transmittance(SUN_WAVELENGTHS[1]),
+        transmittance(SUN_WAVELENGTHS[2]),
+    ) * SUN_LUMINANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noon() -> Sky {
+        Sky::new(Vec3::new(0.0,1.0,1.0), 3.0, Vec3::new(0.3,0.3,0.3))
+    }
+
+    #[test]
+    fn test_sky_is_blue_and_sun_is_yellow() {
+        let sky = noon();
+        let zenith = sky.radiance(Vec3::new(0.0,1.0,0.0));
+        assert!(zenith.z > zenith.x);
+        let sun = sky.radiance(sky.sun_direction());
+        assert!(sun.x > sun.z && sun.luminance() > 1000.0 * zenith.luminance());
+    }
+
+    #[test]
+    fn test_lower_sun_is_redder() {
+        let high = Sky::new(Vec3::new(0.0,1.0,0.2), 3.0, Vec3::new(0.3,0.3,0.3));
+        let low = Sky::new(Vec3::new(0.0,0.05,1.0), 3.0, Vec3::new(0.3,0.3,0.3));
+        let ratio = |s: &Sky| { let c = s.radiance(s.sun_direction()); c.z / c.x };
+        assert!(ratio(&low) < ratio(&high));
+    }
+
+    #[test]
+    fn test_pdf_integrates_to_one() {
+        let sky = noon();
+        let steps = 400;
+        let mut integral = 0.0;
+        for i in 0..steps {
+            for j in 0..steps * 2 {
+                let theta = PI * (i as f64 + 0.5) / steps as f64;
+                let phi = PI * (j as f64 + 0.5) / steps as f64;
+                let d_omega = theta.sin() * (PI / steps as f64) * (PI / steps as f64);
+                integral += sky.pdf(Sky::cell_direction(theta, phi)) * d_omega;
+            }
+        }
+        // The sun disk is too small for the grid, only the sky part is integrated
+        assert!((integral - (1.0 - sky.sun_probability)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sampled_irradiance() {
+        // Irradiance on an upward surface estimated by sampling, against a grid sum plus the sun
+        let sky = noon();
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let n = 100000;
+        let mut sampled = 0.0;
+        for _ in 0..n {
+            let s = sky.sample(&Vec3::new(0.0,0.0,0.0), &mut rng).unwrap();
+            sampled += s.radiance.y * s.dir.y.max(0.0);
+        }
+        let sampled = sampled / n as f64;
+
+        let steps = 200;
+        let mut expected = sky.sun_radiance.y * PI * SUN_RADIUS * SUN_RADIUS * sky.sun_direction().y;
+        for i in 0..steps {
+            for j in 0..steps * 4 {
+                let theta = PI / 2.0 * (i as f64 + 0.5) / steps as f64;
+                let phi = 2.0 * PI * (j as f64 + 0.5) / (steps * 4) as f64;
+                let d_omega = theta.sin() * (PI / 2.0 / steps as f64) * (2.0 * PI / (steps * 4) as f64);
+                expected += sky.sky_radiance(Sky::cell_direction(theta, phi)).y * theta.cos() * d_omega;
+            }
+        }
+        assert!((sampled - expected).abs() / expected < 0.03, "sampled {} expected {}", sampled, expected);
+    }
+}
@@ -0,0 +1,293 @@
+// Module for spectral rendering
+//
+// Paths carry radiance at three wavelengths in a Vec3 instead of RGB. The hero wavelength is
+// sampled uniformly per camera sample, the other two are spaced evenly after it, wrapping around
+// the visible range. RGB colors from textures and lights are upsampled to spectra with Smits'
+// method, and path results are projected to XYZ with the CIE matching functions then to sRGB.
+
+use vec3::Vec3;
+use rand::*;
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 720.0;
+
+// Number of wavelengths carried by a path, one per Vec3 component
+const N_WAVELENGTHS: usize = 3;
+
+// Linear sRGB of the constant spectrum 1 with the matching functions below, used to white
+// balance so a white surface under a white light stays white
+const FLAT_RGB: Vec3 = Vec3 { x: 128.35908, y: 101.52752, z: 97.06616 };
+
+// Smits 1999 spectra for RGB to spectrum conversion, 10 even bins from LAMBDA_MIN to LAMBDA_MAX
+const SMITS_WHITE:   [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN:    [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW:  [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED:     [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN:   [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE:    [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+//
+// Wavelengths of a path
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wavelengths {
+    pub hero: f64,
+    // After a wavelength dependent event only the hero wavelength is still carried
+    pub secondary_terminated: bool,
+}
+
+impl Wavelengths {
+    pub fn sample(rng: &mut XorShiftRng) -> Wavelengths {
+        Wavelengths {
+            hero: LAMBDA_MIN + rng.gen::<f64>() * (LAMBDA_MAX - LAMBDA_MIN),
+            secondary_terminated: false,
+        }
+    }
+
+    pub fn all(&self) -> [f64; N_WAVELENGTHS] {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let rotate = |i: usize| {
+            let l = self.hero + range * (i as f64) / (N_WAVELENGTHS as f64);
+            if l > LAMBDA_MAX { l - range } else { l }
+        };
+        [rotate(0), rotate(1), rotate(2)]
+    }
+
+    // Drop the secondary wavelengths, the hero now stands in for all of them
+    // Returns the weight to apply to the path's attenuation
+    pub fn terminate_secondary(&mut self) -> Vec3 {
+        if self.secondary_terminated {
+            Vec3::new(1.0, 0.0, 0.0)
+        } else {
+            self.secondary_terminated = true;
+            Vec3::new(N_WAVELENGTHS as f64, 0.0, 0.0)
+        }
+    }
+
+    // RGB reflectance or emission at each wavelength
+    pub fn from_rgb(&self, rgb: Vec3) -> Vec3 {
+        let l = self.all();
+        Vec3::new(rgb_to_spectrum(rgb, l[0]), rgb_to_spectrum(rgb, l[1]), rgb_to_spectrum(rgb, l[2]))
+    }
+
+    // Linear sRGB estimate from radiance at each wavelength
+    pub fn to_rgb(&self, values: Vec3) -> Vec3 {
+        let l = self.all();
+        let pdf = 1.0 / (LAMBDA_MAX - LAMBDA_MIN);
+        let xyz = (cie_xyz(l[0]) * values.x + cie_xyz(l[1]) * values.y + cie_xyz(l[2]) * values.z)
+            / (N_WAVELENGTHS as f64 * pdf);
+        Vec3::map2(xyz_to_srgb(xyz), FLAT_RGB, &|c, w| c / w)
+    }
+}
+
+//
+// Conversions
+//
+
+// Smits' RGB to spectrum, evaluated at one wavelength
+pub fn rgb_to_spectrum(rgb: Vec3, lambda: f64) -> f64 {
+    let bin = (((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0) as i32).max(0).min(9) as usize;
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    if r <= g && r <= b {
+        let s = r * SMITS_WHITE[bin];
+        if g <= b {
+            s + (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            s + (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let s = g * SMITS_WHITE[bin];
+        if r <= b {
+            s + (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            s + (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        let s = b * SMITS_WHITE[bin];
+        if r <= g {
+            s + (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            s + (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+// CIE 1931 color matching functions, multi-lobe fit by Wyman, Sloan and Shirley 2013
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let g = |mu: f64, sigma_low: f64, sigma_high: f64| {
+        let sigma = if lambda < mu { sigma_low } else { sigma_high };
+        let t = (lambda - mu) / sigma;
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+pub fn xyz_to_srgb(c: Vec3) -> Vec3 {
+    Vec3::new(
+         3.2404542 * c.x - 1.5371385 * c.y - 0.4985314 * c.z,
+        -0.9692660 * c.x + 1.8760108 * c.y + 0.0415560 * c.z,
+         0.0556434 * c.x - 0.2040259 * c.y + 1.0572252 * c.z,
+    )
+}
+
+// Planck's law, spectral radiance of a blackbody at temperature in kelvin, W/(sr m^2 nm)
+pub fn planck(lambda: f64, temperature: f64) -> f64 {
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62607015e-34;
+    const K: f64 = 1.380649e-23;
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * K * temperature)).exp() - 1.0)) * 1e-9
+}
+
+// Color of a blackbody at temperature in kelvin, linear sRGB with luminance 1: deep red around
+// 1000K, white near 5500K where it is closest to flat, blue past it
+pub fn blackbody(temperature: f64) -> Vec3 {
+    if temperature <= 0.0 {
+        return Vec3::new(0.0,0.0,0.0);
+    }
+    let n = 68;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / n as f64;
+    let mut xyz = Vec3::new(0.0,0.0,0.0);
+    for i in 0..n {
+        let lambda = LAMBDA_MIN + (i as f64 + 0.5) * step;
+        xyz += cie_xyz(lambda) * planck(lambda, temperature) * step;
+    }
+    let rgb = Vec3::map2(xyz_to_srgb(xyz), FLAT_RGB, &|c, w| (c / w).max(0.0));
+    let luminance = rgb.dot(Vec3::new(0.2126,0.7152,0.0722));
+    if luminance > 0.0 && luminance.is_finite() { rgb / luminance } else { Vec3::new(0.0,0.0,0.0) }
+}
+
+//
+// Index of refraction as a function of wavelength
+//
+#[derive(Debug, Clone)]
+pub enum Ior {
+    Constant(f64),
+    // n = a + b / lambda^2, lambda in micrometers
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i), lambda in micrometers
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Ior {
+    pub fn bk7() -> Ior {
+        Ior::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    pub fn fused_silica() -> Ior {
+        Ior::Sellmeier {
+            b: [0.6961663, 0.4079426, 0.8974794],
+            c: [0.0046791, 0.0135121, 97.9340],
+        }
+    }
+
+    pub fn diamond() -> Ior {
+        Ior::Sellmeier {
+            b: [4.3356, 0.3306, 0.0],
+            c: [0.1060 * 0.1060, 0.1750 * 0.1750, 0.0],
+        }
+    }
+
+    // Index at wavelength in nanometers, non positive wavelengths (RGB rendering) use 550nm
+    pub fn at(&self, lambda: f64) -> f64 {
+        let um = if lambda > 0.0 { lambda / 1000.0 } else { 0.55 };
+        let um2 = um * um;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / um2,
+            Ior::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * um2 / (um2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            Ior::Constant(_) => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Integrate spectrum over the visible range to sRGB
+    fn integrate(rgb: Vec3) -> Vec3 {
+        let n = 3400;
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        for i in 0..n {
+            let l = LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * (i as f64 + 0.5) / n as f64;
+            xyz += cie_xyz(l) * rgb_to_spectrum(rgb, l) * ((LAMBDA_MAX - LAMBDA_MIN) / n as f64);
+        }
+        Vec3::map2(xyz_to_srgb(xyz), FLAT_RGB, &|c, w| c / w)
+    }
+
+    #[test]
+    fn test_white_round_trip() {
+        let c = integrate(Vec3::new(1.0, 1.0, 1.0));
+        assert!((c - Vec3::new(1.0, 1.0, 1.0)).length() < 0.01);
+    }
+
+    #[test]
+    fn test_color_round_trip() {
+        for rgb in &[Vec3::new(0.8, 0.1, 0.1), Vec3::new(0.1, 0.7, 0.2), Vec3::new(0.2, 0.3, 0.9)] {
+            let c = integrate(*rgb);
+            assert!((c - *rgb).length() < 0.25);
+        }
+    }
+
+    #[test]
+    fn test_blackbody() {
+        // Peak at Wien's displacement
+        let peak = 2.897771955e6 / 5000.0;
+        assert!(planck(peak, 5000.0) > planck(peak - 10.0, 5000.0));
+        assert!(planck(peak, 5000.0) > planck(peak + 10.0, 5000.0));
+
+        let fire = blackbody(1500.0);
+        assert!(fire.x > fire.y && fire.y > fire.z);
+        let sun = blackbody(5500.0);
+        assert!((sun - Vec3::new(1.0, 1.0, 1.0)).length() < 0.15);
+        let hot = blackbody(15000.0);
+        assert!(hot.z > hot.y && hot.y > hot.x);
+        for c in &[fire, sun, hot] {
+            assert!((c.dot(Vec3::new(0.2126, 0.7152, 0.0722)) - 1.0).abs() < 1e-9);
+        }
+        assert!(blackbody(0.0) == Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_wavelengths_in_range() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for _ in 0..100 {
+            let w = Wavelengths::sample(&mut rng);
+            for l in w.all().iter() {
+                assert!(*l >= LAMBDA_MIN && *l <= LAMBDA_MAX);
+            }
+        }
+    }
+
+    #[test]
+    fn test_terminate_secondary_once() {
+        let mut w = Wavelengths { hero: 500.0, secondary_terminated: false };
+        assert!(w.terminate_secondary() == Vec3::new(3.0, 0.0, 0.0));
+        assert!(w.terminate_secondary() == Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_sellmeier_bk7() {
+        // Known index of BK7 at the sodium D line, 589.3nm
+        assert!((Ior::bk7().at(589.3) - 1.5168).abs() < 0.0005);
+        assert!(Ior::bk7().at(400.0) > Ior::bk7().at(700.0));
+    }
+}
@@ -1,7 +1,8 @@
 use std::slice::Iter;
+use std::f64;
 use std::f64::consts::PI;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Axis {
     X,
     Y,
@@ -26,3 +27,192 @@ pub fn degrees_to_cos_and_sin(degrees: f64) -> (f64, f64) {
     let cos_theta = radians.cos();
     (cos_theta, sin_theta)
 }
+
+// Real roots of a x^2 + b x + c, smallest first. Avoids the cancellation of the textbook formula
+// when b^2 is much larger than 4ac
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    if q == 0.0 {
+        return Some((0.0, 0.0)); // b and c are 0
+    }
+    let (t0, t1) = (q / a, c / q);
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+// Real roots of x^3 + a x^2 + b x + c, smallest first
+pub fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let q = (a * a - 3.0 * b) / 9.0;
+    let r = (2.0 * a * a * a - 9.0 * a * b + 27.0 * c) / 54.0;
+    let shift = a / 3.0;
+    let mut roots = if r * r < q * q * q {
+        // Three, by the trigonometric method
+        let theta = (r / (q * q * q).sqrt()).max(-1.0).min(1.0).acos();
+        let m = -2.0 * q.sqrt();
+        vec![
+            m * (theta / 3.0).cos() - shift,
+            m * ((theta + 2.0 * PI) / 3.0).cos() - shift,
+            m * ((theta - 2.0 * PI) / 3.0).cos() - shift,
+        ]
+    } else {
+        let big = -r.signum() * (r.abs() + (r * r - q * q * q).sqrt()).cbrt();
+        let small = if big != 0.0 { q / big } else { 0.0 };
+        let mut roots = vec![big + small - shift];
+        // The complex pair is real when it is a double root
+        if (big - small).abs() <= 1e-12 * (big.abs() + small.abs()).max(1e-300) {
+            roots.push(-0.5 * (big + small) - shift);
+        }
+        roots
+    };
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    roots
+}
+
+// Real roots of a x^4 + b x^3 + c x^2 + d x + e, smallest first
+//
+// By Ferrari's method: the depressed quartic is split into two quadratics with a root of its
+// resolvent cubic, then each root is polished by Newton's method on the original polynomial to win
+// back the precision lost along the way
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return solve_quadratic(c, d, e).map_or(vec![], |(t0, t1)| vec![t0, t1]);
+        }
+        return solve_cubic(c / b, d / b, e / b);
+    }
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+
+    // y^4 + p y^2 + q y + r with x = y - b / 4
+    let b2 = b * b;
+    let p = c - 3.0 * b2 / 8.0;
+    let q = d - b * c / 2.0 + b2 * b / 8.0;
+    let r = e - b * d / 4.0 + b2 * c / 16.0 - 3.0 * b2 * b2 / 256.0;
+
+    let mut ys = vec![];
+    let m = solve_cubic(p, p * p / 4.0 - r, -q * q / 8.0).into_iter().fold(f64::NEG_INFINITY, f64::max);
+    if m > 1e-14 * (p.abs() + r.abs().sqrt()).max(1e-300) {
+        // (y^2 + p/2 + m)^2 = 2m (y - q / 4m)^2
+        let s = (2.0 * m).sqrt();
+        for &(sign, k) in &[(-1.0, p / 2.0 + m + q / (2.0 * s)), (1.0, p / 2.0 + m - q / (2.0 * s))] {
+            if let Some((y0, y1)) = solve_quadratic(1.0, sign * s, k) {
+                ys.push(y0);
+                ys.push(y1);
+            }
+        }
+    } else {
+        // q is 0, a quadratic in y^2
+        if let Some((z0, z1)) = solve_quadratic(1.0, p, r) {
+            for &z in &[z0, z1] {
+                if z >= 0.0 {
+                    ys.push(-z.sqrt());
+                    ys.push(z.sqrt());
+                }
+            }
+        }
+    }
+
+    let f = |x: f64| (((x + b) * x + c) * x + d) * x + e;
+    let df = |x: f64| ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+    let mut roots: Vec<f64> = ys.into_iter().map(|y| {
+        let mut x = y - b / 4.0;
+        for _ in 0..4 {
+            let slope = df(x);
+            if slope == 0.0 {
+                break;
+            }
+            let next = x - f(x) / slope;
+            if !(f(next).abs() < f(x).abs()) {
+                break;
+            }
+            x = next;
+        }
+        x
+    }).collect();
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    roots
+}
+
+// Angle of (x, z) around the y axis from +x towards +z, in [0, 2 PI)
+pub fn azimuth(x: f64, z: f64) -> f64 {
+    let phi = z.atan2(x);
+    if phi < 0.0 { phi + 2.0 * PI } else { phi }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve_quadratic() {
+        let (t0, t1) = solve_quadratic(2.0, -2.0, -12.0).unwrap();
+        assert!(approx_float_eq(t0, -2.0) && approx_float_eq(t1, 3.0));
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_none());
+
+        // Tiny root next to a huge one, lost to cancellation by the textbook formula
+        let (t0, t1) = solve_quadratic(1.0, 1e9, 1.0).unwrap();
+        assert!((t1 + 1e-9).abs() < 1e-20 && (t0 + 1e9).abs() < 1e-3);
+
+        assert!(solve_quadratic(0.0, 2.0, -4.0) == Some((2.0, 2.0)));
+    }
+
+    #[test]
+    fn test_solve_cubic() {
+        // (x + 2)(x - 1)(x - 3)
+        let roots = solve_cubic(-2.0, -5.0, 6.0);
+        assert!(roots.len() == 3);
+        assert!(approx_float_eq(roots[0], -2.0) && approx_float_eq(roots[1], 1.0) && approx_float_eq(roots[2], 3.0));
+        // (x - 2)(x^2 + 1)
+        let roots = solve_cubic(-2.0, 1.0, -2.0);
+        assert!(roots.len() == 1 && approx_float_eq(roots[0], 2.0));
+    }
+
+    #[test]
+    fn test_solve_quartic() {
+        // Roots far apart and close together, scaled
+        let cases: Vec<Vec<f64>> = vec![
+            vec![-3.0, -1.0, 2.0, 5.0],
+            vec![0.001, 0.002, 100.0, 1000.0],
+            vec![1.0, 1.0 + 1e-4, 7.0, 7.5],
+            vec![-2.0, 2.0],
+            vec![],
+        ];
+        for roots in &cases {
+            // Expand, complex pair roots of x^2 + 1 standing in for missing ones
+            let mut poly = vec![2.0]; // highest power first
+            let mut factors: Vec<Vec<f64>> = roots.iter().map(|&x| vec![1.0, -x]).collect();
+            for _ in 0..(4 - roots.len()) / 2 {
+                factors.push(vec![1.0, 0.0, 1.0]);
+            }
+            for factor in &factors {
+                let mut next = vec![0.0; poly.len() + factor.len() - 1];
+                for (i, a) in poly.iter().enumerate() {
+                    for (j, b) in factor.iter().enumerate() {
+                        next[i + j] += a * b;
+                    }
+                }
+                poly = next;
+            }
+            let found = solve_quartic(poly[0], poly[1], poly[2], poly[3], poly[4]);
+            assert!(found.len() == roots.len(), "{:?} found {:?}", roots, found);
+            for (x, expected) in found.iter().zip(roots) {
+                assert!((x - expected).abs() < 1e-6 * expected.abs().max(1.0), "{:?} found {:?}", roots, found);
+            }
+        }
+    }
+
+    #[test]
+    fn test_azimuth() {
+        assert!(azimuth(1.0, 0.0) == 0.0);
+        assert!(approx_float_eq(azimuth(0.0, 1.0), PI / 2.0));
+        assert!(approx_float_eq(azimuth(0.0, -1.0), 1.5 * PI));
+    }
+}
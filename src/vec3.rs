@@ -64,6 +64,11 @@ impl Vec3 {
         self.x*other.x + self.y*other.y + self.z*other.z
     }
 
+    // Relative luminance of a linear RGB color
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
     pub fn cross(self, other: Vec3) -> Vec3 {
         Vec3 {
             x:  (self.y * other.z - self.z * other.y),
@@ -109,6 +114,52 @@ impl Vec3 {
     }
 }
 
+// Orthonormal basis with w along a direction, e.g. a surface normal
+// Materials work in the local frame where the normal is z
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    // Arbitrary but continuous tangents around unit vector w (Duff et al. 2017)
+    pub fn from_w(w: Vec3) -> Onb {
+        let sign = if w.z >= 0.0 { 1.0 } else { -1.0 };
+        let a = -1.0 / (sign + w.z);
+        let b = w.x * w.y * a;
+        Onb {
+            u: Vec3::new(1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x),
+            v: Vec3::new(b, sign + w.y * w.y * a, -w.y),
+            w: w,
+        }
+    }
+
+    // u along tangent projected onto the plane of w, e.g. a surface's dp/du
+    // Falls back to from_w when the tangent is parallel to w or zero
+    pub fn from_w_tangent(w: Vec3, tangent: Vec3) -> Onb {
+        let t = tangent - w * w.dot(tangent);
+        if t.squared_length() < 1e-12 {
+            return Onb::from_w(w);
+        }
+        let u = t.normalized();
+        Onb {
+            u: u,
+            v: w.cross(u),
+            w: w,
+        }
+    }
+
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
+
+    pub fn to_world(&self, a: Vec3) -> Vec3 {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
 // Vector operations
 impl Add for Vec3 {
     type Output = Vec3;
@@ -140,7 +191,7 @@ impl Mul for Vec3 {
 impl Div for Vec3 {
     type Output = Vec3;
     fn div(self, other: Vec3) -> Vec3 {
-        Vec3 { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z, }
+        Vec3 { x: self.x / other.x, y: self.y / other.y, z: self.z / other.z, }
     }
 }
 
@@ -211,6 +262,14 @@ pub fn random_in_unit_sphere(rng: &mut XorShiftRng) -> Vec3 {
     }
 }
 
+// Direction in the local frame (z up) with density cos(theta) / PI
+pub fn random_cosine_direction(rng: &mut XorShiftRng) -> Vec3 {
+    let r1 = rng.gen::<f64>();
+    let r2 = rng.gen::<f64>();
+    let phi = 2.0 * ::std::f64::consts::PI * r1;
+    Vec3::new(phi.cos() * r2.sqrt(), phi.sin() * r2.sqrt(), (1.0 - r2).sqrt())
+}
+
 pub fn random_in_unit_disk(rng: &mut XorShiftRng) -> Vec3 {
     loop {
         let p = (Vec3::random(rng) * 2.0 - 1.0) * Vec3::new(1.0,1.0,0.0);
@@ -230,6 +289,11 @@ mod tests {
         assert!(Vec3::new(1.0,2.0,3.0) + 2.0 == Vec3::new(3.0,4.0,5.0))
     }
 
+    #[test]
+    fn test_div_components() {
+        assert!(Vec3::new(1.0,2.0,3.0) / Vec3::new(2.0,4.0,1.0) == Vec3::new(0.5,0.5,3.0))
+    }
+
     #[test]
     fn test_sub_scalar() {
         assert!(Vec3::new(1.0,2.0,3.0) - 2.0 == Vec3::new(-1.0,0.0,1.0))
@@ -282,6 +346,19 @@ mod tests {
         assert!(a.cross(b) == Vec3::new(-3.0,6.0,-3.0))
     }
 
+    #[test]
+    fn test_onb_orthonormal() {
+        for w in &[Vec3::new(0.0,0.0,1.0), Vec3::new(0.0,0.0,-1.0), Vec3::new(1.0,2.0,-3.0).normalized()] {
+            let onb = Onb::from_w(*w);
+            assert!(::util::approx_float_eq(onb.u.dot(onb.v), 0.0));
+            assert!(::util::approx_float_eq(onb.u.dot(onb.w), 0.0));
+            assert!(::util::approx_float_eq(onb.u.length(), 1.0));
+            assert!(Vec3::approx_float_eq(&onb.u.cross(onb.v), w));
+            let a = Vec3::new(0.3, -0.2, 0.9);
+            assert!(Vec3::approx_float_eq(&onb.to_world(onb.to_local(a)), &a));
+        }
+    }
+
     #[test]
     fn test_rotate_x_360() {
         let a = Vec3::new(1.0,2.0,3.0);
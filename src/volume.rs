@@ -0,0 +1,487 @@
+// Sparse voxel volumes, e.g. smoke simulations exported from other tools
+//
+// Only the tiles of 8x8x8 voxels holding something are stored (like the leaf nodes of OpenVDB),
+// everywhere else is the background value. As a texture a SparseGrid is trilinearly interpolated
+// between voxel centers, for a model::heterogeneous_medium::HeterogeneousMedium's density or
+// temperature. Its majorant_grid bounds the value over each tile, so tracking through the medium
+// takes big steps through thin or empty parts instead of steps sized for the densest voxel.
+//
+// File format, all little endian:
+//
+//     magic        b"SVOL"
+//     version      u32, 1
+//     resolution   3 x u32, voxels along x, y and z
+//     bounds       6 x f32, world space min x, y, z then max x, y, z
+//     background   f32, value of voxels in no tile
+//     tiles        u32, number of tiles that follow
+//     each tile    3 x u32, index of the tile (voxel index / 8) along x, y and z
+//                  512 x f32, its voxels, x fastest then y then z
+//
+// Voxels of a tile past the resolution are ignored.
+
+use model::bvh::AABB;
+use ray::Ray;
+use shader::texture::{self, Texture};
+use vec3::Vec3;
+
+use std::f64;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const TILE_SIZE: usize = 8;
+const TILE_VOXELS: usize = TILE_SIZE * TILE_SIZE * TILE_SIZE;
+const MAGIC: &[u8; 4] = b"SVOL";
+const VERSION: u32 = 1;
+const NO_TILE: u32 = u32::MAX;
+const MAX_TILES: usize = 1 << 24; // 2048^3 voxels, an index of 64MB
+
+//
+// Sparse Grid
+//
+#[derive(Debug, Clone)]
+pub struct SparseGrid {
+    pub resolution: (usize, usize, usize),
+    pub bounds: AABB,
+    pub background: f64,
+    tiles: (usize, usize, usize), // number of tiles along each axis
+    index: Arc<Vec<u32>>,         // per tile, where its voxels start in values / TILE_VOXELS
+    values: Arc<Vec<f32>>,
+}
+
+impl SparseGrid {
+    // Empty, every voxel the background
+    pub fn new(resolution: (usize, usize, usize), bounds: AABB, background: f64) -> SparseGrid {
+        let (nx, ny, nz) = resolution;
+        assert!(nx > 0 && ny > 0 && nz > 0, "Empty sparse grid");
+        let tiles = tiles_for(resolution).expect("Sparse grid too large");
+        SparseGrid {
+            resolution: resolution,
+            bounds: bounds,
+            background: background,
+            tiles: tiles,
+            index: Arc::new(vec![NO_TILE; tiles.0 * tiles.1 * tiles.2]),
+            values: Arc::new(vec![]),
+        }
+    }
+
+    // Read a grid in the format above
+    pub fn load(path: &str) -> io::Result<SparseGrid> {
+        let file = File::open(Path::new(path))?;
+        SparseGrid::read(&mut BufReader::new(file))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let file = File::create(Path::new(path))?;
+        self.write(&mut BufWriter::new(file))
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<SparseGrid> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a sparse volume"));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported sparse volume version {}", version)));
+        }
+        let resolution = (read_u32(reader)? as usize, read_u32(reader)? as usize, read_u32(reader)? as usize);
+        if resolution.0 == 0 || resolution.1 == 0 || resolution.2 == 0 {
+            return Err(invalid("sparse volume has no voxels"));
+        }
+        if tiles_for(resolution).is_none() {
+            return Err(invalid(&format!("sparse volume of {}x{}x{} voxels is too large", resolution.0, resolution.1, resolution.2)));
+        }
+        let min = Vec3::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+        let max = Vec3::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+        if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+            return Err(invalid("sparse volume bounds are empty"));
+        }
+        let background = read_f32(reader)?;
+
+        let mut grid = SparseGrid::new(resolution, AABB { min: min, max: max }, background);
+        let count = read_u32(reader)?;
+        let mut data = [0f32; TILE_VOXELS];
+        for _ in 0..count {
+            let (i, j, k) = (read_u32(reader)? as usize, read_u32(reader)? as usize, read_u32(reader)? as usize);
+            if i >= grid.tiles.0 || j >= grid.tiles.1 || k >= grid.tiles.2 {
+                return Err(invalid(&format!("tile ({}, {}, {}) outside the volume", i, j, k)));
+            }
+            for v in data.iter_mut() {
+                *v = read_f32(reader)? as f32;
+            }
+            let start = grid.tile(i, j, k);
+            Arc::make_mut(&mut grid.values)[start..start + TILE_VOXELS].copy_from_slice(&data);
+        }
+        Ok(grid)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        for &n in &[self.resolution.0, self.resolution.1, self.resolution.2] {
+            write_u32(writer, n as u32)?;
+        }
+        for &x in &[self.bounds.min.x, self.bounds.min.y, self.bounds.min.z, self.bounds.max.x, self.bounds.max.y, self.bounds.max.z] {
+            write_f32(writer, x)?;
+        }
+        write_f32(writer, self.background)?;
+
+        let (tx, ty, _) = self.tiles;
+        write_u32(writer, self.index.iter().filter(|&&t| t != NO_TILE).count() as u32)?;
+        for (n, &t) in self.index.iter().enumerate() {
+            if t == NO_TILE {
+                continue;
+            }
+            for &i in &[n % tx, (n / tx) % ty, n / (tx * ty)] {
+                write_u32(writer, i as u32)?;
+            }
+            let start = t as usize * TILE_VOXELS;
+            for &v in &self.values[start..start + TILE_VOXELS] {
+                write_f32(writer, v as f64)?;
+            }
+        }
+        writer.flush()
+    }
+
+    pub fn voxel(&self, i: usize, j: usize, k: usize) -> f64 {
+        let (tx, ty, _) = self.tiles;
+        let t = self.index[((k / TILE_SIZE) * ty + j / TILE_SIZE) * tx + i / TILE_SIZE];
+        if t == NO_TILE {
+            return self.background;
+        }
+        self.values[t as usize * TILE_VOXELS + offset(i, j, k)] as f64
+    }
+
+    // Allocates the voxel's tile if it has none
+    pub fn set(&mut self, i: usize, j: usize, k: usize, value: f64) {
+        let (nx, ny, nz) = self.resolution;
+        assert!(i < nx && j < ny && k < nz, "Voxel ({}, {}, {}) outside the grid", i, j, k);
+        let start = self.tile(i / TILE_SIZE, j / TILE_SIZE, k / TILE_SIZE);
+        Arc::make_mut(&mut self.values)[start + offset(i, j, k)] = value as f32;
+    }
+
+    // Number of tiles stored
+    pub fn tile_count(&self) -> usize {
+        self.values.len() / TILE_VOXELS
+    }
+
+    // Largest value anywhere, the background included
+    pub fn max(&self) -> f64 {
+        self.values.iter().fold(self.background, |m, &v| m.max(v as f64))
+    }
+
+    // Value at p, trilinear between voxel centers, the nearest edge voxels up to the bounds and
+    // the background outside them
+    pub fn lookup(&self, p: &Vec3) -> f64 {
+        let b = &self.bounds;
+        if p.x < b.min.x || p.y < b.min.y || p.z < b.min.z || p.x > b.max.x || p.y > b.max.y || p.z > b.max.z {
+            return self.background;
+        }
+        texture::trilinear(self.resolution, b, p, &|i, j, k| self.voxel(i, j, k))
+    }
+
+    // Largest value around each tile. Lookups near a tile's faces blend in its neighbors' voxels,
+    // so each cell is the max over the tile and the ones next to it
+    pub fn majorant_grid(&self) -> MajorantGrid {
+        let (tx, ty, tz) = self.tiles;
+        let tile_max: Vec<f64> = self.index.iter().map(|&t| {
+            if t == NO_TILE {
+                return self.background;
+            }
+            let start = t as usize * TILE_VOXELS;
+            self.values[start..start + TILE_VOXELS].iter().fold(self.background, |m, &v| m.max(v as f64))
+        }).collect();
+
+        let mut max = Vec::with_capacity(tile_max.len());
+        for k in 0..tz {
+            for j in 0..ty {
+                for i in 0..tx {
+                    let mut m = self.background;
+                    for kk in k.saturating_sub(1)..(k + 2).min(tz) {
+                        for jj in j.saturating_sub(1)..(j + 2).min(ty) {
+                            for ii in i.saturating_sub(1)..(i + 2).min(tx) {
+                                m = m.max(tile_max[(kk * ty + jj) * tx + ii]);
+                            }
+                        }
+                    }
+                    max.push(m);
+                }
+            }
+        }
+
+        // Tiles overhang the bounds where the resolution isn't a multiple of the tile size
+        let (nx, ny, nz) = self.resolution;
+        let voxel = (self.bounds.max - self.bounds.min) / Vec3::new(nx as f64, ny as f64, nz as f64);
+        let covered = Vec3::new((tx * TILE_SIZE) as f64, (ty * TILE_SIZE) as f64, (tz * TILE_SIZE) as f64);
+        let bounds = AABB { min: self.bounds.min, max: self.bounds.min + voxel * covered };
+        MajorantGrid::new(self.tiles, bounds, max)
+    }
+
+    // Index of the tile's first voxel in values, allocating it
+    fn tile(&mut self, i: usize, j: usize, k: usize) -> usize {
+        let (tx, ty, _) = self.tiles;
+        let n = (k * ty + j) * tx + i;
+        if self.index[n] == NO_TILE {
+            Arc::make_mut(&mut self.index)[n] = (self.values.len() / TILE_VOXELS) as u32;
+            let background = self.background as f32;
+            Arc::make_mut(&mut self.values).extend_from_slice(&[background; TILE_VOXELS]);
+        }
+        self.index[n] as usize * TILE_VOXELS
+    }
+}
+
+impl Texture for SparseGrid {
+    fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
+        let v = self.lookup(p);
+        Vec3::new(v, v, v)
+    }
+}
+
+//
+// Majorant Grid
+// Upper bounds on a value over coarse cells, the value along a ray is under the bound of each
+// cell it passes through
+//
+#[derive(Debug, Clone)]
+pub struct MajorantGrid {
+    pub resolution: (usize, usize, usize),
+    pub bounds: AABB,
+    pub max: Arc<Vec<f64>>, // x fastest then y then z
+}
+
+impl MajorantGrid {
+    pub fn new(resolution: (usize, usize, usize), bounds: AABB, max: Vec<f64>) -> MajorantGrid {
+        let (nx, ny, nz) = resolution;
+        assert!(max.len() == nx * ny * nz, "Majorant grid has {} values for {}x{}x{} cells", max.len(), nx, ny, nz);
+        MajorantGrid {
+            resolution: resolution,
+            bounds: bounds,
+            max: Arc::new(max),
+        }
+    }
+
+    // Stretches of r covering t_min to t_max in order, each with the bound of the cell it is in,
+    // outside where it isn't in any
+    pub fn segments(&self, r: &Ray, t_min: f64, t_max: f64, outside: f64) -> Vec<(f64, f64, f64)> {
+        let o = [r.origin.x, r.origin.y, r.origin.z];
+        let d = [r.dir.x, r.dir.y, r.dir.z];
+        let min = [self.bounds.min.x, self.bounds.min.y, self.bounds.min.z];
+        let max = [self.bounds.max.x, self.bounds.max.y, self.bounds.max.z];
+        let n = [self.resolution.0, self.resolution.1, self.resolution.2];
+
+        // Where the ray is inside the bounds
+        let (mut t0, mut t1) = (t_min, t_max);
+        for a in 0..3 {
+            if d[a] == 0.0 {
+                if o[a] < min[a] || o[a] > max[a] {
+                    t1 = t0;
+                }
+                continue;
+            }
+            let (near, far) = ((min[a] - o[a]) / d[a], (max[a] - o[a]) / d[a]);
+            let (near, far) = if d[a] < 0.0 { (far, near) } else { (near, far) };
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+        }
+        if t0 >= t1 {
+            return vec![(t_min, t_max, outside)];
+        }
+
+        let mut segments = vec![];
+        if t0 > t_min {
+            segments.push((t_min, t0, outside));
+        }
+
+        // Step from cell to cell
+        let mut cell = [0i64; 3];
+        let mut next = [f64::INFINITY; 3];
+        let mut delta = [f64::INFINITY; 3];
+        let mut step = [0i64; 3];
+        let entry = t0 + (t1 - t0) * 1e-9; // just inside, for the first cell
+        for a in 0..3 {
+            let size = (max[a] - min[a]) / n[a] as f64;
+            let x = o[a] + d[a] * entry;
+            cell[a] = (((x - min[a]) / size).floor() as i64).max(0).min(n[a] as i64 - 1);
+            if d[a] > 0.0 {
+                step[a] = 1;
+                next[a] = (min[a] + (cell[a] + 1) as f64 * size - o[a]) / d[a];
+                delta[a] = size / d[a];
+            } else if d[a] < 0.0 {
+                step[a] = -1;
+                next[a] = (min[a] + cell[a] as f64 * size - o[a]) / d[a];
+                delta[a] = -size / d[a];
+            }
+        }
+        let mut t = t0;
+        loop {
+            let a = if next[0] < next[1] && next[0] < next[2] { 0 } else if next[1] < next[2] { 1 } else { 2 };
+            let end = next[a].min(t1);
+            let i = ((cell[2] as usize * n[1]) + cell[1] as usize) * n[0] + cell[0] as usize;
+            if end > t {
+                segments.push((t, end, self.max[i]));
+            }
+            if end >= t1 {
+                break;
+            }
+            t = end;
+            cell[a] += step[a];
+            next[a] += delta[a];
+            if cell[a] < 0 || cell[a] >= n[a] as i64 {
+                break;
+            }
+        }
+        if segments.last().is_none_or(|s| s.1 < t1) {
+            segments.push((t, t1, outside));
+        }
+
+        if t1 < t_max {
+            segments.push((t1, t_max, outside));
+        }
+        segments
+    }
+}
+
+// Utilities
+
+// Number of tiles along each axis, None past MAX_TILES
+fn tiles_for(resolution: (usize, usize, usize)) -> Option<(usize, usize, usize)> {
+    let tiles = (resolution.0.div_ceil(TILE_SIZE), resolution.1.div_ceil(TILE_SIZE), resolution.2.div_ceil(TILE_SIZE));
+    match tiles.0.checked_mul(tiles.1).and_then(|n| n.checked_mul(tiles.2)) {
+        Some(n) if n <= MAX_TILES => Some(tiles),
+        _ => None,
+    }
+}
+
+// Voxel's place in its tile
+fn offset(i: usize, j: usize, k: usize) -> usize {
+    ((k % TILE_SIZE) * TILE_SIZE + j % TILE_SIZE) * TILE_SIZE + i % TILE_SIZE
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    reader.read_exact(&mut b)?;
+    Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f64> {
+    Ok(f32::from_bits(read_u32(reader)?) as f64)
+}
+
+fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    writer.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+fn write_f32<W: Write>(writer: &mut W, x: f64) -> io::Result<()> {
+    write_u32(writer, (x as f32).to_bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn unit_bounds() -> AABB {
+        AABB { min: Vec3::new(0.0,0.0,0.0), max: Vec3::new(1.0,1.0,1.0) }
+    }
+
+    // A ball of density 1 in a 20^3 grid, only its tiles stored
+    fn ball() -> SparseGrid {
+        let mut grid = SparseGrid::new((20, 20, 20), unit_bounds(), 0.0);
+        for k in 0..20 {
+            for j in 0..20 {
+                for i in 0..20 {
+                    let p = Vec3::new(i as f64 + 0.5, j as f64 + 0.5, k as f64 + 0.5) / 20.0;
+                    if (p - Vec3::new(0.25,0.25,0.25)).length() < 0.15 {
+                        grid.set(i, j, k, 1.0);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn test_sparse_storage() {
+        let grid = ball();
+        assert!(grid.tile_count() == 1);
+        assert!(grid.voxel(5, 5, 5) == 1.0);
+        assert!(grid.voxel(19, 19, 19) == 0.0);
+        assert!(grid.lookup(&Vec3::new(0.25,0.25,0.25)) == 1.0);
+        assert!(grid.lookup(&Vec3::new(0.9,0.9,0.9)) == 0.0);
+        assert!(grid.lookup(&Vec3::new(-1.0,0.25,0.25)) == 0.0);
+        assert!(grid.max() == 1.0);
+    }
+
+    #[test]
+    fn test_trilinear() {
+        let mut grid = SparseGrid::new((2, 1, 1), AABB { min: Vec3::new(0.0,0.0,0.0), max: Vec3::new(2.0,1.0,1.0) }, 0.5);
+        grid.set(1, 0, 0, 3.0);
+        // Halfway between the background voxel at x = 0.5 and the set one at 1.5
+        assert!((grid.lookup(&Vec3::new(1.0,0.5,0.5)) - 1.75).abs() < 1e-9);
+        assert!((grid.lookup(&Vec3::new(1.25,0.5,0.5)) - 2.375).abs() < 1e-9);
+        assert!(grid.lookup(&Vec3::new(1.9,0.5,0.5)) == 3.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let grid = ball();
+        let mut bytes = vec![];
+        grid.write(&mut bytes).unwrap();
+        assert!(&bytes[0..4] == b"SVOL");
+        assert!(bytes.len() == 4 + 4 + 12 + 24 + 4 + 4 + grid.tile_count() * (12 + 512 * 4));
+
+        let read = SparseGrid::read(&mut Cursor::new(bytes.clone())).unwrap();
+        assert!(read.resolution == grid.resolution && read.tile_count() == grid.tile_count());
+        for &(i, j, k) in &[(5, 5, 5), (0, 0, 0), (19, 19, 19), (2, 7, 4)] {
+            assert!(read.voxel(i, j, k) == grid.voxel(i, j, k));
+        }
+
+        // Truncated and garbage
+        assert!(SparseGrid::read(&mut Cursor::new(bytes[..bytes.len() - 3].to_vec())).is_err());
+        assert!(SparseGrid::read(&mut Cursor::new(b"VDB0 not this format".to_vec())).is_err());
+
+        // A resolution too large to index, its tile count overflowing
+        let mut huge = bytes.clone();
+        for n in 0..3 {
+            huge[8 + 4 * n..12 + 4 * n].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        }
+        let err = SparseGrid::read(&mut Cursor::new(huge)).unwrap_err();
+        assert!(err.kind() == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_majorants_bound_lookups() {
+        let grid = ball();
+        let majorants = grid.majorant_grid();
+        assert!(majorants.resolution == (3, 3, 3));
+        assert!(majorants.max[0] == 1.0 && majorants.max[26] == 0.0);
+
+        // Along rays every lookup is under its segment's bound
+        let rays = [
+            Ray::new(Vec3::new(-0.5,0.25,0.25), Vec3::new(1.0,0.0,0.0)),
+            Ray::new(Vec3::new(1.5,1.2,-0.3), Vec3::new(-1.0,-0.8,0.9)),
+            Ray::new(Vec3::new(0.3,0.3,2.0), Vec3::new(0.0,0.0,-1.0)),
+        ];
+        for r in &rays {
+            let segments = majorants.segments(r, 0.0, 4.0, 100.0);
+            assert!(segments[0].0 == 0.0 && segments[segments.len() - 1].1 == 4.0);
+            for w in segments.windows(2) {
+                assert!(w[0].1 == w[1].0);
+            }
+            for &(t0, t1, m) in &segments {
+                for i in 0..20 {
+                    let t = t0 + (t1 - t0) * (i as f64 + 0.5) / 20.0;
+                    assert!(grid.lookup(&r.point_at_parameter(t)) <= m);
+                }
+            }
+        }
+    }
+}